@@ -0,0 +1,54 @@
+use std::fmt;
+
+use micropad_protocol::{MessageType, ProtocolError, ResponseCode};
+
+pub type Result<T> = std::result::Result<T, Error>;
+
+#[derive(Debug)]
+pub enum Error {
+    /// The serial port couldn't be listed or opened.
+    Serial(serialport::Error),
+    Io(std::io::Error),
+    /// The micropad sent something that doesn't decode.
+    Protocol(ProtocolError),
+    /// The micropad answered with an error response code.
+    Response(ResponseCode),
+    /// The micropad's firmware doesn't handle this message.
+    Unsupported(MessageType),
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Error::Serial(err) => write!(f, "Serial port error: {}", err),
+            Error::Io(err) => write!(f, "I/O error: {}", err),
+            Error::Protocol(err) => write!(f, "Malformed response: {}", err),
+            Error::Response(code) => write!(f, "Micropad responded with error: {}", code),
+            Error::Unsupported(message_type) => write!(
+                f,
+                "The micropad firmware does not support {:?}, try updating it",
+                message_type
+            ),
+        }
+    }
+}
+
+impl std::error::Error for Error {}
+
+impl From<serialport::Error> for Error {
+    fn from(err: serialport::Error) -> Error {
+        Error::Serial(err)
+    }
+}
+
+impl From<std::io::Error> for Error {
+    fn from(err: std::io::Error) -> Error {
+        Error::Io(err)
+    }
+}
+
+impl From<ProtocolError> for Error {
+    fn from(err: ProtocolError) -> Error {
+        Error::Protocol(err)
+    }
+}
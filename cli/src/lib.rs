@@ -0,0 +1,37 @@
+//! Drive a USB micropad from Rust: find attached micropads, open a
+//! connection and send it typed requests.
+//!
+//! ```no_run
+//! let ports = micropad_ctrl::find_micropads()?;
+//! let mut micropad = micropad_ctrl::Micropad::open(&ports[0])?;
+//! micropad.set_led_brightness(64)?;
+//! println!("Firmware {}", micropad.version()?);
+//! # Ok::<(), micropad_ctrl::Error>(())
+//! ```
+
+mod error;
+mod micropad;
+mod transport;
+
+pub use error::{Error, Result};
+pub use micropad::{FirmwareVersion, Micropad, ModeInfo, DEFAULT_TIMEOUT};
+pub use transport::Transport;
+
+use serialport::{SerialPortInfo, SerialPortType};
+
+/// Every attached micropad, in the order the OS lists their serial ports.
+pub fn find_micropads() -> Result<Vec<SerialPortInfo>> {
+    let available_ports = serialport::available_ports()?;
+    Ok(available_ports
+        .into_iter()
+        .filter(|info| matches!(serial_number(info), Some(serial) if serial.starts_with("MP00")))
+        .collect())
+}
+
+/// The USB serial number of a port, if it has one.
+pub fn serial_number(port_info: &SerialPortInfo) -> Option<&str> {
+    match port_info.port_type {
+        SerialPortType::UsbPort(ref usb_port_info) => usb_port_info.serial_number.as_deref(),
+        _ => None,
+    }
+}
@@ -1,149 +1,1078 @@
-use clap::{App, Arg, SubCommand};
-use log;
+mod profile;
+
+use clap::{App, Arg, ArgMatches, SubCommand};
 use log::LevelFilter;
-use micropad_protocol::{Message, MessageFrame, ResponseCode, ResponsePayload};
+use micropad_ctrl::{find_micropads, serial_number, Error, FirmwareVersion, Micropad};
+use micropad_protocol::{
+    Chord, Color, Direction, Event, EventSet, Key, Message, ModeName, ResponseCode, ScanCode,
+};
+use serde::Serialize;
+use serde_json::{json, Value};
 use simple_logger::SimpleLogger;
 
-use serialport::{SerialPort, SerialPortInfo, SerialPortType};
-use std::time::Duration;
+use serialport::{SerialPortInfo, SerialPortType, UsbPortInfo};
+use std::fmt;
+use std::fs;
+use std::path::Path;
+use std::process;
+
+use profile::{color_hex, DeviceState, ModeState, Profile, BACKUP_FORMAT, CHORD_COUNT, SLOT_COUNT};
 
 #[derive(Debug)]
 enum CliError {
-    Serial(serialport::Error),
-    Io(std::io::Error),
+    /// Talking to the micropad failed.
+    Micropad(Error),
+    /// A profile or backup file couldn't be read or written.
+    File(std::io::Error),
     NotFound,
+    /// Command line arguments that clap can't check on its own.
+    Usage(String),
+    /// A profile that can't be read, or can't be applied.
+    Profile(String),
+    /// `--device` matched more than one micropad.
+    Ambiguous {
+        selector: String,
+        candidates: Vec<String>,
+    },
+}
+
+impl CliError {
+    /// Process exit code, so scripts can tell failures apart. Error responses
+    /// from the micropad exit with 10 plus the response code.
+    fn exit_code(&self) -> i32 {
+        match self {
+            CliError::Usage(_) => 1,
+            CliError::NotFound => 2,
+            CliError::Ambiguous { .. } => 5,
+            CliError::Profile(_) => 6,
+            CliError::File(_) => 3,
+            CliError::Micropad(err) => match err {
+                Error::Serial(_) | Error::Io(_) => 3,
+                Error::Protocol(_) => 4,
+                Error::Response(code) => 10 + code.raw() as i32,
+                Error::Unsupported(_) => 10 + ResponseCode::UnknownMessage.raw() as i32,
+            },
+        }
+    }
+
+    fn kind(&self) -> &'static str {
+        match self {
+            CliError::Micropad(Error::Serial(_)) => "serial",
+            CliError::Micropad(Error::Io(_)) | CliError::File(_) => "io",
+            CliError::Micropad(Error::Protocol(_)) => "protocol",
+            CliError::Micropad(Error::Response(_)) => "response",
+            CliError::Micropad(Error::Unsupported(_)) => "unsupported",
+            CliError::Usage(_) => "usage",
+            CliError::NotFound => "not_found",
+            CliError::Ambiguous { .. } => "ambiguous",
+            CliError::Profile(_) => "profile",
+        }
+    }
+
+    /// The error as reported by `--format json`.
+    fn to_json(&self, context: &str) -> Value {
+        let mut error = json!({
+            "kind": self.kind(),
+            "message": self.to_string(),
+            "context": context,
+            "exit_code": self.exit_code(),
+        });
+        match self {
+            CliError::Micropad(Error::Response(code)) => error["response_code"] = json!(code.raw()),
+            CliError::Ambiguous { candidates, .. } => error["candidates"] = json!(candidates),
+            _ => {}
+        }
+        error
+    }
 }
 
-impl From<serialport::Error> for CliError {
-    fn from(err: serialport::Error) -> CliError {
-        CliError::Serial(err)
+impl fmt::Display for CliError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            CliError::Micropad(err) => err.fmt(f),
+            CliError::File(err) => write!(f, "I/O error: {}", err),
+            CliError::Usage(message) => f.write_str(message),
+            CliError::Profile(message) => write!(f, "Invalid profile: {}", message),
+            CliError::NotFound => write!(f, "No micropad found"),
+            CliError::Ambiguous {
+                selector,
+                candidates,
+            } => {
+                write!(
+                    f,
+                    "More than one micropad matches '{}', pick one by index or port:",
+                    selector
+                )?;
+                for candidate in candidates {
+                    write!(f, "\n    {}", candidate)?;
+                }
+                Ok(())
+            }
+        }
+    }
+}
+
+impl std::error::Error for CliError {}
+
+impl From<Error> for CliError {
+    fn from(err: Error) -> CliError {
+        CliError::Micropad(err)
     }
 }
 
 impl From<std::io::Error> for CliError {
     fn from(err: std::io::Error) -> CliError {
-        CliError::Io(err)
+        CliError::File(err)
+    }
+}
+
+/// Which micropad `--device` picks out.
+enum DeviceSelector {
+    /// Position among the attached micropads
+    Index(usize),
+    /// Serial number or port name
+    Name(String),
+}
+
+impl DeviceSelector {
+    fn parse(value: &str) -> DeviceSelector {
+        match value.parse() {
+            Ok(index) => DeviceSelector::Index(index),
+            Err(_) => DeviceSelector::Name(value.to_string()),
+        }
+    }
+
+    fn select(&self) -> Result<SerialPortInfo, CliError> {
+        match self {
+            DeviceSelector::Index(position) => {
+                find_micropads()?.into_iter().nth(*position).ok_or_else(|| {
+                    log::error!(
+                        "Could not find an attached micropad at position: {}, is it plugged in?",
+                        position
+                    );
+                    CliError::NotFound
+                })
+            }
+            DeviceSelector::Name(name) => {
+                let mut matching: Vec<(usize, SerialPortInfo)> = find_micropads()?
+                    .into_iter()
+                    .enumerate()
+                    .filter(|(_, info)| {
+                        info.port_name == *name || serial_number(info) == Some(name.as_str())
+                    })
+                    .collect();
+                match matching.len() {
+                    // Not a USB micropad, but a port like the simulator's
+                    0 if Path::new(name).exists() => Ok(SerialPortInfo {
+                        port_name: name.clone(),
+                        port_type: SerialPortType::Unknown,
+                    }),
+                    0 => {
+                        log::error!(
+                            "Could not find an attached micropad with port or serial number: {}",
+                            name
+                        );
+                        Err(CliError::NotFound)
+                    }
+                    1 => Ok(matching.remove(0).1),
+                    _ => Err(CliError::Ambiguous {
+                        selector: name.clone(),
+                        candidates: matching
+                            .iter()
+                            .map(|(index, info)| {
+                                format!(
+                                    "{}: {} ({})",
+                                    index,
+                                    info.port_name,
+                                    serial_number(info).unwrap_or("no serial number")
+                                )
+                            })
+                            .collect(),
+                    }),
+                }
+            }
+        }
+    }
+}
+
+/// The micropads a command runs against.
+enum Selection {
+    One(DeviceSelector),
+    All,
+}
+
+/// The outcome of a successful command: a message for people, and the same
+/// facts as a JSON object for `--format json`.
+struct Report {
+    text: String,
+    json: Value,
+}
+
+impl Report {
+    fn new<S: Into<String>>(text: S, json: Value) -> Report {
+        Report {
+            text: text.into(),
+            json,
+        }
+    }
+}
+
+/// How results and errors are printed.
+#[derive(Copy, Clone, Eq, PartialEq)]
+enum Format {
+    /// Log lines, for people
+    Text,
+    /// One JSON document on stdout, for scripts
+    Json,
+}
+
+fn key_json(key: &Key) -> Value {
+    match key {
+        Key::Normal(scan_code) => json!({
+            "kind": "normal",
+            "code": scan_code.raw(),
+            "name": scan_code.name(),
+        }),
+        Key::Media(media_code) => json!({
+            "kind": "media",
+            "code": media_code.usage(),
+            "name": media_code.name(),
+        }),
+        Key::Combo {
+            modifiers,
+            scan_code,
+        } => json!({
+            "kind": "combo",
+            "code": scan_code.raw(),
+            "name": scan_code.name(),
+            "modifiers": modifiers.names().collect::<Vec<_>>(),
+        }),
+        Key::System(system_code) => json!({
+            "kind": "system",
+            "code": system_code.usage(),
+            "name": system_code.name(),
+        }),
+        Key::Scroll(direction) => json!({
+            "kind": "scroll",
+            "code": direction.raw(),
+            "name": direction.name(),
+        }),
     }
 }
 
-fn find_micropads() -> Result<Box<dyn Iterator<Item = SerialPortInfo>>, CliError> {
-    let available_ports = serialport::available_ports()?;
-    Ok(Box::new(available_ports.into_iter().filter(
-        |info| match info.port_type {
-            SerialPortType::UsbPort(ref usb_port_info) => match usb_port_info.serial_number {
-                Some(ref serial) if serial.starts_with("MP00") => true,
-                _ => false,
+fn event_json(event: &Event) -> Value {
+    match event {
+        Event::ButtonDown(button) => json!({ "event": "button_down", "button": button }),
+        Event::ButtonUp(button) => json!({ "event": "button_up", "button": button }),
+        Event::EncoderStep { encoder, direction } => json!({
+            "event": "encoder_step",
+            "encoder": encoder,
+            "direction": match direction {
+                Direction::Clockwise => "clockwise",
+                Direction::CounterClockwise => "counter_clockwise",
             },
-            _ => false,
-        },
-    )))
-}
-
-fn find_micropad(position: usize) -> Result<SerialPortInfo, CliError> {
-    find_micropads()?
-        .nth(position)
-        .ok_or(CliError::NotFound)
-        .map_err(|err| match err {
-            CliError::NotFound => {
-                log::error!(
-                    "Could not find an attached micropad at position: {}, is it plugged in?",
-                    position
-                );
-                CliError::NotFound
-            }
-            err => err,
+        }),
+        Event::ModeChanged(mode) => json!({ "event": "mode_changed", "mode": mode }),
+    }
+}
+
+fn ping(micropad: &mut Micropad) -> Result<Report, CliError> {
+    micropad.ping()?;
+    Ok(Report::new("Got ping response!", json!({})))
+}
+
+fn set_led_brightness(micropad: &mut Micropad, brightness: u8) -> Result<Report, CliError> {
+    micropad.set_led_brightness(brightness)?;
+    Ok(Report::new(
+        format!("LED brightness changed to: {}", brightness),
+        json!({ "brightness": brightness }),
+    ))
+}
+
+fn get_led_brightness(micropad: &mut Micropad) -> Result<Report, CliError> {
+    let brightness = micropad.led_brightness()?;
+    Ok(Report::new(
+        format!("Current LED brightness is: {}", brightness),
+        json!({ "brightness": brightness }),
+    ))
+}
+
+fn set_debounce_time(micropad: &mut Micropad, debounce_ms: u8) -> Result<Report, CliError> {
+    micropad.set_debounce_time(debounce_ms)?;
+    Ok(Report::new(
+        format!("Debounce time changed to: {}ms", debounce_ms),
+        json!({ "debounce_ms": debounce_ms }),
+    ))
+}
+
+fn get_debounce_time(micropad: &mut Micropad) -> Result<Report, CliError> {
+    let debounce_ms = micropad.debounce_time()?;
+    Ok(Report::new(
+        format!("Current debounce time is: {}ms", debounce_ms),
+        json!({ "debounce_ms": debounce_ms }),
+    ))
+}
+
+fn get_version(micropad: &mut Micropad) -> Result<Report, CliError> {
+    let version = micropad.version()?;
+    Ok(Report::new(
+        format!("Current firmware version is is: {}", version),
+        json!({
+            "version": version.to_string(),
+            "major": version.major,
+            "minor": version.minor,
+            "patch": version.patch,
+        }),
+    ))
+}
+
+fn get_mode_info(micropad: &mut Micropad) -> Result<Report, CliError> {
+    let info = micropad.mode_info()?;
+    Ok(Report::new(
+        format!(
+            "Built in mode count: {}\nUser mode count: {}\nCurrent mode index: {}",
+            info.built_in_mode_count, info.user_mode_count, info.current_mode_index
+        ),
+        json!({
+            "built_in_mode_count": info.built_in_mode_count,
+            "user_mode_count": info.user_mode_count,
+            "current_mode_index": info.current_mode_index,
+        }),
+    ))
+}
+
+fn get_capabilities(micropad: &mut Micropad) -> Result<Report, CliError> {
+    let capabilities = micropad.capabilities();
+    Ok(Report::new(
+        format!(
+            "Protocol version: {}\nFrame size: {}\nMax payload size: {}\n\
+             Supported messages: {:#018x}\nButtons: {}\nEncoders: {}\nLEDs: {}\n\
+             Max user modes: {}",
+            capabilities.protocol_version,
+            capabilities.frame_size,
+            capabilities.max_payload_size,
+            capabilities.supported_messages.0,
+            capabilities.button_count,
+            capabilities.encoder_count,
+            capabilities.led_count,
+            capabilities.max_user_modes
+        ),
+        json!({
+            "protocol_version": capabilities.protocol_version,
+            "frame_size": capabilities.frame_size,
+            "max_payload_size": capabilities.max_payload_size,
+            "supported_messages": capabilities.supported_messages.0,
+            "button_count": capabilities.button_count,
+            "encoder_count": capabilities.encoder_count,
+            "led_count": capabilities.led_count,
+            "max_user_modes": capabilities.max_user_modes,
+        }),
+    ))
+}
+
+fn get_key_binding(micropad: &mut Micropad, mode: u8, slot: u8) -> Result<Report, CliError> {
+    let key = micropad.key_binding(mode, slot)?;
+    Ok(Report::new(
+        format!("Mode {} slot {} is bound to: {}", mode, slot, key),
+        json!({ "mode": mode, "slot": slot, "key": key_json(&key) }),
+    ))
+}
+
+fn set_key_binding(
+    micropad: &mut Micropad,
+    mode: u8,
+    slot: u8,
+    key: Key,
+) -> Result<Report, CliError> {
+    micropad.set_key_binding(mode, slot, key)?;
+    Ok(Report::new(
+        format!("Mode {} slot {} bound to: {}", mode, slot, key),
+        json!({ "mode": mode, "slot": slot, "key": key_json(&key) }),
+    ))
+}
+
+fn get_chord(micropad: &mut Micropad, mode: u8, index: u8) -> Result<Report, CliError> {
+    match micropad.chord(mode, index)? {
+        Some(chord) => Ok(Report::new(
+            format!(
+                "Mode {} chord {} is {} bound to: {}",
+                mode,
+                index,
+                format_buttons(chord.buttons),
+                chord.key
+            ),
+            json!({
+                "mode": mode,
+                "index": index,
+                "buttons": format_buttons(chord.buttons),
+                "key": key_json(&chord.key),
+            }),
+        )),
+        None => Ok(Report::new(
+            format!("Mode {} chord {} is unused", mode, index),
+            json!({ "mode": mode, "index": index, "buttons": null, "key": null }),
+        )),
+    }
+}
+
+fn set_chord(
+    micropad: &mut Micropad,
+    mode: u8,
+    index: u8,
+    chord: Option<Chord>,
+) -> Result<Report, CliError> {
+    micropad.set_chord(mode, index, chord)?;
+    match chord {
+        Some(chord) => Ok(Report::new(
+            format!(
+                "Mode {} chord {} set to {} bound to: {}",
+                mode,
+                index,
+                format_buttons(chord.buttons),
+                chord.key
+            ),
+            json!({
+                "mode": mode,
+                "index": index,
+                "buttons": format_buttons(chord.buttons),
+                "key": key_json(&chord.key),
+            }),
+        )),
+        None => Ok(Report::new(
+            format!("Mode {} chord {} cleared", mode, index),
+            json!({ "mode": mode, "index": index, "buttons": null, "key": null }),
+        )),
+    }
+}
+
+fn get_mode_name(micropad: &mut Micropad, mode: u8) -> Result<Report, CliError> {
+    let name = micropad.mode_name(mode)?;
+    Ok(Report::new(
+        format!("Mode {} is named: {}", mode, name.as_str()),
+        json!({ "mode": mode, "name": name.as_str() }),
+    ))
+}
+
+fn create_mode(micropad: &mut Micropad, copy_from: u8, name: ModeName) -> Result<Report, CliError> {
+    let mode = micropad.create_mode(copy_from, name)?;
+    Ok(Report::new(
+        format!("Created mode {}: {}", mode, name.as_str()),
+        json!({ "mode": mode, "name": name.as_str() }),
+    ))
+}
+
+fn delete_mode(micropad: &mut Micropad, mode: u8) -> Result<Report, CliError> {
+    micropad.delete_mode(mode)?;
+    Ok(Report::new(
+        format!("Deleted mode {}", mode),
+        json!({ "mode": mode }),
+    ))
+}
+
+fn move_mode(micropad: &mut Micropad, from: u8, to: u8) -> Result<Report, CliError> {
+    micropad.move_mode(from, to)?;
+    Ok(Report::new(
+        format!("Moved mode {} to {}", from, to),
+        json!({ "from": from, "to": to }),
+    ))
+}
+
+fn rename_mode(micropad: &mut Micropad, mode: u8, name: ModeName) -> Result<Report, CliError> {
+    micropad.rename_mode(mode, name)?;
+    Ok(Report::new(
+        format!("Renamed mode {} to: {}", mode, name.as_str()),
+        json!({ "mode": mode, "name": name.as_str() }),
+    ))
+}
+
+fn set_mode(micropad: &mut Micropad, mode: u8) -> Result<Report, CliError> {
+    micropad.set_mode(mode)?;
+    Ok(Report::new(
+        format!("Switched to mode {}", mode),
+        json!({ "mode": mode }),
+    ))
+}
+
+fn get_mode(micropad: &mut Micropad) -> Result<Report, CliError> {
+    let mode = micropad.mode()?;
+    Ok(Report::new(
+        format!("Current mode index: {}", mode),
+        json!({ "mode": mode }),
+    ))
+}
+
+fn get_slot_color(micropad: &mut Micropad, mode: u8, slot: u8) -> Result<Report, CliError> {
+    let color = micropad.slot_color(mode, slot)?;
+    Ok(Report::new(
+        format!("Mode {} slot {} color is: {}", mode, slot, color_hex(color)),
+        json!({ "mode": mode, "slot": slot, "color": color_hex(color) }),
+    ))
+}
+
+fn set_slot_color(
+    micropad: &mut Micropad,
+    mode: u8,
+    slot: u8,
+    color: Color,
+) -> Result<Report, CliError> {
+    micropad.set_slot_color(mode, slot, color)?;
+    Ok(Report::new(
+        format!(
+            "Mode {} slot {} color set to: {}",
+            mode,
+            slot,
+            color_hex(color)
+        ),
+        json!({ "mode": mode, "slot": slot, "color": color_hex(color) }),
+    ))
+}
+
+/// Read back everything a profile can set.
+fn read_device_state(micropad: &mut Micropad) -> Result<DeviceState, CliError> {
+    let led_brightness = micropad.led_brightness()?;
+    let debounce_ms = if micropad.supports(&Message::GetDebounceTime) {
+        Some(micropad.debounce_time()?)
+    } else {
+        None
+    };
+    let info = micropad.mode_info()?;
+
+    let mode_count = info.mode_count()?;
+    let mut modes = Vec::with_capacity(mode_count as usize);
+    for mode in 0..mode_count {
+        let mut state = ModeState {
+            name: micropad.mode_name(mode)?,
+            keys: [Key::Normal(ScanCode::Return); SLOT_COUNT],
+            colors: [None; SLOT_COUNT],
+            chords: None,
+        };
+        let has_colors = micropad.supports(&Message::GetSlotColor { mode, slot: 0 });
+        for slot in 0..SLOT_COUNT {
+            state.keys[slot] = micropad.key_binding(mode, slot as u8)?;
+            if has_colors {
+                state.colors[slot] = Some(micropad.slot_color(mode, slot as u8)?);
+            }
+        }
+        if micropad.supports(&Message::GetChord { mode, index: 0 }) {
+            let mut chords = [None; CHORD_COUNT];
+            for (index, chord) in chords.iter_mut().enumerate() {
+                *chord = micropad.chord(mode, index as u8)?;
+            }
+            state.chords = Some(chords);
+        }
+        modes.push(state);
+    }
+
+    Ok(DeviceState {
+        led_brightness,
+        debounce_ms,
+        built_in_mode_count: info.built_in_mode_count,
+        current_mode: info.current_mode_index,
+        modes,
+    })
+}
+
+fn read_profile(path: &str) -> Result<Profile, CliError> {
+    let contents = fs::read_to_string(path)?;
+    Profile::parse(Path::new(path), &contents).map_err(CliError::Profile)
+}
+
+/// Send only the messages needed to make the micropad match a profile.
+fn apply(
+    micropad: &mut Micropad,
+    profile: &Profile,
+    dry_run: bool,
+    save: bool,
+) -> Result<Report, CliError> {
+    let state = read_device_state(micropad)?;
+    let changes =
+        profile::plan(profile, &state, micropad.capabilities()).map_err(CliError::Profile)?;
+    if save && !dry_run && !micropad.supports(&Message::SaveConfig) {
+        return Err(CliError::Profile(
+            "The micropad's firmware can't save settings, leave out --save".to_string(),
+        ));
+    }
+
+    if !dry_run {
+        for change in &changes {
+            log::debug!("{}", change);
+            match micropad.send(&change.message)? {
+                (ResponseCode::Ok, _) => {}
+                (code, _) => return Err(Error::Response(code).into()),
+            }
+        }
+        if save {
+            micropad.save_config()?;
+        }
+    }
+
+    let descriptions: Vec<String> = changes.iter().map(|change| change.to_string()).collect();
+    let text = if descriptions.is_empty() {
+        "The micropad already matches the profile".to_string()
+    } else if dry_run {
+        format!("Would make these changes:\n{}", descriptions.join("\n"))
+    } else {
+        format!("Made these changes:\n{}", descriptions.join("\n"))
+    };
+    Ok(Report::new(
+        text,
+        json!({
+            "dry_run": dry_run,
+            "saved": save && !dry_run,
+            "changes": descriptions,
+        }),
+    ))
+}
+
+fn backup(micropad: &mut Micropad, path: &str) -> Result<Report, CliError> {
+    let firmware_version = micropad.version()?.to_string();
+    let state = read_device_state(micropad)?;
+    let contents = Profile::backup(&state, &firmware_version)
+        .write(Path::new(path))
+        .map_err(CliError::Profile)?;
+    fs::write(path, contents)?;
+    Ok(Report::new(
+        format!(
+            "Backed up {} modes from firmware {} to {}",
+            state.modes.len(),
+            firmware_version,
+            path
+        ),
+        json!({
+            "path": path,
+            "firmware_version": firmware_version,
+            "mode_count": state.modes.len(),
+        }),
+    ))
+}
+
+/// Read a backup, refusing files this cli can't make sense of.
+fn read_backup(path: &str) -> Result<Profile, CliError> {
+    let backup = read_profile(path)?;
+    match backup.backup {
+        None => Err(CliError::Profile(format!(
+            "{} has no [backup] table, use apply for profiles",
+            path
+        ))),
+        Some(ref info) if info.format > BACKUP_FORMAT => Err(CliError::Profile(format!(
+            "{} uses backup format {}, but this cli only reads up to format {}. Update the cli",
+            path, info.format, BACKUP_FORMAT
+        ))),
+        Some(ref info) if info.firmware_version.parse::<FirmwareVersion>().is_err() => {
+            Err(CliError::Profile(format!(
+                "{} has an invalid firmware version: {}",
+                path, info.firmware_version
+            )))
+        }
+        Some(_) => Ok(backup),
+    }
+}
+
+/// Write a backup back to a micropad. Backups taken from newer firmware may
+/// use settings this micropad doesn't have, so they are refused unless
+/// forced. Older backups are migrated by applying them as a profile, which
+/// leaves settings they predate alone.
+fn restore(
+    micropad: &mut Micropad,
+    backup: &Profile,
+    force: bool,
+    dry_run: bool,
+    save: bool,
+) -> Result<Report, CliError> {
+    let backup_version = backup
+        .backup
+        .as_ref()
+        .and_then(|info| info.firmware_version.parse::<FirmwareVersion>().ok())
+        .unwrap_or_default();
+    let firmware_version = micropad.version()?;
+    if backup_version > firmware_version && !force {
+        return Err(CliError::Profile(format!(
+            "The backup was taken from firmware {}, newer than this micropad's {}. \
+             Update the firmware, or pass --force to try anyway",
+            backup_version, firmware_version
+        )));
+    }
+    if backup_version != firmware_version {
+        log::warn!(
+            "Restoring a backup from firmware {} onto firmware {}",
+            backup_version,
+            firmware_version
+        );
+    }
+    apply(micropad, backup, dry_run, save)
+}
+
+fn save_config(micropad: &mut Micropad) -> Result<Report, CliError> {
+    micropad.save_config()?;
+    Ok(Report::new(
+        "Settings saved to the micropad's flash",
+        json!({}),
+    ))
+}
+
+fn load_config(micropad: &mut Micropad) -> Result<Report, CliError> {
+    micropad.load_config()?;
+    Ok(Report::new(
+        "Settings restored from the micropad's flash",
+        json!({}),
+    ))
+}
+
+fn factory_reset(micropad: &mut Micropad) -> Result<Report, CliError> {
+    micropad.factory_reset()?;
+    Ok(Report::new("Micropad reset to factory settings", json!({})))
+}
+
+/// One attached micropad, as shown by `list`.
+#[derive(Serialize)]
+struct Listing {
+    index: usize,
+    port: String,
+    vid: u16,
+    pid: u16,
+    serial_number: Option<String>,
+    manufacturer: Option<String>,
+    product: Option<String>,
+    firmware_version: Option<String>,
+    current_mode: Option<u8>,
+    current_mode_name: Option<String>,
+    /// Why the micropad couldn't be queried, if it couldn't.
+    error: Option<String>,
+}
+
+impl Listing {
+    fn new(index: usize, port: &SerialPortInfo, usb: &UsbPortInfo) -> Listing {
+        let mut listing = Listing {
+            index,
+            port: port.port_name.clone(),
+            vid: usb.vid,
+            pid: usb.pid,
+            serial_number: usb.serial_number.clone(),
+            manufacturer: usb.manufacturer.clone(),
+            product: usb.product.clone(),
+            firmware_version: None,
+            current_mode: None,
+            current_mode_name: None,
+            error: None,
+        };
+        let result = Micropad::open(port)
+            .map_err(CliError::from)
+            .and_then(|mut micropad| listing.query(&mut micropad));
+        if let Err(err) = result {
+            listing.error = Some(err.to_string());
+        }
+        listing
+    }
+
+    fn query(&mut self, micropad: &mut Micropad) -> Result<(), CliError> {
+        self.firmware_version = Some(micropad.version()?.to_string());
+        let current_mode_index = micropad.mode_info()?.current_mode_index;
+        self.current_mode = Some(current_mode_index);
+        // Firmware without mode names still lists fine, just without the name
+        if micropad.supports(&Message::GetModeName(current_mode_index)) {
+            let name = micropad.mode_name(current_mode_index)?;
+            self.current_mode_name = Some(name.as_str().to_string());
+        }
+        Ok(())
+    }
+}
+
+impl fmt::Display for Listing {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "{}: {} {:04x}:{:04x}",
+            self.index, self.port, self.vid, self.pid
+        )?;
+        for detail in [&self.serial_number, &self.manufacturer, &self.product]
+            .iter()
+            .copied()
+            .flatten()
+        {
+            write!(f, " {}", detail)?;
+        }
+        if let Some(ref version) = self.firmware_version {
+            write!(f, ", firmware {}", version)?;
+        }
+        if let Some(mode) = self.current_mode {
+            write!(f, ", mode {}", mode)?;
+        }
+        if let Some(ref name) = self.current_mode_name {
+            write!(f, " ({})", name)?;
+        }
+        if let Some(ref err) = self.error {
+            write!(f, ", {}", err)?;
+        }
+        Ok(())
+    }
+}
+
+fn list() -> Result<Report, CliError> {
+    let listings: Vec<Listing> = find_micropads()?
+        .into_iter()
+        .enumerate()
+        .filter_map(|(index, port)| match port.port_type {
+            SerialPortType::UsbPort(ref usb) => Some(Listing::new(index, &port, usb)),
+            _ => None,
         })
+        .collect();
+
+    let text = if listings.is_empty() {
+        "No micropads attached".to_string()
+    } else {
+        listings
+            .iter()
+            .map(Listing::to_string)
+            .collect::<Vec<_>>()
+            .join("\n")
+    };
+    Ok(Report::new(text, json!(listings)))
 }
 
-fn connect_micropad(port_info: &SerialPortInfo) -> Result<Box<dyn SerialPort>, CliError> {
-    serialport::new(&port_info.port_name, 11520)
-        .timeout(Duration::from_millis(500))
-        .open()
-        .map_err(|err| err.into())
+/// Parses colors written as `#rrggbb`, with or without the `#`.
+fn parse_color(value: &str) -> Option<Color> {
+    let hex = value.strip_prefix('#').unwrap_or(value);
+    if hex.len() != 6 || !hex.is_ascii() {
+        return None;
+    }
+    let channel = |i: usize| u8::from_str_radix(&hex[i..i + 2], 16).ok();
+    Some(Color::new(channel(0)?, channel(2)?, channel(4)?))
 }
 
-fn send_message(message: &Message) -> Result<(ResponseCode, ResponsePayload), CliError> {
-    let micropad_info = find_micropad(0)?;
-    let mut micropad_port = connect_micropad(&micropad_info)?;
+/// Explains the key forms `Key`'s `FromStr` accepts.
+const KEY_USAGE: &str = "Key must be a key name like PageDown or ctrl+shift+M, media:<name>, system:<name> or a code like normal:0x28";
 
-    let request_frame = MessageFrame::from(message);
-    micropad_port.write_all(&request_frame.buf)?;
+/// Names of the buttons that can be chorded, by button number.
+const CHORD_BUTTON_NAMES: [&str; 3] = ["play_pause", "next", "previous"];
 
-    let mut response_frame = MessageFrame::new();
-    micropad_port.read_exact(&mut response_frame.buf)?;
-    Ok(response_frame.into_code_and_payload(message))
+/// Writes a chord's buttons the way `parse_buttons` reads them.
+fn format_buttons(buttons: u8) -> String {
+    CHORD_BUTTON_NAMES
+        .iter()
+        .enumerate()
+        .filter(|(button, _)| buttons & (1 << button) != 0)
+        .map(|(_, name)| *name)
+        .collect::<Vec<_>>()
+        .join("+")
 }
 
-fn ping() -> Result<(), CliError> {
-    match send_message(&Message::Ping)? {
-        (ResponseCode::Ok, _) => log::info!("Got ping response!"),
-        response => log::error!("Got non-ok response: {:?}", response),
+/// Parses button names joined with `+`, like `next+previous`, into a
+/// button mask.
+fn parse_buttons(value: &str) -> Option<u8> {
+    value.split('+').try_fold(0u8, |buttons, name| {
+        let button = CHORD_BUTTON_NAMES.iter().position(|n| *n == name.trim())?;
+        Some(buttons | 1 << button)
+    })
+}
+
+fn watch(micropad: &mut Micropad, events: EventSet, format: Format) -> Result<Report, CliError> {
+    micropad.subscribe(events)?;
+    log::info!("Watching for events, press Ctrl-C to stop");
+
+    // The micropad drops our subscription on its own once the port closes
+    loop {
+        if let Some(event) = micropad.next_event()? {
+            match format {
+                Format::Text => log::info!("{:?}", event),
+                Format::Json => println!("{}", event_json(&event)),
+            }
+        }
     }
+}
+
+fn usage_error(message: &str) -> CliError {
+    CliError::Usage(message.to_string())
+}
+
+fn parse_u8(matches: &ArgMatches, name: &str, message: &str) -> Result<u8, CliError> {
+    matches
+        .value_of(name)
+        .and_then(|v| v.parse::<u8>().ok())
+        .ok_or_else(|| usage_error(message))
+}
+
+fn mode_index(matches: &ArgMatches, name: &str) -> Result<u8, CliError> {
+    parse_u8(matches, name, "Mode must be a value between 0-255")
+}
+
+fn mode_name(matches: &ArgMatches) -> Result<ModeName, CliError> {
+    matches
+        .value_of("name")
+        .and_then(|v| ModeName::new(v).ok())
+        .ok_or_else(|| usage_error("Mode names can be at most 16 bytes"))
+}
+
+fn mode_and_slot(matches: &ArgMatches) -> Result<(u8, u8), CliError> {
+    let mode = mode_index(matches, "mode")?;
+    let slot = parse_u8(matches, "slot", "Slot must be a value between 0-255")?;
+    Ok((mode, slot))
+}
 
-    Ok(())
+fn mode_and_chord(matches: &ArgMatches) -> Result<(u8, u8), CliError> {
+    let mode = mode_index(matches, "mode")?;
+    let index = parse_u8(
+        matches,
+        "index",
+        "Chord index must be a value between 0-255",
+    )?;
+    Ok((mode, index))
 }
 
-fn set_led_brightness(brightness: u8) -> Result<(), CliError> {
-    match send_message(&Message::SetLedBrightness(brightness))? {
-        (ResponseCode::Ok, _) => log::info!("LED brightness changed to: {}", brightness),
-        response => log::error!("Got non-ok response: {:?}", response),
+/// The chord to set, or `None` with `--clear`.
+fn chord(matches: &ArgMatches) -> Result<Option<Chord>, CliError> {
+    if matches.is_present("clear") {
+        return Ok(None);
     }
+    let buttons = matches
+        .value_of("buttons")
+        .and_then(parse_buttons)
+        .ok_or_else(|| {
+            usage_error(&format!(
+                "Buttons must be joined with +, from: {}",
+                CHORD_BUTTON_NAMES.join(", ")
+            ))
+        })?;
+    let key = matches
+        .value_of("key")
+        .and_then(|key| key.parse().ok())
+        .ok_or_else(|| usage_error(KEY_USAGE))?;
+    Chord::new(buttons, key)
+        .map(Some)
+        .map_err(|_| usage_error("Chords need at least two buttons"))
+}
 
-    Ok(())
+fn print_error(err: &CliError, format: Format, context: &str) {
+    match format {
+        Format::Text => log::error!("{}: {}", context, err),
+        Format::Json => println!("{}", json!({ "error": err.to_json(context) })),
+    }
 }
 
-fn get_led_brightness() -> Result<(), CliError> {
-    match send_message(&Message::GetLedBrightness)? {
-        (ResponseCode::Ok, ResponsePayload::LedBrightness(brightness)) => {
-            log::info!("Current LED brightness is: {}", brightness);
+fn print_report(report: &Report, format: Format) {
+    match format {
+        Format::Text => {
+            for line in report.text.lines() {
+                log::info!("{}", line);
+            }
         }
-        (response, _) => log::error!("Got non-ok response: {:?}", response),
+        Format::Json => println!("{}", report.json),
     }
+}
 
-    Ok(())
+/// Unwrap a result, or print the error and exit with its exit code.
+fn exit_on_error<T>(result: Result<T, CliError>, format: Format, context: &str) -> T {
+    match result {
+        Ok(value) => value,
+        Err(err) => {
+            print_error(&err, format, context);
+            process::exit(err.exit_code());
+        }
+    }
 }
 
-fn get_version() -> Result<(), CliError> {
-    match send_message(&Message::GetVersion)? {
-        (
-            ResponseCode::Ok,
-            ResponsePayload::Version {
-                major,
-                minor,
-                patch,
-            },
-        ) => {
+/// Run a command against the selected micropads, printing its report or
+/// exiting if it fails. With `--all`, every micropad gets a turn even if an
+/// earlier one failed, and the exit code is that of the first failure.
+fn run<F>(selection: &Selection, format: Format, context: &str, mut command: F)
+where
+    F: FnMut(&mut Micropad) -> Result<Report, CliError>,
+{
+    let selector = match selection {
+        Selection::One(selector) => selector,
+        Selection::All => return run_all(format, context, command),
+    };
+    let result = selector.select().and_then(|port_info| {
+        let mut micropad = Micropad::open(&port_info)?;
+        command(&mut micropad)
+    });
+    print_report(&exit_on_error(result, format, context), format);
+}
+
+fn run_all<F>(format: Format, context: &str, mut command: F)
+where
+    F: FnMut(&mut Micropad) -> Result<Report, CliError>,
+{
+    let micropads: Vec<SerialPortInfo> =
+        exit_on_error(find_micropads().map_err(CliError::from), format, context);
+    if micropads.is_empty() {
+        exit_on_error(Err::<(), _>(CliError::NotFound), format, context);
+    }
+
+    let mut first_failure = None;
+    let mut results = Vec::new();
+    for port_info in &micropads {
+        let serial = serial_number(port_info);
+        if format == Format::Text {
             log::info!(
-                "Current firmware version is is: {}.{}.{}",
-                major,
-                minor,
-                patch
+                "{} ({}):",
+                port_info.port_name,
+                serial.unwrap_or("no serial number")
             );
         }
-        (response, _) => log::error!("Got non-ok response: {:?}", response),
+        let result = Micropad::open(port_info)
+            .map_err(CliError::from)
+            .and_then(|mut micropad| command(&mut micropad));
+        let mut outcome = match result {
+            Ok(report) => {
+                if format == Format::Text {
+                    print_report(&report, format);
+                }
+                json!({ "result": report.json })
+            }
+            Err(err) => {
+                if format == Format::Text {
+                    print_error(&err, format, context);
+                }
+                first_failure.get_or_insert(err.exit_code());
+                json!({ "error": err.to_json(context) })
+            }
+        };
+        outcome["port"] = json!(port_info.port_name);
+        outcome["serial_number"] = json!(serial);
+        results.push(outcome);
+    }
+    if format == Format::Json {
+        println!("{}", Value::Array(results));
     }
+    if let Some(exit_code) = first_failure {
+        process::exit(exit_code);
+    }
+}
 
-    Ok(())
+fn mode_arg() -> Arg<'static, 'static> {
+    Arg::with_name("mode")
+        .short("m")
+        .long("mode")
+        .required(true)
+        .takes_value(true)
+        .help("The mode index")
 }
 
-fn get_mode_info() -> Result<(), CliError> {
-    match send_message(&Message::GetModeInfo)? {
-        (
-            ResponseCode::Ok,
-            ResponsePayload::ModeInfo {
-                built_in_mode_count,
-                user_mode_count,
-                current_mode_index,
-            },
-        ) => {
-            log::info!("Built in mode count: {}", built_in_mode_count);
-            log::info!("User mode count: {}", user_mode_count);
-            log::info!("Current mode index: {}", current_mode_index);
-        }
-        (response, _) => log::error!("Got non-ok response: {:?}", response),
-    }
+fn slot_arg() -> Arg<'static, 'static> {
+    Arg::with_name("slot")
+        .short("s")
+        .long("slot")
+        .required(true)
+        .takes_value(true)
+        .help("The slot: 0 encoder clockwise, 1 encoder counter clockwise, 2 play/pause, 3 next, 4 previous")
+}
+
+fn chord_index_arg() -> Arg<'static, 'static> {
+    Arg::with_name("index")
+        .short("i")
+        .long("index")
+        .required(true)
+        .takes_value(true)
+        .help("The chord index, 0 - 3")
+}
 
-    Ok(())
+fn name_arg() -> Arg<'static, 'static> {
+    Arg::with_name("name")
+        .short("n")
+        .long("name")
+        .required(true)
+        .takes_value(true)
+        .help("The mode name, up to 16 bytes")
 }
 
 fn main() {
@@ -151,11 +1080,52 @@ fn main() {
         .version("0.1")
         .author("Blake Smith <blakesmith0@gmail.com>")
         .about("Control and configure your USB micropad via the command line")
+        .after_help(
+            "EXIT CODES:\n    \
+             0   Success\n    \
+             1   Invalid command line arguments\n    \
+             2   No micropad found\n    \
+             3   Serial port or I/O error\n    \
+             4   Malformed response from the micropad\n    \
+             5   --device matched more than one micropad\n    \
+             6   Invalid profile\n    \
+             11  Unsupported message\n    \
+             12  Invalid argument\n    \
+             13  Device busy\n    \
+             14  Not found\n    \
+             15  Device storage error\n    \
+             16  Request corrupted in transit",
+        )
         .arg(
             Arg::with_name("debug")
                 .help("Enable debug logging")
                 .short("d"),
         )
+        .arg(
+            Arg::with_name("device")
+                .long("device")
+                .takes_value(true)
+                .value_name("index|serial|path")
+                .help("The micropad to talk to, by position, serial number or port. Defaults to the first one found"),
+        )
+        .arg(
+            Arg::with_name("format")
+                .long("format")
+                .takes_value(true)
+                .possible_values(&["text", "json"])
+                .default_value("text")
+                .help("Print results as log lines, or as JSON on stdout. JSON output turns logging off unless -d is given"),
+        )
+        .arg(
+            Arg::with_name("all")
+                .long("all")
+                .conflicts_with("device")
+                .help("Run the command against every attached micropad"),
+        )
+        .subcommand(
+            SubCommand::with_name("list")
+                .about("List the attached micropads, with their firmware version and current mode"),
+        )
         .subcommand(
             SubCommand::with_name("ping").about("Ping the micropad to test for connectivity"),
         )
@@ -173,58 +1143,600 @@ fn main() {
         .subcommand(
             SubCommand::with_name("get_led_brightness").about("Get the current LED brightness"),
         )
+        .subcommand(
+            SubCommand::with_name("set_debounce_time")
+                .about("Set how long buttons must settle before a press or release counts")
+                .arg(
+                    Arg::with_name("time")
+                        .short("t")
+                        .long("time")
+                        .required(true)
+                        .takes_value(true)
+                        .help("The debounce time in milliseconds, 0 - 50"),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("get_debounce_time").about("Get the current debounce time"),
+        )
         .subcommand(
             SubCommand::with_name("get_mode_info").about("Get the current mode information"),
         )
         .subcommand(SubCommand::with_name("get_version").about("Get the current firmware version"))
+        .subcommand(
+            SubCommand::with_name("watch")
+                .about("Print button, encoder and mode change events as they happen")
+                .arg(
+                    Arg::with_name("buttons")
+                        .long("buttons")
+                        .help("Watch button presses and releases"),
+                )
+                .arg(
+                    Arg::with_name("encoders")
+                        .long("encoders")
+                        .help("Watch encoder steps"),
+                )
+                .arg(
+                    Arg::with_name("modes")
+                        .long("modes")
+                        .help("Watch mode changes"),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("get_capabilities")
+                .about("Get the protocol version and hardware the firmware supports"),
+        )
+        .subcommand(
+            SubCommand::with_name("get_key_binding")
+                .about("Get the key bound to a slot of a mode")
+                .arg(mode_arg())
+                .arg(slot_arg()),
+        )
+        .subcommand(
+            SubCommand::with_name("set_key_binding")
+                .about("Bind a key to a slot of a mode")
+                .arg(mode_arg())
+                .arg(slot_arg())
+                .arg(
+                    Arg::with_name("key")
+                        .short("k")
+                        .long("key")
+                        .required(true)
+                        .takes_value(true)
+                        .help("The key, by name or code, e.g. F13, PageDown, ctrl+shift+M, media:PlayPause, system:Sleep, scroll:Down, normal:0x28 or media:0xCD"),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("get_chord")
+                .about("Get one of a mode's chords")
+                .arg(mode_arg())
+                .arg(chord_index_arg()),
+        )
+        .subcommand(
+            SubCommand::with_name("set_chord")
+                .about("Bind a key to several buttons held together, in place of their own keys")
+                .arg(mode_arg())
+                .arg(chord_index_arg())
+                .arg(
+                    Arg::with_name("buttons")
+                        .short("b")
+                        .long("buttons")
+                        .takes_value(true)
+                        .required_unless("clear")
+                        .help("The buttons, joined with +, e.g. next+previous"),
+                )
+                .arg(
+                    Arg::with_name("key")
+                        .short("k")
+                        .long("key")
+                        .takes_value(true)
+                        .required_unless("clear")
+                        .help("The key, as for set_key_binding"),
+                )
+                .arg(
+                    Arg::with_name("clear")
+                        .long("clear")
+                        .conflicts_with_all(&["buttons", "key"])
+                        .help("Clear the chord instead"),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("get_mode_name")
+                .about("Get the name of a mode")
+                .arg(mode_arg()),
+        )
+        .subcommand(
+            SubCommand::with_name("create_mode")
+                .about("Create a user mode, starting with the bindings of an existing mode")
+                .arg(name_arg())
+                .arg(
+                    Arg::with_name("copy_from")
+                        .short("c")
+                        .long("copy-from")
+                        .takes_value(true)
+                        .default_value("0")
+                        .help("The index of the mode to copy bindings from"),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("delete_mode")
+                .about("Delete a user mode")
+                .arg(mode_arg()),
+        )
+        .subcommand(
+            SubCommand::with_name("move_mode")
+                .about("Move a user mode to another position among the user modes")
+                .arg(
+                    Arg::with_name("from")
+                        .long("from")
+                        .required(true)
+                        .takes_value(true)
+                        .help("The index of the mode to move"),
+                )
+                .arg(
+                    Arg::with_name("to")
+                        .long("to")
+                        .required(true)
+                        .takes_value(true)
+                        .help("The index the mode ends up at"),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("rename_mode")
+                .about("Rename a user mode")
+                .arg(mode_arg())
+                .arg(name_arg()),
+        )
+        .subcommand(
+            SubCommand::with_name("set_mode")
+                .about("Switch to a mode")
+                .arg(mode_arg()),
+        )
+        .subcommand(SubCommand::with_name("get_mode").about("Get the current mode index"))
+        .subcommand(
+            SubCommand::with_name("get_slot_color")
+                .about("Get the color the LED pulses when a slot of a mode is used")
+                .arg(mode_arg())
+                .arg(slot_arg()),
+        )
+        .subcommand(
+            SubCommand::with_name("set_slot_color")
+                .about("Set the color the LED pulses when a slot of a mode is used")
+                .arg(mode_arg())
+                .arg(slot_arg())
+                .arg(
+                    Arg::with_name("color")
+                        .short("c")
+                        .long("color")
+                        .required(true)
+                        .takes_value(true)
+                        .help("The color, as #rrggbb"),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("apply")
+                .about("Make the micropad match a TOML or YAML profile, sending only what differs")
+                .arg(
+                    Arg::with_name("profile")
+                        .required(true)
+                        .help("The profile, a .toml, .yaml or .yml file"),
+                )
+                .arg(
+                    Arg::with_name("dry_run")
+                        .long("dry-run")
+                        .help("Print the changes without making them"),
+                )
+                .arg(
+                    Arg::with_name("save")
+                        .long("save")
+                        .help("Save the settings once the changes are made"),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("backup")
+                .about("Write every setting of the micropad to a TOML or YAML file")
+                .arg(
+                    Arg::with_name("file")
+                        .required(true)
+                        .help("The backup file, a .toml, .yaml or .yml file"),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("restore")
+                .about("Write the settings in a backup back to a micropad")
+                .arg(
+                    Arg::with_name("file")
+                        .required(true)
+                        .help("The backup file"),
+                )
+                .arg(
+                    Arg::with_name("dry_run")
+                        .long("dry-run")
+                        .help("Print the changes without making them"),
+                )
+                .arg(
+                    Arg::with_name("save")
+                        .long("save")
+                        .help("Save the settings once they are restored"),
+                )
+                .arg(
+                    Arg::with_name("force")
+                        .long("force")
+                        .help("Restore backups taken from newer firmware"),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("save_config")
+                .about("Save the current settings, so they survive unplugging the micropad"),
+        )
+        .subcommand(
+            SubCommand::with_name("load_config")
+                .about("Discard unsaved changes, going back to the saved settings"),
+        )
+        .subcommand(
+            SubCommand::with_name("factory_reset")
+                .about("Erase the saved settings, and go back to the factory settings"),
+        )
         .get_matches();
 
-    if matches.is_present("debug") {
-        SimpleLogger::new()
-            .with_level(LevelFilter::Debug)
-            .init()
-            .unwrap();
+    let format = match matches.value_of("format") {
+        Some("json") => Format::Json,
+        _ => Format::Text,
+    };
+
+    // The logger writes to stdout, where it would get in the way of JSON
+    let level = if matches.is_present("debug") {
+        LevelFilter::Debug
+    } else if format == Format::Json {
+        LevelFilter::Off
     } else {
-        SimpleLogger::new()
-            .with_level(LevelFilter::Info)
-            .init()
-            .unwrap();
-    }
+        LevelFilter::Info
+    };
+    SimpleLogger::new().with_level(level).init().unwrap();
+
+    let selection = if matches.is_present("all") {
+        Selection::All
+    } else {
+        Selection::One(DeviceSelector::parse(
+            matches.value_of("device").unwrap_or("0"),
+        ))
+    };
 
     match matches.subcommand() {
+        ("list", Some(_sub_matches)) => {
+            let report = exit_on_error(list(), format, "Failed to list micropads");
+            print_report(&report, format);
+        }
         ("ping", Some(_sub_matches)) => {
             log::info!("Pinging device");
-            ping().expect("Failed to ping device");
+            run(&selection, format, "Failed to ping device", ping);
         }
         ("set_led_brightness", Some(brightness_matches)) => {
-            let brightness = brightness_matches
-                .value_of("brightness")
-                .map(|v| {
-                    v.parse::<u8>()
-                        .expect("Brightness must be a value between 0-255!")
-                })
-                .unwrap();
+            let brightness = exit_on_error(
+                parse_u8(
+                    brightness_matches,
+                    "brightness",
+                    "Brightness must be a value between 0-255",
+                ),
+                format,
+                "Failed to set LED brightness",
+            );
             log::info!("Setting LED brightness to: {}", brightness);
-            set_led_brightness(brightness).expect("Failed to set LED brightness");
+            run(
+                &selection,
+                format,
+                "Failed to set LED brightness",
+                |micropad| set_led_brightness(micropad, brightness),
+            );
         }
         ("get_led_brightness", Some(_sub_matches)) => {
             log::info!("Getting LED brightness");
-            get_led_brightness().expect("Failed to get LED brightness");
+            run(
+                &selection,
+                format,
+                "Failed to get LED brightness",
+                get_led_brightness,
+            );
+        }
+        ("set_debounce_time", Some(debounce_matches)) => {
+            let debounce_ms = exit_on_error(
+                parse_u8(
+                    debounce_matches,
+                    "time",
+                    "Debounce time must be a value between 0-50",
+                ),
+                format,
+                "Failed to set debounce time",
+            );
+            log::info!("Setting debounce time to: {}ms", debounce_ms);
+            run(
+                &selection,
+                format,
+                "Failed to set debounce time",
+                |micropad| set_debounce_time(micropad, debounce_ms),
+            );
+        }
+        ("get_debounce_time", Some(_sub_matches)) => {
+            log::info!("Getting debounce time");
+            run(
+                &selection,
+                format,
+                "Failed to get debounce time",
+                get_debounce_time,
+            );
         }
         ("get_mode_info", Some(_sub_matches)) => {
             log::info!("Getting mode info");
-            get_mode_info().expect("Failed to get mode info");
+            run(&selection, format, "Failed to get mode info", get_mode_info);
         }
         ("get_version", Some(_sub_matches)) => {
             log::info!("Getting the current version");
-            get_version().expect("Failed to get firmware version");
+            run(
+                &selection,
+                format,
+                "Failed to get firmware version",
+                get_version,
+            );
+        }
+        ("watch", Some(watch_matches)) => {
+            if let Selection::All = selection {
+                let err =
+                    usage_error("watch follows one micropad at a time, pick one with --device");
+                exit_on_error(Err::<(), _>(err), format, "Failed to watch events");
+            }
+            let mut events = EventSet::empty();
+            if watch_matches.is_present("buttons") {
+                events = events.union(EventSet::BUTTONS);
+            }
+            if watch_matches.is_present("encoders") {
+                events = events.union(EventSet::ENCODERS);
+            }
+            if watch_matches.is_present("modes") {
+                events = events.union(EventSet::MODES);
+            }
+            if events.is_empty() {
+                events = EventSet::ALL;
+            }
+            run(&selection, format, "Failed to watch events", |micropad| {
+                watch(micropad, events, format)
+            });
+        }
+        ("get_capabilities", Some(_sub_matches)) => {
+            log::info!("Getting the firmware capabilities");
+            run(
+                &selection,
+                format,
+                "Failed to get firmware capabilities",
+                get_capabilities,
+            );
+        }
+        ("get_key_binding", Some(binding_matches)) => {
+            let (mode, slot) = exit_on_error(
+                mode_and_slot(binding_matches),
+                format,
+                "Failed to get key binding",
+            );
+            log::info!("Getting key binding for mode {} slot {}", mode, slot);
+            run(
+                &selection,
+                format,
+                "Failed to get key binding",
+                |micropad| get_key_binding(micropad, mode, slot),
+            );
+        }
+        ("set_key_binding", Some(binding_matches)) => {
+            let (mode, slot) = exit_on_error(
+                mode_and_slot(binding_matches),
+                format,
+                "Failed to set key binding",
+            );
+            let key = exit_on_error(
+                binding_matches
+                    .value_of("key")
+                    .and_then(|key| key.parse().ok())
+                    .ok_or_else(|| usage_error(KEY_USAGE)),
+                format,
+                "Failed to set key binding",
+            );
+            log::info!("Setting key binding for mode {} slot {}", mode, slot);
+            run(
+                &selection,
+                format,
+                "Failed to set key binding",
+                |micropad| set_key_binding(micropad, mode, slot, key),
+            );
+        }
+        ("get_chord", Some(chord_matches)) => {
+            let (mode, index) =
+                exit_on_error(mode_and_chord(chord_matches), format, "Failed to get chord");
+            log::info!("Getting chord {} of mode {}", index, mode);
+            run(&selection, format, "Failed to get chord", |micropad| {
+                get_chord(micropad, mode, index)
+            });
+        }
+        ("set_chord", Some(chord_matches)) => {
+            let (mode, index) =
+                exit_on_error(mode_and_chord(chord_matches), format, "Failed to set chord");
+            let chord = exit_on_error(chord(chord_matches), format, "Failed to set chord");
+            log::info!("Setting chord {} of mode {}", index, mode);
+            run(&selection, format, "Failed to set chord", |micropad| {
+                set_chord(micropad, mode, index, chord)
+            });
+        }
+        ("get_mode_name", Some(mode_matches)) => {
+            let mode = exit_on_error(
+                mode_index(mode_matches, "mode"),
+                format,
+                "Failed to get mode name",
+            );
+            log::info!("Getting the name of mode {}", mode);
+            run(&selection, format, "Failed to get mode name", |micropad| {
+                get_mode_name(micropad, mode)
+            });
+        }
+        ("create_mode", Some(mode_matches)) => {
+            let copy_from = exit_on_error(
+                mode_index(mode_matches, "copy_from"),
+                format,
+                "Failed to create mode",
+            );
+            let name = exit_on_error(mode_name(mode_matches), format, "Failed to create mode");
+            log::info!("Creating mode {}", name.as_str());
+            run(&selection, format, "Failed to create mode", |micropad| {
+                create_mode(micropad, copy_from, name)
+            });
+        }
+        ("delete_mode", Some(mode_matches)) => {
+            let mode = exit_on_error(
+                mode_index(mode_matches, "mode"),
+                format,
+                "Failed to delete mode",
+            );
+            log::info!("Deleting mode {}", mode);
+            run(&selection, format, "Failed to delete mode", |micropad| {
+                delete_mode(micropad, mode)
+            });
+        }
+        ("move_mode", Some(mode_matches)) => {
+            let from = exit_on_error(
+                mode_index(mode_matches, "from"),
+                format,
+                "Failed to move mode",
+            );
+            let to = exit_on_error(
+                mode_index(mode_matches, "to"),
+                format,
+                "Failed to move mode",
+            );
+            log::info!("Moving mode {} to {}", from, to);
+            run(&selection, format, "Failed to move mode", |micropad| {
+                move_mode(micropad, from, to)
+            });
+        }
+        ("rename_mode", Some(mode_matches)) => {
+            let mode = exit_on_error(
+                mode_index(mode_matches, "mode"),
+                format,
+                "Failed to rename mode",
+            );
+            let name = exit_on_error(mode_name(mode_matches), format, "Failed to rename mode");
+            log::info!("Renaming mode {}", mode);
+            run(&selection, format, "Failed to rename mode", |micropad| {
+                rename_mode(micropad, mode, name)
+            });
+        }
+        ("set_mode", Some(mode_matches)) => {
+            let mode = exit_on_error(
+                mode_index(mode_matches, "mode"),
+                format,
+                "Failed to switch mode",
+            );
+            log::info!("Switching to mode {}", mode);
+            run(&selection, format, "Failed to switch mode", |micropad| {
+                set_mode(micropad, mode)
+            });
+        }
+        ("get_mode", Some(_sub_matches)) => {
+            log::info!("Getting the current mode");
+            run(
+                &selection,
+                format,
+                "Failed to get the current mode",
+                get_mode,
+            );
+        }
+        ("get_slot_color", Some(color_matches)) => {
+            let (mode, slot) = exit_on_error(
+                mode_and_slot(color_matches),
+                format,
+                "Failed to get slot color",
+            );
+            log::info!("Getting the color of mode {} slot {}", mode, slot);
+            run(&selection, format, "Failed to get slot color", |micropad| {
+                get_slot_color(micropad, mode, slot)
+            });
+        }
+        ("set_slot_color", Some(color_matches)) => {
+            let (mode, slot) = exit_on_error(
+                mode_and_slot(color_matches),
+                format,
+                "Failed to set slot color",
+            );
+            let color = exit_on_error(
+                color_matches
+                    .value_of("color")
+                    .and_then(parse_color)
+                    .ok_or_else(|| usage_error("Color must be written as #rrggbb")),
+                format,
+                "Failed to set slot color",
+            );
+            log::info!("Setting the color of mode {} slot {}", mode, slot);
+            run(&selection, format, "Failed to set slot color", |micropad| {
+                set_slot_color(micropad, mode, slot, color)
+            });
+        }
+        ("apply", Some(apply_matches)) => {
+            let path = apply_matches.value_of("profile").unwrap_or_default();
+            let profile = exit_on_error(read_profile(path), format, "Failed to apply profile");
+            let dry_run = apply_matches.is_present("dry_run");
+            let save = apply_matches.is_present("save");
+            log::info!("Applying profile {}", path);
+            run(&selection, format, "Failed to apply profile", |micropad| {
+                apply(micropad, &profile, dry_run, save)
+            });
+        }
+        ("backup", Some(backup_matches)) => {
+            if let Selection::All = selection {
+                let err =
+                    usage_error("backup reads one micropad at a time, pick one with --device");
+                exit_on_error(Err::<(), _>(err), format, "Failed to back up settings");
+            }
+            let path = backup_matches.value_of("file").unwrap_or_default();
+            log::info!("Backing up settings to {}", path);
+            run(
+                &selection,
+                format,
+                "Failed to back up settings",
+                |micropad| backup(micropad, path),
+            );
+        }
+        ("restore", Some(restore_matches)) => {
+            let path = restore_matches.value_of("file").unwrap_or_default();
+            let backup = exit_on_error(read_backup(path), format, "Failed to restore settings");
+            let force = restore_matches.is_present("force");
+            let dry_run = restore_matches.is_present("dry_run");
+            let save = restore_matches.is_present("save");
+            log::info!("Restoring settings from {}", path);
+            run(
+                &selection,
+                format,
+                "Failed to restore settings",
+                |micropad| restore(micropad, &backup, force, dry_run, save),
+            );
+        }
+        ("save_config", Some(_sub_matches)) => {
+            log::info!("Saving settings");
+            run(&selection, format, "Failed to save settings", save_config);
+        }
+        ("load_config", Some(_sub_matches)) => {
+            log::info!("Loading saved settings");
+            run(&selection, format, "Failed to load settings", load_config);
+        }
+        ("factory_reset", Some(_sub_matches)) => {
+            log::info!("Resetting to factory settings");
+            run(
+                &selection,
+                format,
+                "Failed to reset to factory settings",
+                factory_reset,
+            );
         }
         (unknown, _) => {
-            if unknown.is_empty() {
-                log::error!("No command provided");
+            let err = if unknown.is_empty() {
+                usage_error("No command provided")
             } else {
-                log::error!("Unknown command: {}", unknown);
-            }
+                CliError::Usage(format!("Unknown command: {}", unknown))
+            };
+            exit_on_error(Err::<(), _>(err), format, "Failed to run command");
         }
     }
 }
@@ -0,0 +1,625 @@
+use std::collections::VecDeque;
+use std::convert::TryFrom;
+use std::fmt;
+use std::io;
+use std::str::FromStr;
+use std::time::Duration;
+
+use micropad_protocol::{
+    next_request_id, Capabilities, Chord, Color, Event, EventSet, FrameDecoder, FrameEncoder, Key,
+    Message, MessageFrame, ModeName, Packet, PacketDecoder, ResponseCode, ResponsePayload,
+    PROTOCOL_VERSION, UNSOLICITED_REQUEST_ID,
+};
+use serialport::{SerialPort, SerialPortInfo};
+
+use crate::{Error, Result, Transport};
+
+/// How long to wait on the micropad before giving up, unless told otherwise.
+pub const DEFAULT_TIMEOUT: Duration = Duration::from_millis(500);
+
+const BAUD_RATE: u32 = 11520;
+
+/// Requests answered with a checksum error never reached the micropad's
+/// message handler, so they are safe to send again. Requests whose
+/// response was corrupted on the way back are sent again too.
+const MAX_SEND_ATTEMPTS: usize = 2;
+
+/// A packet read from the micropad.
+enum Incoming {
+    Frame(MessageFrame),
+    /// A packet that failed its checksum or framing, with the request ID
+    /// it carried, or `UNSOLICITED_REQUEST_ID` when that was lost too.
+    Corrupted(u8),
+}
+
+#[derive(Copy, Clone, Default, Eq, PartialEq, Ord, PartialOrd, Debug)]
+pub struct FirmwareVersion {
+    pub major: u8,
+    pub minor: u8,
+    pub patch: u8,
+}
+
+impl fmt::Display for FirmwareVersion {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}.{}.{}", self.major, self.minor, self.patch)
+    }
+}
+
+impl FromStr for FirmwareVersion {
+    type Err = ();
+
+    /// Parses `major.minor.patch`.
+    fn from_str(value: &str) -> std::result::Result<FirmwareVersion, ()> {
+        let mut parts = value
+            .split('.')
+            .map(|part| part.parse::<u8>().map_err(|_| ()));
+        match (parts.next(), parts.next(), parts.next(), parts.next()) {
+            (Some(major), Some(minor), Some(patch), None) => Ok(FirmwareVersion {
+                major: major?,
+                minor: minor?,
+                patch: patch?,
+            }),
+            _ => Err(()),
+        }
+    }
+}
+
+#[derive(Copy, Clone, Eq, PartialEq, Debug)]
+pub struct ModeInfo {
+    pub built_in_mode_count: u8,
+    pub user_mode_count: u8,
+    pub current_mode_index: u8,
+}
+
+impl ModeInfo {
+    /// Mode indexes are a byte, so counts that add up past 255 can only
+    /// come from a garbled response.
+    pub fn mode_count(&self) -> Result<u8> {
+        self.built_in_mode_count
+            .checked_add(self.user_mode_count)
+            .ok_or_else(unexpected_payload)
+    }
+}
+
+/// An open connection to a micropad, along with the capabilities it
+/// reported when we connected. Requests are answered in order over the one
+/// connection, so keep a `Micropad` around rather than opening one per
+/// request.
+pub struct Micropad<T: Transport = Box<dyn SerialPort>> {
+    transport: T,
+    packets: PacketDecoder,
+    capabilities: Capabilities,
+    request_id: u8,
+    events: VecDeque<Event>,
+}
+
+impl Micropad {
+    pub fn open(port_info: &SerialPortInfo) -> Result<Micropad> {
+        Micropad::open_with_timeout(port_info, DEFAULT_TIMEOUT)
+    }
+
+    pub fn open_with_timeout(port_info: &SerialPortInfo, timeout: Duration) -> Result<Micropad> {
+        let port = serialport::new(&port_info.port_name, BAUD_RATE)
+            .timeout(timeout)
+            .open()?;
+        Micropad::new(port)
+    }
+}
+
+impl<T: Transport> Micropad<T> {
+    /// Connect over an already open transport, negotiating capabilities.
+    pub fn new(mut transport: T) -> Result<Micropad<T>> {
+        // Drop any responses left over from an earlier session
+        transport.clear_input()?;
+
+        let mut micropad = Micropad {
+            transport,
+            packets: PacketDecoder::new(),
+            capabilities: Capabilities::legacy(),
+            request_id: UNSOLICITED_REQUEST_ID,
+            events: VecDeque::new(),
+        };
+        micropad.capabilities = micropad.negotiate()?;
+        Ok(micropad)
+    }
+
+    pub fn set_timeout(&mut self, timeout: Duration) -> Result<()> {
+        Ok(self.transport.set_timeout(timeout)?)
+    }
+
+    /// What the firmware reported it can do when we connected. Firmware
+    /// that predates the handshake gets `Capabilities::legacy()`.
+    pub fn capabilities(&self) -> &Capabilities {
+        &self.capabilities
+    }
+
+    pub fn supports(&self, message: &Message) -> bool {
+        self.capabilities.supports(message)
+    }
+
+    fn negotiate(&mut self) -> Result<Capabilities> {
+        match self.exchange(&Message::GetCapabilities)? {
+            (ResponseCode::Ok, ResponsePayload::Capabilities(capabilities)) => {
+                log::debug!("Micropad capabilities: {:?}", capabilities);
+                if capabilities.protocol_version > PROTOCOL_VERSION {
+                    log::warn!(
+                        "Micropad speaks protocol version {}, newer than this library's version {}. Consider updating.",
+                        capabilities.protocol_version,
+                        PROTOCOL_VERSION
+                    );
+                }
+                Ok(capabilities)
+            }
+            (ResponseCode::UnknownMessage, _) => {
+                log::debug!("Micropad firmware predates capabilities, assuming legacy messages");
+                Ok(Capabilities::legacy())
+            }
+            (code, _) => Err(Error::Response(code)),
+        }
+    }
+
+    /// Send a message, refusing up front if the firmware doesn't support
+    /// it. Error response codes are returned rather than turned into errors.
+    pub fn send(&mut self, message: &Message) -> Result<(ResponseCode, ResponsePayload)> {
+        if !self.capabilities.supports(message) {
+            return Err(Error::Unsupported(message.message_type()));
+        }
+        self.exchange(message)
+    }
+
+    /// Send a message, turning error response codes into errors.
+    fn request(&mut self, message: &Message) -> Result<ResponsePayload> {
+        match self.send(message)? {
+            (ResponseCode::Ok, payload) => Ok(payload),
+            (code, _) => Err(Error::Response(code)),
+        }
+    }
+
+    pub fn ping(&mut self) -> Result<()> {
+        self.request(&Message::Ping).map(drop)
+    }
+
+    pub fn version(&mut self) -> Result<FirmwareVersion> {
+        match self.request(&Message::GetVersion)? {
+            ResponsePayload::Version {
+                major,
+                minor,
+                patch,
+            } => Ok(FirmwareVersion {
+                major,
+                minor,
+                patch,
+            }),
+            _ => Err(unexpected_payload()),
+        }
+    }
+
+    pub fn mode_info(&mut self) -> Result<ModeInfo> {
+        match self.request(&Message::GetModeInfo)? {
+            ResponsePayload::ModeInfo {
+                built_in_mode_count,
+                user_mode_count,
+                current_mode_index,
+            } => Ok(ModeInfo {
+                built_in_mode_count,
+                user_mode_count,
+                current_mode_index,
+            }),
+            _ => Err(unexpected_payload()),
+        }
+    }
+
+    pub fn led_brightness(&mut self) -> Result<u8> {
+        match self.request(&Message::GetLedBrightness)? {
+            ResponsePayload::LedBrightness(brightness) => Ok(brightness),
+            _ => Err(unexpected_payload()),
+        }
+    }
+
+    pub fn set_led_brightness(&mut self, brightness: u8) -> Result<()> {
+        self.request(&Message::SetLedBrightness(brightness))
+            .map(drop)
+    }
+
+    pub fn debounce_time(&mut self) -> Result<u8> {
+        match self.request(&Message::GetDebounceTime)? {
+            ResponsePayload::DebounceTime(debounce_ms) => Ok(debounce_ms),
+            _ => Err(unexpected_payload()),
+        }
+    }
+
+    pub fn set_debounce_time(&mut self, debounce_ms: u8) -> Result<()> {
+        self.request(&Message::SetDebounceTime(debounce_ms))
+            .map(drop)
+    }
+
+    pub fn key_binding(&mut self, mode: u8, slot: u8) -> Result<Key> {
+        match self.request(&Message::GetKeyBinding { mode, slot })? {
+            ResponsePayload::KeyBinding(key) => Ok(key),
+            _ => Err(unexpected_payload()),
+        }
+    }
+
+    pub fn set_key_binding(&mut self, mode: u8, slot: u8, key: Key) -> Result<()> {
+        self.request(&Message::SetKeyBinding { mode, slot, key })
+            .map(drop)
+    }
+
+    pub fn slot_color(&mut self, mode: u8, slot: u8) -> Result<Color> {
+        match self.request(&Message::GetSlotColor { mode, slot })? {
+            ResponsePayload::SlotColor(color) => Ok(color),
+            _ => Err(unexpected_payload()),
+        }
+    }
+
+    pub fn set_slot_color(&mut self, mode: u8, slot: u8, color: Color) -> Result<()> {
+        self.request(&Message::SetSlotColor { mode, slot, color })
+            .map(drop)
+    }
+
+    /// One of a mode's chords, `None` if it's unused.
+    pub fn chord(&mut self, mode: u8, index: u8) -> Result<Option<Chord>> {
+        match self.request(&Message::GetChord { mode, index })? {
+            ResponsePayload::Chord(chord) => Ok(chord),
+            _ => Err(unexpected_payload()),
+        }
+    }
+
+    /// Set one of a mode's chords, or clear it with `None`.
+    pub fn set_chord(&mut self, mode: u8, index: u8, chord: Option<Chord>) -> Result<()> {
+        self.request(&Message::SetChord { mode, index, chord })
+            .map(drop)
+    }
+
+    pub fn mode_name(&mut self, mode: u8) -> Result<ModeName> {
+        match self.request(&Message::GetModeName(mode))? {
+            ResponsePayload::ModeName(name) => Ok(name),
+            _ => Err(unexpected_payload()),
+        }
+    }
+
+    /// Create a user mode, returning its index.
+    pub fn create_mode(&mut self, copy_from: u8, name: ModeName) -> Result<u8> {
+        match self.request(&Message::CreateUserMode { copy_from, name })? {
+            ResponsePayload::ModeIndex(mode) => Ok(mode),
+            _ => Err(unexpected_payload()),
+        }
+    }
+
+    pub fn delete_mode(&mut self, mode: u8) -> Result<()> {
+        self.request(&Message::DeleteUserMode(mode)).map(drop)
+    }
+
+    pub fn move_mode(&mut self, from: u8, to: u8) -> Result<()> {
+        self.request(&Message::MoveUserMode { from, to }).map(drop)
+    }
+
+    pub fn rename_mode(&mut self, mode: u8, name: ModeName) -> Result<()> {
+        self.request(&Message::RenameUserMode { mode, name })
+            .map(drop)
+    }
+
+    /// The current mode index.
+    pub fn mode(&mut self) -> Result<u8> {
+        match self.request(&Message::GetMode)? {
+            ResponsePayload::ModeIndex(mode) => Ok(mode),
+            _ => Err(unexpected_payload()),
+        }
+    }
+
+    pub fn set_mode(&mut self, mode: u8) -> Result<()> {
+        self.request(&Message::SetMode(mode)).map(drop)
+    }
+
+    pub fn save_config(&mut self) -> Result<()> {
+        self.request(&Message::SaveConfig).map(drop)
+    }
+
+    pub fn load_config(&mut self) -> Result<()> {
+        self.request(&Message::LoadConfig).map(drop)
+    }
+
+    pub fn factory_reset(&mut self) -> Result<()> {
+        self.request(&Message::FactoryReset).map(drop)
+    }
+
+    /// Start receiving events, collected with `next_event`. The micropad
+    /// drops subscriptions on its own once the port closes.
+    pub fn subscribe(&mut self, events: EventSet) -> Result<()> {
+        self.request(&Message::Subscribe(events)).map(drop)
+    }
+
+    pub fn unsubscribe(&mut self, events: EventSet) -> Result<()> {
+        self.request(&Message::Unsubscribe(events)).map(drop)
+    }
+
+    fn exchange(&mut self, message: &Message) -> Result<(ResponseCode, ResponsePayload)> {
+        let mut attempt = 1;
+        loop {
+            let request_id = self.write_request(message)?;
+            match self.read_response(request_id, message)? {
+                (ResponseCode::ChecksumError, _) if attempt < MAX_SEND_ATTEMPTS => {
+                    log::debug!("Request corrupted in transit, resending");
+                    attempt += 1;
+                }
+                response => return Ok(response),
+            }
+        }
+    }
+
+    /// Write a request under a fresh request ID, returning the ID.
+    fn write_request(&mut self, message: &Message) -> Result<u8> {
+        self.request_id = next_request_id(self.request_id);
+        for request_frame in FrameEncoder::request(self.request_id, message) {
+            self.transport
+                .write_all(Packet::encode(&request_frame).as_bytes())?;
+        }
+        Ok(self.request_id)
+    }
+
+    fn read_packet(&mut self) -> Result<Incoming> {
+        let mut byte = [0u8; 1];
+        loop {
+            self.transport.read_exact(&mut byte)?;
+            match self.packets.push(byte[0]) {
+                None => {}
+                Some(Ok(frame)) => return Ok(Incoming::Frame(frame)),
+                Some(Err(err)) => {
+                    let request_id = self.packets.last_request_id();
+                    log::debug!("Dropping packet for request {}: {}", request_id, err);
+                    return Ok(Incoming::Corrupted(request_id));
+                }
+            }
+        }
+    }
+
+    /// Keep an event for `next_event`. Events this library doesn't know
+    /// are skipped, as newer firmware may send more kinds.
+    fn queue_event(&mut self, frame: &MessageFrame) {
+        match Event::try_from(frame) {
+            Ok(event) => self.events.push_back(event),
+            Err(err) => log::debug!("Skipping unknown event {:02x?}: {}", frame.buf, err),
+        }
+    }
+
+    /// Read frames until the final frame of the response to `request_id`.
+    /// Frames answering other requests are stale leftovers, and are skipped.
+    /// A corrupted packet for `request_id` is answered as a checksum error,
+    /// so the request is sent again.
+    fn read_response(
+        &mut self,
+        request_id: u8,
+        message: &Message,
+    ) -> Result<(ResponseCode, ResponsePayload)> {
+        let mut decoder = FrameDecoder::new();
+        loop {
+            let response_frame = match self.read_packet()? {
+                Incoming::Frame(frame) => frame,
+                Incoming::Corrupted(id) if id == request_id => {
+                    return Ok((ResponseCode::ChecksumError, ResponsePayload::None));
+                }
+                Incoming::Corrupted(_) => continue,
+            };
+            log::debug!(
+                "Read response frame {}: {:02x?}",
+                response_frame.request_id,
+                response_frame.buf
+            );
+
+            // Events that arrive while waiting on a response are kept for
+            // `next_event`.
+            if Event::is_event_frame(&response_frame) {
+                self.queue_event(&response_frame);
+                continue;
+            }
+
+            // A corrupted request whose ID couldn't be recovered is most
+            // likely the one we're waiting on.
+            let unattributed_checksum_error = response_frame.request_id == UNSOLICITED_REQUEST_ID
+                && response_frame.header() == ResponseCode::ChecksumError.raw();
+            if response_frame.request_id != request_id && !unattributed_checksum_error {
+                log::debug!(
+                    "Skipping stale frame for request {}",
+                    response_frame.request_id
+                );
+                continue;
+            }
+
+            if decoder.push(&response_frame)? {
+                return Ok(decoder.into_code_and_payload(message)?);
+            }
+        }
+    }
+
+    /// Wait for the next event from a subscription, returning `None` if
+    /// none arrives before the timeout.
+    pub fn next_event(&mut self) -> Result<Option<Event>> {
+        while self.events.is_empty() {
+            let frame = match self.read_packet() {
+                Ok(Incoming::Frame(frame)) => frame,
+                Ok(Incoming::Corrupted(_)) => continue,
+                Err(Error::Io(err)) if err.kind() == io::ErrorKind::TimedOut => return Ok(None),
+                Err(err) => return Err(err),
+            };
+            if Event::is_event_frame(&frame) {
+                self.queue_event(&frame);
+            } else {
+                log::debug!("Skipping stale frame for request {}", frame.request_id);
+            }
+        }
+        Ok(self.events.pop_front())
+    }
+}
+
+/// Responses are decoded by the message they answer, so a mismatched
+/// payload means the response was garbled.
+fn unexpected_payload() -> Error {
+    Error::Protocol(micropad_protocol::ProtocolError::Framing)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use micropad_protocol::{MessageSet, Packet};
+
+    const CAPABILITIES: Capabilities = Capabilities {
+        protocol_version: PROTOCOL_VERSION,
+        frame_size: 8,
+        max_payload_size: 56,
+        supported_messages: MessageSet(!0),
+        button_count: 4,
+        encoder_count: 1,
+        led_count: 1,
+        max_user_modes: 4,
+    };
+
+    /// Plays back canned bytes from the micropad, and keeps what was sent.
+    #[derive(Default)]
+    struct Playback {
+        input: VecDeque<u8>,
+        sent: Vec<u8>,
+    }
+
+    impl io::Read for Playback {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            match self.input.pop_front() {
+                Some(byte) => {
+                    buf[0] = byte;
+                    Ok(1)
+                }
+                None => Err(io::ErrorKind::TimedOut.into()),
+            }
+        }
+    }
+
+    impl io::Write for Playback {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.sent.extend_from_slice(buf);
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    impl Transport for Playback {
+        fn clear_input(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+
+        fn set_timeout(&mut self, _timeout: Duration) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    impl Playback {
+        fn respond(&mut self, request_id: u8, code: ResponseCode, payload: &ResponsePayload) {
+            for frame in FrameEncoder::response(request_id, code, payload) {
+                self.input.extend(Packet::encode(&frame).as_bytes());
+            }
+        }
+
+        /// A packet for `frame` whose checksum doesn't match, so only its
+        /// request ID survives.
+        fn corrupt(&mut self, frame: &MessageFrame) {
+            let mut bytes = Packet::encode(frame).as_bytes().to_vec();
+            let checksum = bytes.len() - 2;
+            bytes[checksum] ^= 0x01;
+            assert_ne!(bytes[checksum], 0);
+            self.input.extend(bytes);
+        }
+
+        fn sent_packets(&self) -> usize {
+            self.sent.iter().filter(|&&byte| byte == 0).count()
+        }
+    }
+
+    /// A micropad that has already answered the capabilities request.
+    fn playback() -> Playback {
+        let mut playback = Playback::default();
+        playback.respond(
+            1,
+            ResponseCode::Ok,
+            &ResponsePayload::Capabilities(CAPABILITIES),
+        );
+        playback
+    }
+
+    fn ok_frame(request_id: u8) -> MessageFrame {
+        FrameEncoder::response(request_id, ResponseCode::Ok, &ResponsePayload::None)
+            .next()
+            .unwrap()
+    }
+
+    #[test]
+    fn corrupted_packets_for_other_requests_are_dropped() {
+        let mut playback = playback();
+        playback.corrupt(&ok_frame(7));
+        playback.corrupt(&MessageFrame::from(&Event::ButtonDown(1)));
+        playback.input.extend(&[0x05, 0xff, 0x00]);
+        playback.respond(2, ResponseCode::Ok, &ResponsePayload::None);
+
+        let mut micropad = Micropad::new(playback).unwrap();
+        micropad.ping().unwrap();
+        assert_eq!(micropad.transport.sent_packets(), 2);
+    }
+
+    #[test]
+    fn corrupted_responses_are_requested_again() {
+        let mut playback = playback();
+        playback.corrupt(&ok_frame(2));
+        playback.respond(3, ResponseCode::Ok, &ResponsePayload::None);
+
+        let mut micropad = Micropad::new(playback).unwrap();
+        micropad.ping().unwrap();
+        assert_eq!(micropad.transport.sent_packets(), 3);
+    }
+
+    #[test]
+    fn watching_outlasts_corrupted_packets() {
+        let mut playback = playback();
+        playback.corrupt(&MessageFrame::from(&Event::ButtonDown(1)));
+        playback.input.extend(&[0x05, 0xff, 0x00]);
+        playback
+            .input
+            .extend(Packet::encode(&MessageFrame::from(&Event::ButtonUp(1))).as_bytes());
+
+        let mut micropad = Micropad::new(playback).unwrap();
+        assert_eq!(micropad.next_event().unwrap(), Some(Event::ButtonUp(1)));
+        assert_eq!(micropad.next_event().unwrap(), None);
+    }
+
+    #[test]
+    fn mode_counts_past_a_byte_are_errors() {
+        let info = ModeInfo {
+            built_in_mode_count: 2,
+            user_mode_count: 253,
+            current_mode_index: 0,
+        };
+        assert_eq!(info.mode_count().unwrap(), 255);
+        let info = ModeInfo {
+            user_mode_count: 254,
+            ..info
+        };
+        assert!(info.mode_count().is_err());
+    }
+
+    #[test]
+    fn unknown_events_are_skipped() {
+        let mut playback = playback();
+        let mut unknown = MessageFrame::new();
+        unknown.buf[0] = 0x4f;
+        playback.input.extend(Packet::encode(&unknown).as_bytes());
+        playback
+            .input
+            .extend(Packet::encode(&MessageFrame::from(&Event::ButtonDown(2))).as_bytes());
+        playback.respond(2, ResponseCode::Ok, &ResponsePayload::None);
+        playback.input.extend(Packet::encode(&unknown).as_bytes());
+
+        let mut micropad = Micropad::new(playback).unwrap();
+        micropad.ping().unwrap();
+        assert_eq!(micropad.next_event().unwrap(), Some(Event::ButtonDown(2)));
+        assert_eq!(micropad.next_event().unwrap(), None);
+    }
+}
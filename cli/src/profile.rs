@@ -0,0 +1,724 @@
+//! Declarative micropad profiles, and working out which messages bring a
+//! micropad in line with one.
+//!
+//! A profile is a TOML or YAML file:
+//!
+//! ```toml
+//! led_brightness = 80
+//! debounce_ms = 10
+//!
+//! [[modes]]
+//! name = "Music"
+//!
+//! [[modes]]
+//! name = "Navigation"
+//! slots.play_pause = { key = "Return", color = "#ffffff" }
+//!
+//! [[modes]]
+//! name = "Editing"
+//! slots.encoder_clockwise = { key = "ctrl+Tab" }
+//! slots.encoder_counter_clockwise = { key = "ctrl+shift+Tab" }
+//! chords = [{ buttons = "next+previous", key = "ctrl+Z" }]
+//! ```
+//!
+//! Every setting is optional, and anything left out is left alone. If modes
+//! are listed, the first ones describe the built-in modes, and the rest are
+//! the user modes the micropad should end up with, in order. A mode's
+//! chords are listed in order too, and an empty entry, `{}`, clears one.
+//!
+//! Backups are profiles with every setting filled in, along with a
+//! `[backup]` table recording the file format and the firmware they were
+//! taken from.
+
+use std::collections::BTreeMap;
+use std::fmt;
+use std::path::Path;
+
+use micropad_protocol::{Capabilities, Chord, Color, Key, Message, ModeName};
+use serde::{Deserialize, Serialize};
+
+use crate::{format_buttons, parse_buttons, parse_color};
+
+/// Slot names, in slot order.
+pub const SLOT_NAMES: [&str; 5] = [
+    "encoder_clockwise",
+    "encoder_counter_clockwise",
+    "play_pause",
+    "next",
+    "previous",
+];
+
+pub const SLOT_COUNT: usize = SLOT_NAMES.len();
+
+/// Chords each mode has.
+pub const CHORD_COUNT: usize = 4;
+
+/// Longest debounce time the micropad accepts, in milliseconds.
+pub const MAX_DEBOUNCE_MS: u8 = 50;
+
+/// Version of the backup file layout, bumped whenever a change would stop
+/// older versions of the cli reading it correctly.
+pub const BACKUP_FORMAT: u32 = 1;
+
+#[derive(Deserialize, Serialize)]
+#[serde(deny_unknown_fields)]
+struct ProfileFile {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    backup: Option<BackupInfo>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    led_brightness: Option<u8>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    debounce_ms: Option<u8>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    current_mode: Option<u8>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    modes: Vec<ModeFile>,
+}
+
+/// Where a backup came from.
+#[derive(Clone, Deserialize, Serialize)]
+#[serde(deny_unknown_fields)]
+pub struct BackupInfo {
+    pub format: u32,
+    pub firmware_version: String,
+}
+
+#[derive(Deserialize, Serialize)]
+#[serde(deny_unknown_fields)]
+struct ModeFile {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    name: Option<String>,
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    slots: BTreeMap<String, SlotFile>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    chords: Vec<ChordFile>,
+}
+
+#[derive(Deserialize, Serialize)]
+#[serde(deny_unknown_fields)]
+struct SlotFile {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    key: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    color: Option<String>,
+}
+
+/// A chord, or an unused one when both are left out.
+#[derive(Deserialize, Serialize)]
+#[serde(deny_unknown_fields)]
+struct ChordFile {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    buttons: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    key: Option<String>,
+}
+
+enum FileFormat {
+    Toml,
+    Yaml,
+}
+
+impl FileFormat {
+    fn of(path: &Path) -> Result<FileFormat, String> {
+        match path.extension().and_then(|e| e.to_str()) {
+            Some("toml") => Ok(FileFormat::Toml),
+            Some("yaml") | Some("yml") => Ok(FileFormat::Yaml),
+            _ => Err("Profiles must be .toml, .yaml or .yml files".to_string()),
+        }
+    }
+}
+
+/// A validated profile.
+pub struct Profile {
+    pub backup: Option<BackupInfo>,
+    pub led_brightness: Option<u8>,
+    pub debounce_ms: Option<u8>,
+    pub current_mode: Option<u8>,
+    pub modes: Vec<ProfileMode>,
+}
+
+pub struct ProfileMode {
+    pub name: Option<ModeName>,
+    pub slots: [ProfileSlot; SLOT_COUNT],
+    /// The first chords, `None` for unused ones. Later chords are left
+    /// alone.
+    pub chords: Vec<Option<Chord>>,
+}
+
+#[derive(Copy, Clone, Default)]
+pub struct ProfileSlot {
+    pub key: Option<Key>,
+    pub color: Option<Color>,
+}
+
+impl Profile {
+    /// Parses a profile, picking TOML or YAML by the file extension.
+    pub fn parse(path: &Path, contents: &str) -> Result<Profile, String> {
+        let file: ProfileFile = match FileFormat::of(path)? {
+            FileFormat::Toml => toml::from_str(contents).map_err(|err| err.to_string())?,
+            FileFormat::Yaml => serde_yaml::from_str(contents).map_err(|err| err.to_string())?,
+        };
+
+        if let Some(debounce_ms) = file.debounce_ms {
+            if debounce_ms > MAX_DEBOUNCE_MS {
+                return Err(format!(
+                    "The debounce time can be at most {}ms: {}",
+                    MAX_DEBOUNCE_MS, debounce_ms
+                ));
+            }
+        }
+
+        let mut modes = Vec::with_capacity(file.modes.len());
+        for (index, mode) in file.modes.into_iter().enumerate() {
+            let name = match mode.name {
+                Some(name) => Some(ModeName::new(&name).map_err(|_| {
+                    format!("Mode {}: names can be at most 16 bytes: {}", index, name)
+                })?),
+                None => None,
+            };
+            let mut slots = [ProfileSlot::default(); SLOT_COUNT];
+            for (slot_name, slot) in mode.slots {
+                let position =
+                    SLOT_NAMES
+                        .iter()
+                        .position(|s| *s == slot_name)
+                        .ok_or_else(|| {
+                            format!(
+                                "Mode {}: unknown slot {}, slots are: {}",
+                                index,
+                                slot_name,
+                                SLOT_NAMES.join(", ")
+                            )
+                        })?;
+                if let Some(key) = slot.key {
+                    slots[position].key = Some(key.parse().map_err(|_| {
+                        format!("Mode {} slot {}: invalid key {}", index, slot_name, key)
+                    })?);
+                }
+                if let Some(color) = slot.color {
+                    slots[position].color = Some(parse_color(&color).ok_or_else(|| {
+                        format!("Mode {} slot {}: invalid color {}", index, slot_name, color)
+                    })?);
+                }
+            }
+            if mode.chords.len() > CHORD_COUNT {
+                return Err(format!(
+                    "Mode {}: modes have at most {} chords",
+                    index, CHORD_COUNT
+                ));
+            }
+            let mut chords = Vec::with_capacity(mode.chords.len());
+            for (chord_index, chord) in mode.chords.into_iter().enumerate() {
+                chords.push(match (chord.buttons, chord.key) {
+                    (None, None) => None,
+                    (Some(buttons), Some(key)) => {
+                        let chord = parse_buttons(&buttons)
+                            .zip(key.parse().ok())
+                            .and_then(|(buttons, key)| Chord::new(buttons, key).ok());
+                        Some(chord.ok_or_else(|| {
+                            format!(
+                                "Mode {} chord {}: invalid chord {} bound to {}",
+                                index, chord_index, buttons, key
+                            )
+                        })?)
+                    }
+                    _ => {
+                        return Err(format!(
+                            "Mode {} chord {}: chords need both buttons and a key",
+                            index, chord_index
+                        ))
+                    }
+                });
+            }
+            modes.push(ProfileMode {
+                name,
+                slots,
+                chords,
+            });
+        }
+
+        Ok(Profile {
+            backup: file.backup,
+            led_brightness: file.led_brightness,
+            debounce_ms: file.debounce_ms,
+            current_mode: file.current_mode,
+            modes,
+        })
+    }
+
+    /// A backup of every setting the micropad reported.
+    pub fn backup(device: &DeviceState, firmware_version: &str) -> Profile {
+        let modes = device
+            .modes
+            .iter()
+            .map(|mode| {
+                let mut slots = [ProfileSlot::default(); SLOT_COUNT];
+                for (slot, profile_slot) in slots.iter_mut().enumerate() {
+                    profile_slot.key = Some(mode.keys[slot]);
+                    profile_slot.color = mode.colors[slot];
+                }
+                ProfileMode {
+                    name: Some(mode.name),
+                    slots,
+                    chords: mode.chords.map_or(Vec::new(), |chords| chords.to_vec()),
+                }
+            })
+            .collect();
+        Profile {
+            backup: Some(BackupInfo {
+                format: BACKUP_FORMAT,
+                firmware_version: firmware_version.to_string(),
+            }),
+            led_brightness: Some(device.led_brightness),
+            debounce_ms: device.debounce_ms,
+            current_mode: Some(device.current_mode),
+            modes,
+        }
+    }
+
+    /// Writes the profile as TOML or YAML, picked by the file extension.
+    pub fn write(&self, path: &Path) -> Result<String, String> {
+        let file = ProfileFile {
+            backup: self.backup.clone(),
+            led_brightness: self.led_brightness,
+            debounce_ms: self.debounce_ms,
+            current_mode: self.current_mode,
+            modes: self
+                .modes
+                .iter()
+                .map(|mode| ModeFile {
+                    name: mode.name.map(|name| name.as_str().to_string()),
+                    slots: mode
+                        .slots
+                        .iter()
+                        .zip(&SLOT_NAMES)
+                        .filter(|(slot, _)| slot.key.is_some() || slot.color.is_some())
+                        .map(|(slot, slot_name)| {
+                            let slot_file = SlotFile {
+                                key: slot.key.map(|key| key.to_string()),
+                                color: slot.color.map(color_hex),
+                            };
+                            (slot_name.to_string(), slot_file)
+                        })
+                        .collect(),
+                    chords: mode
+                        .chords
+                        .iter()
+                        .map(|chord| ChordFile {
+                            buttons: chord.map(|chord| format_buttons(chord.buttons)),
+                            key: chord.map(|chord| chord.key.to_string()),
+                        })
+                        .collect(),
+                })
+                .collect(),
+        };
+        match FileFormat::of(path)? {
+            FileFormat::Toml => toml::to_string(&file).map_err(|err| err.to_string()),
+            FileFormat::Yaml => serde_yaml::to_string(&file).map_err(|err| err.to_string()),
+        }
+    }
+}
+
+/// Everything a profile can set, as read back from a micropad.
+pub struct DeviceState {
+    pub led_brightness: u8,
+    /// `None` when the firmware predates debouncing.
+    pub debounce_ms: Option<u8>,
+    pub built_in_mode_count: u8,
+    pub current_mode: u8,
+    pub modes: Vec<ModeState>,
+}
+
+#[derive(Clone)]
+pub struct ModeState {
+    pub name: ModeName,
+    pub keys: [Key; SLOT_COUNT],
+    /// `None` when the firmware predates slot colors.
+    pub colors: [Option<Color>; SLOT_COUNT],
+    /// `None` when the firmware predates chords.
+    pub chords: Option<[Option<Chord>; CHORD_COUNT]>,
+}
+
+/// One message needed to apply a profile.
+pub struct Change {
+    pub message: Message,
+    pub description: String,
+}
+
+impl fmt::Display for Change {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(&self.description)
+    }
+}
+
+pub fn color_hex(color: Color) -> String {
+    format!("#{:02x}{:02x}{:02x}", color.r, color.g, color.b)
+}
+
+fn describe_chord(chord: Option<Chord>) -> String {
+    match chord {
+        Some(chord) => format!("{} bound to {}", format_buttons(chord.buttons), chord.key),
+        None => "unused".to_string(),
+    }
+}
+
+/// Works out the messages that take a micropad from `device` to `profile`,
+/// in the order they must be sent. Fails if the micropad can't take every
+/// one of them, so nothing gets sent that leaves it half way there.
+pub fn plan(
+    profile: &Profile,
+    device: &DeviceState,
+    capabilities: &Capabilities,
+) -> Result<Vec<Change>, String> {
+    let built_in = device.built_in_mode_count as usize;
+    let mut changes = Vec::new();
+    let mut change = |message, description| {
+        changes.push(Change {
+            message,
+            description,
+        })
+    };
+
+    if let Some(brightness) = profile.led_brightness {
+        if brightness != device.led_brightness {
+            change(
+                Message::SetLedBrightness(brightness),
+                format!(
+                    "Set LED brightness to {}, was {}",
+                    brightness, device.led_brightness
+                ),
+            );
+        }
+    }
+
+    if let Some(debounce_ms) = profile.debounce_ms {
+        if Some(debounce_ms) != device.debounce_ms {
+            let was = device
+                .debounce_ms
+                .map_or("unknown".to_string(), |was| format!("{}ms", was));
+            change(
+                Message::SetDebounceTime(debounce_ms),
+                format!("Set debounce time to {}ms, was {}", debounce_ms, was),
+            );
+        }
+    }
+
+    let mut modes = device.modes.clone();
+    let mut current_mode = device.current_mode;
+    if !profile.modes.is_empty() {
+        if profile.modes.len() < built_in {
+            return Err(format!(
+                "The profile's modes must start with the micropad's {} built-in modes",
+                built_in
+            ));
+        }
+        let max_user_modes = capabilities.max_user_modes as usize;
+        if profile.modes.len() - built_in > max_user_modes {
+            return Err(format!(
+                "The profile has {} user modes, but the micropad can hold at most {}",
+                profile.modes.len() - built_in,
+                max_user_modes
+            ));
+        }
+        for (index, (wanted, mode)) in profile.modes.iter().zip(&modes).take(built_in).enumerate() {
+            match wanted.name {
+                Some(name) if name != mode.name => {
+                    return Err(format!(
+                        "Mode {} is the built-in {} mode, which can't be renamed to {}",
+                        index,
+                        mode.name.as_str(),
+                        name.as_str()
+                    ))
+                }
+                _ => {}
+            }
+        }
+
+        // Delete from the end, so the remaining indices don't shift
+        while modes.len() > profile.modes.len() {
+            let index = modes.len() - 1;
+            change(
+                Message::DeleteUserMode(index as u8),
+                format!("Delete mode {} ({})", index, modes[index].name.as_str()),
+            );
+            modes.pop();
+            // Deleting the current mode switches to the first one
+            if current_mode as usize == index {
+                current_mode = 0;
+            }
+        }
+        for (index, wanted) in profile.modes.iter().enumerate().skip(built_in) {
+            if index < modes.len() {
+                match wanted.name {
+                    Some(name) if name != modes[index].name => {
+                        change(
+                            Message::RenameUserMode {
+                                mode: index as u8,
+                                name,
+                            },
+                            format!(
+                                "Rename mode {} to {}, was {}",
+                                index,
+                                name.as_str(),
+                                modes[index].name.as_str()
+                            ),
+                        );
+                        modes[index].name = name;
+                    }
+                    _ => {}
+                }
+            } else {
+                // New modes start out as copies of the first mode
+                let name = wanted.name.unwrap_or_else(ModeName::empty);
+                change(
+                    Message::CreateUserMode { copy_from: 0, name },
+                    format!("Create mode {} ({})", index, name.as_str()),
+                );
+                let mut mode = modes[0].clone();
+                mode.name = name;
+                modes.push(mode);
+            }
+        }
+    }
+
+    for (index, (wanted, mode)) in profile.modes.iter().zip(&modes).enumerate() {
+        let index = index as u8;
+        for (slot, (wanted, slot_name)) in wanted.slots.iter().zip(&SLOT_NAMES).enumerate() {
+            let slot_index = slot as u8;
+            if let Some(key) = wanted.key {
+                if key != mode.keys[slot] {
+                    change(
+                        Message::SetKeyBinding {
+                            mode: index,
+                            slot: slot_index,
+                            key,
+                        },
+                        format!(
+                            "Bind mode {} {} to {}, was {}",
+                            index, slot_name, key, mode.keys[slot]
+                        ),
+                    );
+                }
+            }
+            if let Some(color) = wanted.color {
+                if Some(color) != mode.colors[slot] {
+                    let was = mode.colors[slot].map_or("unknown".to_string(), color_hex);
+                    change(
+                        Message::SetSlotColor {
+                            mode: index,
+                            slot: slot_index,
+                            color,
+                        },
+                        format!(
+                            "Color mode {} {} {}, was {}",
+                            index,
+                            slot_name,
+                            color_hex(color),
+                            was
+                        ),
+                    );
+                }
+            }
+        }
+    }
+
+    for (index, (wanted, mode)) in profile.modes.iter().zip(&modes).enumerate() {
+        for (chord_index, chord) in wanted.chords.iter().enumerate() {
+            let was = mode.chords.map(|chords| chords[chord_index]);
+            if Some(*chord) != was {
+                change(
+                    Message::SetChord {
+                        mode: index as u8,
+                        index: chord_index as u8,
+                        chord: *chord,
+                    },
+                    format!(
+                        "Set mode {} chord {} to {}, was {}",
+                        index,
+                        chord_index,
+                        describe_chord(*chord),
+                        was.map_or("unknown".to_string(), describe_chord)
+                    ),
+                );
+            }
+        }
+    }
+
+    if let Some(mode) = profile.current_mode {
+        if mode != current_mode {
+            change(
+                Message::SetMode(mode),
+                format!("Switch to mode {}, was {}", mode, current_mode),
+            );
+        }
+    }
+
+    if let Some(change) = changes
+        .iter()
+        .find(|change| !capabilities.supports(&change.message))
+    {
+        return Err(format!(
+            "The micropad's firmware doesn't support this change: {}",
+            change
+        ));
+    }
+    Ok(changes)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use micropad_protocol::{
+        MediaCode, MessageSet, MessageType, ScanCode, LEGACY_MESSAGES, PROTOCOL_VERSION,
+    };
+
+    const CAPABILITIES: Capabilities = Capabilities {
+        protocol_version: PROTOCOL_VERSION,
+        frame_size: 8,
+        max_payload_size: 56,
+        supported_messages: MessageSet(!0),
+        button_count: 4,
+        encoder_count: 1,
+        led_count: 1,
+        max_user_modes: 4,
+    };
+
+    fn mode(name: &str, key: Key) -> ModeState {
+        ModeState {
+            name: ModeName::new(name).unwrap(),
+            keys: [key; SLOT_COUNT],
+            colors: [Some(Color::new(0, 0, 255)); SLOT_COUNT],
+            chords: Some([None; CHORD_COUNT]),
+        }
+    }
+
+    /// Two built-in modes, followed by user modes named `user_modes`, with
+    /// the last mode current.
+    fn device(user_modes: &[&str]) -> DeviceState {
+        let mut modes = vec![
+            mode("Music", Key::Media(MediaCode::PLAY_PAUSE)),
+            mode("Navigation", Key::Normal(ScanCode::Return)),
+        ];
+        for name in user_modes {
+            modes.push(mode(name, Key::Normal(ScanCode::Escape)));
+        }
+        DeviceState {
+            led_brightness: 127,
+            debounce_ms: Some(5),
+            built_in_mode_count: 2,
+            current_mode: modes.len() as u8 - 1,
+            modes,
+        }
+    }
+
+    fn profile(toml: &str) -> Profile {
+        Profile::parse(Path::new("profile.toml"), toml).unwrap()
+    }
+
+    fn planned(profile: &Profile, device: &DeviceState) -> Vec<String> {
+        plan(profile, device, &CAPABILITIES)
+            .unwrap()
+            .iter()
+            .map(Change::to_string)
+            .collect()
+    }
+
+    #[test]
+    fn matching_profiles_change_nothing() {
+        let device = device(&["Editing"]);
+        assert!(planned(&profile(""), &device).is_empty());
+        assert!(planned(&Profile::backup(&device, "0.1.0"), &device).is_empty());
+    }
+
+    #[test]
+    fn extra_user_modes_are_deleted_from_the_end() {
+        let device = device(&["Editing", "Gaming", "Video"]);
+        let profile = profile(
+            r#"
+            current_mode = 0
+            modes = [{ name = "Music" }, { name = "Navigation" }, { name = "Editing" }]
+            "#,
+        );
+        // Deleting the current mode already switched to mode 0
+        assert_eq!(
+            planned(&profile, &device),
+            ["Delete mode 4 (Video)", "Delete mode 3 (Gaming)"]
+        );
+    }
+
+    #[test]
+    fn missing_user_modes_are_created() {
+        let device = device(&[]);
+        let profile = profile(
+            r#"
+            [[modes]]
+            [[modes]]
+            [[modes]]
+            name = "Editing"
+            slots.play_pause = { key = "media:PlayPause" }
+            slots.next = { key = "ctrl+Z" }
+            "#,
+        );
+        // New modes are copies of mode 0, so only differences are bound
+        assert_eq!(
+            planned(&profile, &device),
+            [
+                "Create mode 2 (Editing)",
+                "Bind mode 2 next to ctrl+Z, was media:PlayPause"
+            ]
+        );
+    }
+
+    #[test]
+    fn built_in_modes_cant_be_renamed() {
+        let device = device(&[]);
+        let profile = profile(r#"modes = [{ name = "Music" }, { name = "Arrows" }]"#);
+        assert!(plan(&profile, &device, &CAPABILITIES).is_err());
+    }
+
+    #[test]
+    fn only_changed_colors_are_set() {
+        let mut device = device(&[]);
+        device.modes[1].colors = [None; SLOT_COUNT];
+        let profile = profile(
+            r##"
+            [[modes]]
+            slots.next = { color = "#0000ff" }
+            slots.previous = { color = "#ff0000" }
+            [[modes]]
+            slots.next = { color = "#0000ff" }
+            "##,
+        );
+        assert_eq!(
+            planned(&profile, &device),
+            [
+                "Color mode 0 previous #ff0000, was #0000ff",
+                "Color mode 1 next #0000ff, was unknown"
+            ]
+        );
+    }
+
+    #[test]
+    fn user_modes_are_limited() {
+        let device = device(&[]);
+        let profile = profile("modes = [{}, {}, {}, {}, {}, {}, {}]");
+        assert!(plan(&profile, &device, &CAPABILITIES).is_err());
+        let capabilities = Capabilities {
+            max_user_modes: 5,
+            ..CAPABILITIES
+        };
+        assert_eq!(plan(&profile, &device, &capabilities).unwrap().len(), 5);
+    }
+
+    #[test]
+    fn unsupported_changes_fail_before_anything_is_sent() {
+        let device = device(&[]);
+        let profile = profile("led_brightness = 10\ndebounce_ms = 10");
+        let capabilities = Capabilities {
+            supported_messages: LEGACY_MESSAGES.with(MessageType::GetCapabilities),
+            ..CAPABILITIES
+        };
+        assert!(plan(&profile, &device, &capabilities).is_err());
+        assert_eq!(plan(&profile, &device, &CAPABILITIES).unwrap().len(), 2);
+    }
+}
@@ -0,0 +1,25 @@
+use std::io;
+use std::time::Duration;
+
+use serialport::{ClearBuffer, SerialPort};
+
+/// A byte stream to a micropad. Implemented for serial ports, and for
+/// anything else that can stand in for one, like a simulator or a test
+/// double.
+pub trait Transport: io::Read + io::Write {
+    /// Drop anything received but not yet read.
+    fn clear_input(&mut self) -> io::Result<()>;
+
+    /// How long reads wait for data before failing with `TimedOut`.
+    fn set_timeout(&mut self, timeout: Duration) -> io::Result<()>;
+}
+
+impl Transport for Box<dyn SerialPort> {
+    fn clear_input(&mut self) -> io::Result<()> {
+        Ok(self.clear(ClearBuffer::Input)?)
+    }
+
+    fn set_timeout(&mut self, timeout: Duration) -> io::Result<()> {
+        Ok(SerialPort::set_timeout(self.as_mut(), timeout)?)
+    }
+}
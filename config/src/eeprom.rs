@@ -0,0 +1,417 @@
+//! EEPROM emulation on top of pages of NOR flash.
+//!
+//! Records are appended one after another to the active page, so repeated
+//! saves spread their writes across the whole page instead of erasing it
+//! each time. Once the active page is full, the next page is erased and
+//! becomes active, cycling through all the pages in turn. Only the newest
+//! valid record is ever read back.
+//!
+//! Each page starts with an 8 byte header: a magic number, then a
+//! generation counter that goes up by one every time a page is activated,
+//! both 32 bit little endian. Records follow the header:
+//!
+//! - A 16 bit little endian length of the data.
+//! - The data, padded with 0xFF to an even length.
+//! - A CRC-16 of the length and data bytes, 16 bit little endian.
+//!
+//! The length is written first, so a record torn by a power loss still
+//! marks how far to skip, and fails its checksum. The previous record
+//! stays readable until the next one is complete.
+
+/// Errors from the flash driver, or from records that can't be stored.
+#[derive(Copy, Clone, Eq, PartialEq, Debug)]
+pub enum FlashError {
+    /// The flash controller reported a failed program or erase.
+    Hardware,
+    /// The data does not fit in a single page.
+    TooLarge,
+}
+
+/// Pages of NOR flash, addressed by byte offset from the first page. Flash
+/// reads back as 0xFF after an erase, and each half-word can be written
+/// once between erases.
+pub trait Flash {
+    /// Bytes per erasable page.
+    fn page_size(&self) -> usize;
+
+    fn page_count(&self) -> usize;
+
+    fn read(&self, offset: usize, buf: &mut [u8]);
+
+    /// Programs half-words starting at an even offset. `data` must have an
+    /// even length, and land on erased flash.
+    fn write(&mut self, offset: usize, data: &[u8]) -> Result<(), FlashError>;
+
+    fn erase_page(&mut self, page: usize) -> Result<(), FlashError>;
+}
+
+const PAGE_MAGIC: u32 = 0x4d50_4346;
+const PAGE_HEADER_SIZE: usize = 8;
+const RECORD_OVERHEAD: usize = 4;
+const ERASED_LEN: u16 = 0xFFFF;
+
+/// CRC-16 with polynomial 0x1021 and initial value 0xFFFF
+/// (CRC-16/CCITT-FALSE).
+pub fn crc16(crc: u16, bytes: &[u8]) -> u16 {
+    let mut crc = crc;
+    for byte in bytes {
+        crc ^= (*byte as u16) << 8;
+        for _ in 0..8 {
+            crc = if crc & 0x8000 != 0 {
+                (crc << 1) ^ 0x1021
+            } else {
+                crc << 1
+            };
+        }
+    }
+    crc
+}
+
+const CRC16_INIT: u16 = 0xFFFF;
+
+/// A page with a valid header, and what was found on it.
+#[derive(Copy, Clone)]
+struct PageScan {
+    page: usize,
+    generation: u32,
+    /// Offset and length of the last record whose checksum matched.
+    latest: Option<(usize, usize)>,
+    /// Offset of the first free byte, or the page size if the page can't
+    /// take any more records.
+    end: usize,
+}
+
+pub struct Eeprom<F> {
+    flash: F,
+}
+
+impl<F: Flash> Eeprom<F> {
+    pub fn new(flash: F) -> Self {
+        Self { flash }
+    }
+
+    pub fn flash(&self) -> &F {
+        &self.flash
+    }
+
+    /// Copies the newest valid record into `buf`, returning it. Returns
+    /// `None` if nothing was ever written, or the record is larger than
+    /// `buf`.
+    pub fn read<'a>(&self, buf: &'a mut [u8]) -> Option<&'a [u8]> {
+        // Normally the newest page holds the newest record, but if power
+        // was lost while switching pages it may not have any yet.
+        let mut older_than = u32::MAX;
+        while let Some(scan) = self.newest_page(older_than) {
+            if let Some((offset, len)) = scan.latest {
+                let record = buf.get_mut(..len)?;
+                self.flash
+                    .read(scan.page * self.flash.page_size() + offset + 2, record);
+                return Some(record);
+            }
+            older_than = scan.generation;
+        }
+        None
+    }
+
+    /// Appends a record, moving on to the next page once the active page
+    /// is full.
+    pub fn write(&mut self, data: &[u8]) -> Result<(), FlashError> {
+        let page_size = self.flash.page_size();
+        let record_size = RECORD_OVERHEAD + data.len() + data.len() % 2;
+        if PAGE_HEADER_SIZE + record_size > page_size || data.len() >= ERASED_LEN as usize {
+            return Err(FlashError::TooLarge);
+        }
+
+        let (page, offset) = match self.newest_page(u32::MAX) {
+            Some(scan) if scan.end + record_size <= page_size => (scan.page, scan.end),
+            active => {
+                let (page, generation) = match active {
+                    Some(scan) => (
+                        (scan.page + 1) % self.flash.page_count(),
+                        scan.generation + 1,
+                    ),
+                    None => (0, 0),
+                };
+                self.activate(page, generation)?;
+                (page, PAGE_HEADER_SIZE)
+            }
+        };
+
+        let base = page * page_size + offset;
+        let len = (data.len() as u16).to_le_bytes();
+        let crc = crc16(crc16(CRC16_INIT, &len), data);
+        self.flash.write(base, &len)?;
+
+        let even_len = data.len() - data.len() % 2;
+        self.flash.write(base + 2, &data[..even_len])?;
+        if even_len < data.len() {
+            self.flash
+                .write(base + 2 + even_len, &[data[even_len], 0xFF])?;
+        }
+        self.flash
+            .write(base + 2 + even_len + data.len() % 2 * 2, &crc.to_le_bytes())
+    }
+
+    /// Erases every page, forgetting all records.
+    pub fn erase(&mut self) -> Result<(), FlashError> {
+        for page in 0..self.flash.page_count() {
+            self.flash.erase_page(page)?;
+        }
+        Ok(())
+    }
+
+    fn activate(&mut self, page: usize, generation: u32) -> Result<(), FlashError> {
+        self.flash.erase_page(page)?;
+        let mut header = [0u8; PAGE_HEADER_SIZE];
+        header[..4].copy_from_slice(&PAGE_MAGIC.to_le_bytes());
+        header[4..].copy_from_slice(&generation.to_le_bytes());
+        self.flash.write(page * self.flash.page_size(), &header)
+    }
+
+    /// The page with the highest generation below `older_than`.
+    fn newest_page(&self, older_than: u32) -> Option<PageScan> {
+        let mut newest: Option<(usize, u32)> = None;
+        for page in 0..self.flash.page_count() {
+            let mut header = [0u8; PAGE_HEADER_SIZE];
+            self.flash.read(page * self.flash.page_size(), &mut header);
+            let magic = u32::from_le_bytes([header[0], header[1], header[2], header[3]]);
+            let generation = u32::from_le_bytes([header[4], header[5], header[6], header[7]]);
+            let is_newer = match newest {
+                Some((_, newest)) => generation > newest,
+                None => true,
+            };
+            if magic == PAGE_MAGIC && generation < older_than && is_newer {
+                newest = Some((page, generation));
+            }
+        }
+        newest.map(|(page, generation)| self.scan(page, generation))
+    }
+
+    fn scan(&self, page: usize, generation: u32) -> PageScan {
+        let page_size = self.flash.page_size();
+        let base = page * page_size;
+        let mut scan = PageScan {
+            page,
+            generation,
+            latest: None,
+            end: PAGE_HEADER_SIZE,
+        };
+
+        while scan.end + RECORD_OVERHEAD <= page_size {
+            let mut len = [0u8; 2];
+            self.flash.read(base + scan.end, &mut len);
+            if u16::from_le_bytes(len) == ERASED_LEN {
+                return scan;
+            }
+
+            let data_len = u16::from_le_bytes(len) as usize;
+            let record_size = RECORD_OVERHEAD + data_len + data_len % 2;
+            if scan.end + record_size > page_size {
+                break;
+            }
+
+            let mut crc = crc16(CRC16_INIT, &len);
+            let mut chunk = [0u8; 16];
+            let mut read = 0;
+            while read < data_len {
+                let chunk_len = (data_len - read).min(chunk.len());
+                self.flash
+                    .read(base + scan.end + 2 + read, &mut chunk[..chunk_len]);
+                crc = crc16(crc, &chunk[..chunk_len]);
+                read += chunk_len;
+            }
+            let mut stored_crc = [0u8; 2];
+            self.flash
+                .read(base + scan.end + record_size - 2, &mut stored_crc);
+            if u16::from_le_bytes(stored_crc) == crc {
+                scan.latest = Some((scan.end, data_len));
+            }
+            scan.end += record_size;
+        }
+
+        // Whatever follows can't be trusted to be erased
+        scan.end = page_size;
+        scan
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const PAGE_SIZE: usize = 64;
+    const PAGE_COUNT: usize = 2;
+
+    /// Flash in RAM, which enforces write-once half-words and can simulate
+    /// a power loss partway through a write.
+    struct RamFlash {
+        bytes: [u8; PAGE_SIZE * PAGE_COUNT],
+        erase_counts: [usize; PAGE_COUNT],
+        /// Half-words left to program before power is lost.
+        power_left: Option<usize>,
+    }
+
+    impl RamFlash {
+        fn new() -> Self {
+            Self {
+                bytes: [0xFF; PAGE_SIZE * PAGE_COUNT],
+                erase_counts: [0; PAGE_COUNT],
+                power_left: None,
+            }
+        }
+    }
+
+    impl Flash for RamFlash {
+        fn page_size(&self) -> usize {
+            PAGE_SIZE
+        }
+
+        fn page_count(&self) -> usize {
+            PAGE_COUNT
+        }
+
+        fn read(&self, offset: usize, buf: &mut [u8]) {
+            buf.copy_from_slice(&self.bytes[offset..offset + buf.len()]);
+        }
+
+        fn write(&mut self, offset: usize, data: &[u8]) -> Result<(), FlashError> {
+            assert_eq!(offset % 2, 0, "unaligned write at {}", offset);
+            assert_eq!(data.len() % 2, 0, "odd length write at {}", offset);
+            for (i, half_word) in data.chunks(2).enumerate() {
+                if let Some(power_left) = self.power_left.as_mut() {
+                    if *power_left == 0 {
+                        return Err(FlashError::Hardware);
+                    }
+                    *power_left -= 1;
+                }
+                let at = offset + i * 2;
+                assert_eq!(
+                    &self.bytes[at..at + 2],
+                    &[0xFF, 0xFF],
+                    "write to programmed flash at {}",
+                    at
+                );
+                self.bytes[at..at + 2].copy_from_slice(half_word);
+            }
+            Ok(())
+        }
+
+        fn erase_page(&mut self, page: usize) -> Result<(), FlashError> {
+            self.bytes[page * PAGE_SIZE..(page + 1) * PAGE_SIZE].fill(0xFF);
+            self.erase_counts[page] += 1;
+            Ok(())
+        }
+    }
+
+    fn read(eeprom: &Eeprom<RamFlash>) -> Option<[u8; 8]> {
+        let mut buf = [0u8; 8];
+        eeprom.read(&mut buf).map(|record| {
+            let mut out = [0u8; 8];
+            out[..record.len()].copy_from_slice(record);
+            out
+        })
+    }
+
+    #[test]
+    fn crc16_matches_reference() {
+        assert_eq!(crc16(CRC16_INIT, b"123456789"), 0x29B1);
+    }
+
+    #[test]
+    fn empty_flash_has_no_record() {
+        let eeprom = Eeprom::new(RamFlash::new());
+        assert_eq!(read(&eeprom), None);
+    }
+
+    #[test]
+    fn reads_back_the_newest_record() {
+        let mut eeprom = Eeprom::new(RamFlash::new());
+        eeprom.write(&[1, 2, 3]).unwrap();
+        assert_eq!(read(&eeprom), Some([1, 2, 3, 0, 0, 0, 0, 0]));
+        eeprom.write(&[4, 5, 6, 7]).unwrap();
+        assert_eq!(read(&eeprom), Some([4, 5, 6, 7, 0, 0, 0, 0]));
+    }
+
+    #[test]
+    fn appends_before_erasing() {
+        let mut eeprom = Eeprom::new(RamFlash::new());
+        // 8 byte header, then 8 byte records: 7 fit in a 64 byte page
+        for i in 0..7 {
+            eeprom.write(&[i, i, i, i]).unwrap();
+        }
+        assert_eq!(eeprom.flash().erase_counts, [1, 0]);
+        assert_eq!(read(&eeprom), Some([6, 6, 6, 6, 0, 0, 0, 0]));
+    }
+
+    #[test]
+    fn levels_wear_across_pages() {
+        let mut eeprom = Eeprom::new(RamFlash::new());
+        for i in 0..70 {
+            eeprom.write(&[i, i, i, i]).unwrap();
+            assert_eq!(read(&eeprom), Some([i, i, i, i, 0, 0, 0, 0]));
+        }
+        assert_eq!(eeprom.flash().erase_counts, [5, 5]);
+    }
+
+    #[test]
+    fn rejects_records_larger_than_a_page() {
+        let mut eeprom = Eeprom::new(RamFlash::new());
+        let data = [0u8; PAGE_SIZE - PAGE_HEADER_SIZE - RECORD_OVERHEAD + 1];
+        assert_eq!(eeprom.write(&data), Err(FlashError::TooLarge));
+        let data = [0u8; PAGE_SIZE - PAGE_HEADER_SIZE - RECORD_OVERHEAD];
+        assert_eq!(eeprom.write(&data), Ok(()));
+    }
+
+    #[test]
+    fn keeps_the_previous_record_after_a_torn_write() {
+        for power_left in 0..4 {
+            let mut eeprom = Eeprom::new(RamFlash::new());
+            eeprom.write(&[1, 2, 3, 4]).unwrap();
+            eeprom.flash.power_left = Some(power_left);
+            assert_eq!(eeprom.write(&[5, 6, 7, 8]), Err(FlashError::Hardware));
+            assert_eq!(read(&eeprom), Some([1, 2, 3, 4, 0, 0, 0, 0]));
+
+            // Power comes back, and the next save still lands
+            eeprom.flash.power_left = None;
+            eeprom.write(&[9, 10, 11, 12]).unwrap();
+            assert_eq!(read(&eeprom), Some([9, 10, 11, 12, 0, 0, 0, 0]));
+        }
+    }
+
+    #[test]
+    fn falls_back_to_the_older_page_after_a_torn_page_switch() {
+        let mut eeprom = Eeprom::new(RamFlash::new());
+        for i in 0..7 {
+            eeprom.write(&[i, i, i, i]).unwrap();
+        }
+        // Lose power after the new page's header, before its first record
+        // is complete
+        eeprom.flash.power_left = Some(5);
+        assert_eq!(eeprom.write(&[7, 7, 7, 7]), Err(FlashError::Hardware));
+        assert_eq!(read(&eeprom), Some([6, 6, 6, 6, 0, 0, 0, 0]));
+
+        eeprom.flash.power_left = None;
+        eeprom.write(&[8, 8, 8, 8]).unwrap();
+        assert_eq!(read(&eeprom), Some([8, 8, 8, 8, 0, 0, 0, 0]));
+    }
+
+    #[test]
+    fn ignores_corrupted_records() {
+        let mut eeprom = Eeprom::new(RamFlash::new());
+        eeprom.write(&[1, 2, 3, 4]).unwrap();
+        eeprom.write(&[5, 6, 7, 8]).unwrap();
+        // Flip a bit in the newest record's data
+        eeprom.flash.bytes[PAGE_HEADER_SIZE + 8 + 2] ^= 0x01;
+        assert_eq!(read(&eeprom), Some([1, 2, 3, 4, 0, 0, 0, 0]));
+    }
+
+    #[test]
+    fn erase_forgets_everything() {
+        let mut eeprom = Eeprom::new(RamFlash::new());
+        eeprom.write(&[1, 2, 3, 4]).unwrap();
+        eeprom.erase().unwrap();
+        assert_eq!(read(&eeprom), None);
+        eeprom.write(&[5, 6]).unwrap();
+        assert_eq!(read(&eeprom), Some([5, 6, 0, 0, 0, 0, 0, 0]));
+    }
+}
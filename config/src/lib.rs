@@ -0,0 +1,52 @@
+//! Micropad settings, and how they are kept in flash across power cycles.
+//!
+//! Kept free of any hardware dependencies, so the storage format can be
+//! tested on the host.
+
+#![no_std]
+
+pub mod eeprom;
+pub mod modes;
+pub mod record;
+
+pub use eeprom::{Eeprom, Flash, FlashError};
+pub use modes::{
+    Mode, Modes, BUILT_IN_MODE_COUNT, CHORD_BUTTONS, DEFAULT_COLORS, ENCODER_SLOTS, MAX_CHORDS,
+    MAX_USER_MODES, SLOT_COUNT,
+};
+pub use record::{RecordError, MAX_RECORD_SIZE};
+
+pub const DEFAULT_LED_BRIGHTNESS: u8 = 127;
+
+/// How long, in milliseconds, a button has to hold a new level before the
+/// change counts. Long enough for the bounce of the switches on the board.
+pub const DEFAULT_DEBOUNCE_MS: u8 = 5;
+
+/// Longest debounce time the host can set. Anything longer starts to feel
+/// like lag.
+pub const MAX_DEBOUNCE_MS: u8 = 50;
+
+/// Every setting that survives a power cycle.
+#[derive(Clone, Eq, PartialEq, Debug)]
+pub struct Config {
+    pub led_brightness: u8,
+    pub debounce_ms: u8,
+    pub modes: Modes,
+}
+
+impl Config {
+    /// Factory settings.
+    pub const fn new() -> Self {
+        Self {
+            led_brightness: DEFAULT_LED_BRIGHTNESS,
+            debounce_ms: DEFAULT_DEBOUNCE_MS,
+            modes: Modes::new(),
+        }
+    }
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Self::new()
+    }
+}
@@ -0,0 +1,266 @@
+use micropad_protocol::{Chord, Color, Key, MediaCode, ModeName, ResponseCode, ScanCode};
+
+/// Number of bindable slots in each mode.
+pub const SLOT_COUNT: usize = 5;
+
+/// Number of chords each mode can have.
+pub const MAX_CHORDS: usize = 4;
+
+/// Buttons that can be part of a chord, one bit per button number: play /
+/// pause, next and previous. The encoder button always switches modes.
+pub const CHORD_BUTTONS: u8 = 0b111;
+
+/// Slots the encoder's steps send: clockwise, then counter clockwise.
+/// Only these can scroll, as scrolling goes by the steps turned.
+pub const ENCODER_SLOTS: [usize; 2] = [0, 1];
+
+/// What each slot of a mode does: the key it sends, and the color the LED
+/// pulses. Slots are encoder clockwise, encoder counter clockwise, play /
+/// pause, next and previous. Chords send their own key while all their
+/// buttons are held.
+#[derive(Copy, Clone, Eq, PartialEq, Debug)]
+pub struct Mode {
+    pub keys: [Key; SLOT_COUNT],
+    pub colors: [Color; SLOT_COUNT],
+    pub chords: [Option<Chord>; MAX_CHORDS],
+}
+
+impl Mode {
+    /// Rebind a slot. Fails with `NotFound` for slots past `SLOT_COUNT`,
+    /// and `InvalidArgument` for scroll keys outside `ENCODER_SLOTS`.
+    pub fn set_key(&mut self, slot: u8, key: Key) -> Result<(), ResponseCode> {
+        let binding = self
+            .keys
+            .get_mut(slot as usize)
+            .ok_or(ResponseCode::NotFound)?;
+        if matches!(key, Key::Scroll(_)) && !ENCODER_SLOTS.contains(&(slot as usize)) {
+            return Err(ResponseCode::InvalidArgument);
+        }
+        *binding = key;
+        Ok(())
+    }
+
+    /// Set or clear a chord. Fails with `NotFound` for indexes past
+    /// `MAX_CHORDS`, and `InvalidArgument` for buttons outside
+    /// `CHORD_BUTTONS` or scroll keys.
+    pub fn set_chord(&mut self, index: u8, chord: Option<Chord>) -> Result<(), ResponseCode> {
+        let slot = self
+            .chords
+            .get_mut(index as usize)
+            .ok_or(ResponseCode::NotFound)?;
+        if matches!(chord, Some(chord) if chord.buttons & !CHORD_BUTTONS != 0
+            || matches!(chord.key, Key::Scroll(_)))
+        {
+            return Err(ResponseCode::InvalidArgument);
+        }
+        *slot = chord;
+        Ok(())
+    }
+}
+
+/// Slot colors every mode starts out with.
+pub const DEFAULT_COLORS: [Color; SLOT_COUNT] = [
+    Color::new(0, 255, 255),
+    Color::new(255, 0, 255),
+    Color::new(0, 0, 255),
+    Color::new(0, 255, 0),
+    Color::new(255, 0, 0),
+];
+
+pub const BUILT_IN_MODE_COUNT: usize = 2;
+pub const MAX_USER_MODES: usize = 4;
+
+const MUSIC_MODE: Mode = Mode {
+    keys: [
+        Key::Media(MediaCode::VOLUME_UP),
+        Key::Media(MediaCode::VOLUME_DOWN),
+        Key::Media(MediaCode::PLAY_PAUSE),
+        Key::Media(MediaCode::SCAN_NEXT),
+        Key::Media(MediaCode::SCAN_PREV),
+    ],
+    colors: DEFAULT_COLORS,
+    chords: [None; MAX_CHORDS],
+};
+
+const NAV_MODE: Mode = Mode {
+    keys: [
+        Key::Normal(ScanCode::DownArrow),
+        Key::Normal(ScanCode::UpArrow),
+        Key::Normal(ScanCode::Return),
+        Key::Normal(ScanCode::RightArrow),
+        Key::Normal(ScanCode::LeftArrow),
+    ],
+    colors: DEFAULT_COLORS,
+    chords: [None; MAX_CHORDS],
+};
+
+const BUILT_IN_MODE_NAMES: [&str; BUILT_IN_MODE_COUNT] = ["Music", "Navigation"];
+
+#[derive(Copy, Clone, Eq, PartialEq, Debug)]
+pub(crate) struct UserMode {
+    pub(crate) name: ModeName,
+    pub(crate) mode: Mode,
+}
+
+pub(crate) const EMPTY_USER_MODE: UserMode = UserMode {
+    name: ModeName::empty(),
+    mode: MUSIC_MODE,
+};
+
+/// The built-in modes followed by any user modes, indexed in that order,
+/// along with which one is current.
+#[derive(Clone, Eq, PartialEq, Debug)]
+pub struct Modes {
+    pub(crate) built_in: [Mode; BUILT_IN_MODE_COUNT],
+    pub(crate) user: [UserMode; MAX_USER_MODES],
+    pub(crate) user_count: u8,
+    pub(crate) current: u8,
+}
+
+impl Modes {
+    pub const fn new() -> Self {
+        Self {
+            built_in: [MUSIC_MODE, NAV_MODE],
+            user: [EMPTY_USER_MODE; MAX_USER_MODES],
+            user_count: 0,
+            current: 0,
+        }
+    }
+
+    pub fn count(&self) -> u8 {
+        BUILT_IN_MODE_COUNT as u8 + self.user_count
+    }
+
+    pub fn user_mode_count(&self) -> u8 {
+        self.user_count
+    }
+
+    pub fn current_index(&self) -> u8 {
+        self.current
+    }
+
+    pub fn current(&self) -> &Mode {
+        // The current index always refers to an existing mode
+        self.get(self.current).unwrap_or(&self.built_in[0])
+    }
+
+    /// Switch straight to a mode.
+    pub fn set_current(&mut self, index: u8) -> Result<(), ResponseCode> {
+        if index >= self.count() {
+            return Err(ResponseCode::NotFound);
+        }
+        self.current = index;
+        Ok(())
+    }
+
+    /// Walk the built-in modes, then the user modes, wrapping around.
+    pub fn next(&mut self) {
+        self.current = (self.current + 1) % self.count();
+    }
+
+    pub fn get(&self, index: u8) -> Option<&Mode> {
+        match self.locate(index)? {
+            Location::BuiltIn(i) => Some(&self.built_in[i]),
+            Location::User(i) => Some(&self.user[i].mode),
+        }
+    }
+
+    pub fn get_mut(&mut self, index: u8) -> Option<&mut Mode> {
+        match self.locate(index)? {
+            Location::BuiltIn(i) => Some(&mut self.built_in[i]),
+            Location::User(i) => Some(&mut self.user[i].mode),
+        }
+    }
+
+    pub fn name(&self, index: u8) -> Option<ModeName> {
+        match self.locate(index)? {
+            Location::BuiltIn(i) => ModeName::new(BUILT_IN_MODE_NAMES[i]).ok(),
+            Location::User(i) => Some(self.user[i].name),
+        }
+    }
+
+    /// Add a user mode with the same bindings and colors as an existing
+    /// mode, returning its index.
+    pub fn create(&mut self, copy_from: u8, name: ModeName) -> Result<u8, ResponseCode> {
+        let mode = *self.get(copy_from).ok_or(ResponseCode::NotFound)?;
+        if self.user_count as usize == MAX_USER_MODES {
+            return Err(ResponseCode::InvalidArgument);
+        }
+        self.user[self.user_count as usize] = UserMode { name, mode };
+        self.user_count += 1;
+        Ok(self.count() - 1)
+    }
+
+    /// Remove a user mode. Deleting the current mode switches back to the
+    /// first built-in mode.
+    pub fn delete(&mut self, index: u8) -> Result<(), ResponseCode> {
+        let user_index = self.user_index(index)?;
+        self.user[user_index..self.user_count as usize].rotate_left(1);
+        self.user_count -= 1;
+        if self.current == index {
+            self.current = 0;
+        } else if self.current > index {
+            self.current -= 1;
+        }
+        Ok(())
+    }
+
+    /// Move a user mode to another position among the user modes. The
+    /// current mode stays current, even if its index changes.
+    pub fn move_to(&mut self, from: u8, to: u8) -> Result<(), ResponseCode> {
+        let from_index = self.user_index(from)?;
+        let to_index = self.user_index(to)?;
+        if from_index < to_index {
+            self.user[from_index..=to_index].rotate_left(1);
+        } else {
+            self.user[to_index..=from_index].rotate_right(1);
+        }
+        if self.current == from {
+            self.current = to;
+        } else if from < self.current && self.current <= to {
+            self.current -= 1;
+        } else if to <= self.current && self.current < from {
+            self.current += 1;
+        }
+        Ok(())
+    }
+
+    pub fn rename(&mut self, index: u8, name: ModeName) -> Result<(), ResponseCode> {
+        let user_index = self.user_index(index)?;
+        self.user[user_index].name = name;
+        Ok(())
+    }
+
+    fn locate(&self, index: u8) -> Option<Location> {
+        let index = index as usize;
+        if index < BUILT_IN_MODE_COUNT {
+            Some(Location::BuiltIn(index))
+        } else if index < self.count() as usize {
+            Some(Location::User(index - BUILT_IN_MODE_COUNT))
+        } else {
+            None
+        }
+    }
+
+    /// Position of a mode among the user modes. Built-in modes can't be
+    /// deleted, moved or renamed, and fail with `InvalidArgument`.
+    fn user_index(&self, index: u8) -> Result<usize, ResponseCode> {
+        match self.locate(index) {
+            Some(Location::User(i)) => Ok(i),
+            Some(Location::BuiltIn(_)) => Err(ResponseCode::InvalidArgument),
+            None => Err(ResponseCode::NotFound),
+        }
+    }
+}
+
+impl Default for Modes {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Where a mode index points.
+enum Location {
+    BuiltIn(usize),
+    User(usize),
+}
@@ -0,0 +1,319 @@
+//! The byte layout of a saved `Config`.
+//!
+//! - Byte 1: Record version, bumped whenever the layout changes.
+//! - Byte 2: LED brightness.
+//! - Byte 3: Current mode index.
+//! - Byte 4: User mode count.
+//! - Byte 5: Debounce time, in milliseconds.
+//! - Each built-in mode: its key bindings, then its slot colors, 3 bytes
+//!   per slot each, then its chords, 4 bytes each.
+//! - Each user mode: its name, then its key bindings and slot colors.
+//!
+//! Keys, colors and names use the same encoding as the serial protocol.
+//! Checksums are added by the `Eeprom` the record is stored in.
+//!
+//! Firmware from before saved settings never wrote the settings pages, so
+//! they hold no valid record, and those micropads start from the defaults
+//! until their first save. Records of any other version are rejected.
+
+use micropad_protocol::chords::{self, ENCODED_CHORD_SIZE};
+use micropad_protocol::colors::ENCODED_COLOR_SIZE;
+use micropad_protocol::keys::ENCODED_KEY_SIZE;
+use micropad_protocol::{
+    Color, Key, ModeName, Payload, ProtocolError, ScanCode, MAX_MODE_NAME_SIZE,
+};
+
+use crate::modes::{
+    Mode, UserMode, BUILT_IN_MODE_COUNT, CHORD_BUTTONS, DEFAULT_COLORS, MAX_CHORDS, MAX_USER_MODES,
+    SLOT_COUNT,
+};
+use crate::{Config, MAX_DEBOUNCE_MS};
+
+pub const RECORD_VERSION: u8 = 1;
+
+const HEADER_SIZE: usize = 5;
+const MODE_SIZE: usize =
+    SLOT_COUNT * (ENCODED_KEY_SIZE + ENCODED_COLOR_SIZE) + MAX_CHORDS * ENCODED_CHORD_SIZE;
+const USER_MODE_SIZE: usize = 1 + MAX_MODE_NAME_SIZE + MODE_SIZE;
+
+/// Largest encoded record, in bytes.
+pub const MAX_RECORD_SIZE: usize =
+    HEADER_SIZE + BUILT_IN_MODE_COUNT * MODE_SIZE + MAX_USER_MODES * USER_MODE_SIZE;
+
+/// Reasons a stored record could not be turned back into a `Config`.
+#[derive(Copy, Clone, Eq, PartialEq, Debug)]
+pub enum RecordError {
+    /// The record was written by firmware with a different layout.
+    Version(u8),
+    /// The record ended early.
+    Truncated,
+    /// A value in the record is out of range.
+    Invalid,
+}
+
+impl From<ProtocolError> for RecordError {
+    fn from(err: ProtocolError) -> RecordError {
+        match err {
+            ProtocolError::Truncated => RecordError::Truncated,
+            _ => RecordError::Invalid,
+        }
+    }
+}
+
+impl Config {
+    /// Writes the record into `buf`, which must hold at least
+    /// `MAX_RECORD_SIZE` bytes, returning its length.
+    pub fn encode(&self, buf: &mut [u8]) -> usize {
+        let mut writer = Writer { buf, len: 0 };
+        writer.extend_from_slice(&[
+            RECORD_VERSION,
+            self.led_brightness,
+            self.modes.current,
+            self.modes.user_count,
+            self.debounce_ms,
+        ]);
+        for mode in &self.modes.built_in {
+            writer.mode(mode);
+        }
+        for user_mode in &self.modes.user[..self.modes.user_count as usize] {
+            let mut name = Payload::new();
+            user_mode.name.encode(&mut name);
+            writer.extend_from_slice(name.as_slice());
+            writer.mode(&user_mode.mode);
+        }
+        writer.len
+    }
+
+    pub fn decode(bytes: &[u8]) -> Result<Config, RecordError> {
+        let mut reader = Reader { bytes };
+        let version = reader.byte()?;
+        if version != RECORD_VERSION {
+            return Err(RecordError::Version(version));
+        }
+
+        let mut config = Config::new();
+        config.led_brightness = reader.byte()?;
+        let current = reader.byte()?;
+        let user_count = reader.byte()?;
+        if user_count as usize > MAX_USER_MODES {
+            return Err(RecordError::Invalid);
+        }
+        config.debounce_ms = reader.byte()?;
+        if config.debounce_ms > MAX_DEBOUNCE_MS {
+            return Err(RecordError::Invalid);
+        }
+
+        for mode in &mut config.modes.built_in {
+            *mode = reader.mode()?;
+        }
+        for user_mode in &mut config.modes.user[..user_count as usize] {
+            let name = ModeName::decode(reader.bytes)?;
+            reader.take(1 + name.as_str().len())?;
+            *user_mode = UserMode {
+                name,
+                mode: reader.mode()?,
+            };
+        }
+        config.modes.user_count = user_count;
+
+        if current >= config.modes.count() {
+            return Err(RecordError::Invalid);
+        }
+        config.modes.current = current;
+        Ok(config)
+    }
+}
+
+struct Writer<'a> {
+    buf: &'a mut [u8],
+    len: usize,
+}
+
+impl Writer<'_> {
+    fn extend_from_slice(&mut self, bytes: &[u8]) {
+        self.buf[self.len..self.len + bytes.len()].copy_from_slice(bytes);
+        self.len += bytes.len();
+    }
+
+    fn mode(&mut self, mode: &Mode) {
+        for key in &mode.keys {
+            self.extend_from_slice(&key.encode());
+        }
+        for color in &mode.colors {
+            self.extend_from_slice(&color.encode());
+        }
+        for chord in &mode.chords {
+            self.extend_from_slice(&chords::encode(chord));
+        }
+    }
+}
+
+struct Reader<'a> {
+    bytes: &'a [u8],
+}
+
+impl<'a> Reader<'a> {
+    fn take(&mut self, len: usize) -> Result<&'a [u8], RecordError> {
+        if self.bytes.len() < len {
+            return Err(RecordError::Truncated);
+        }
+        let (taken, rest) = self.bytes.split_at(len);
+        self.bytes = rest;
+        Ok(taken)
+    }
+
+    fn byte(&mut self) -> Result<u8, RecordError> {
+        Ok(self.take(1)?[0])
+    }
+
+    fn mode(&mut self) -> Result<Mode, RecordError> {
+        let mut mode = Mode {
+            keys: [Key::Normal(ScanCode::Return); SLOT_COUNT],
+            colors: DEFAULT_COLORS,
+            chords: [None; MAX_CHORDS],
+        };
+        for key in &mut mode.keys {
+            *key = Key::decode(self.take(ENCODED_KEY_SIZE)?)?;
+        }
+        for color in &mut mode.colors {
+            *color = Color::decode(self.take(ENCODED_COLOR_SIZE)?)?;
+        }
+        for chord in &mut mode.chords {
+            *chord = chords::decode(self.take(ENCODED_CHORD_SIZE)?)?;
+            if matches!(chord, Some(chord) if chord.buttons & !CHORD_BUTTONS != 0) {
+                return Err(RecordError::Invalid);
+            }
+        }
+        Ok(mode)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use micropad_protocol::{Chord, MediaCode, Modifiers, ScrollDirection, SystemCode};
+
+    fn customized() -> Config {
+        let mut config = Config::new();
+        config.led_brightness = 42;
+        config.debounce_ms = 12;
+        config.modes.get_mut(1).unwrap().keys[2] = Key::Media(MediaCode::MUTE);
+        config.modes.get_mut(1).unwrap().colors[4] = Color::new(1, 2, 3);
+        config
+            .modes
+            .get_mut(1)
+            .unwrap()
+            .set_chord(2, Chord::new(0b110, Key::Media(MediaCode::MUTE)).ok())
+            .unwrap();
+        config
+            .modes
+            .create(0, ModeName::new("Editing").unwrap())
+            .unwrap();
+        config.modes.create(1, ModeName::new("").unwrap()).unwrap();
+        config.modes.get_mut(3).unwrap().keys[0] = Key::Normal(ScanCode::Return);
+        config.modes.get_mut(3).unwrap().keys[1] = Key::Combo {
+            modifiers: Modifiers::LEFT_GUI,
+            scan_code: ScanCode::LeftArrow,
+        };
+        config.modes.get_mut(3).unwrap().keys[4] = Key::System(SystemCode::Sleep);
+        config.modes.get_mut(2).unwrap().keys[0] = Key::Scroll(ScrollDirection::Down);
+        config.modes.next();
+        config.modes.next();
+        config
+    }
+
+    #[test]
+    fn round_trips_defaults() {
+        let config = Config::new();
+        let mut buf = [0u8; MAX_RECORD_SIZE];
+        let len = config.encode(&mut buf);
+        assert_eq!(len, HEADER_SIZE + BUILT_IN_MODE_COUNT * MODE_SIZE);
+        assert_eq!(Config::decode(&buf[..len]), Ok(config));
+    }
+
+    #[test]
+    fn round_trips_user_modes() {
+        let config = customized();
+        let mut buf = [0u8; MAX_RECORD_SIZE];
+        let len = config.encode(&mut buf);
+        let decoded = Config::decode(&buf[..len]).unwrap();
+        assert_eq!(decoded, config);
+        assert_eq!(decoded.modes.name(2).unwrap().as_str(), "Editing");
+        assert_eq!(decoded.modes.current_index(), 2);
+    }
+
+    #[test]
+    fn fits_the_largest_config() {
+        let mut config = Config::new();
+        for _ in 0..MAX_USER_MODES {
+            config
+                .modes
+                .create(0, ModeName::new("sixteen bytes!!!").unwrap())
+                .unwrap();
+        }
+        let mut buf = [0u8; MAX_RECORD_SIZE];
+        assert_eq!(config.encode(&mut buf), MAX_RECORD_SIZE);
+        assert_eq!(Config::decode(&buf), Ok(config));
+    }
+
+    #[test]
+    fn rejects_other_versions() {
+        let mut buf = [0u8; MAX_RECORD_SIZE];
+        let len = Config::new().encode(&mut buf);
+        buf[0] = RECORD_VERSION + 1;
+        assert_eq!(
+            Config::decode(&buf[..len]),
+            Err(RecordError::Version(RECORD_VERSION + 1))
+        );
+    }
+
+    #[test]
+    fn rejects_truncated_records() {
+        let config = customized();
+        let mut buf = [0u8; MAX_RECORD_SIZE];
+        let len = config.encode(&mut buf);
+        for short in 0..len {
+            assert_eq!(
+                Config::decode(&buf[..short]),
+                Err(RecordError::Truncated),
+                "decoding {} of {} bytes",
+                short,
+                len
+            );
+        }
+    }
+
+    #[test]
+    fn rejects_out_of_range_values() {
+        let mut buf = [0u8; MAX_RECORD_SIZE];
+        let len = Config::new().encode(&mut buf);
+
+        let mut bad_mode = buf;
+        bad_mode[2] = BUILT_IN_MODE_COUNT as u8;
+        assert_eq!(Config::decode(&bad_mode[..len]), Err(RecordError::Invalid));
+
+        let mut bad_key = buf;
+        bad_key[HEADER_SIZE] = 0x7F;
+        assert_eq!(Config::decode(&bad_key[..len]), Err(RecordError::Invalid));
+
+        let mut bad_chord = buf;
+        let chord_offset = HEADER_SIZE + SLOT_COUNT * (ENCODED_KEY_SIZE + ENCODED_COLOR_SIZE);
+        bad_chord[chord_offset..chord_offset + ENCODED_CHORD_SIZE]
+            .copy_from_slice(&[0b1001, 0x01, 0x28, 0x00]);
+        assert_eq!(Config::decode(&bad_chord[..len]), Err(RecordError::Invalid));
+
+        let mut bad_debounce = buf;
+        bad_debounce[4] = MAX_DEBOUNCE_MS + 1;
+        assert_eq!(
+            Config::decode(&bad_debounce[..len]),
+            Err(RecordError::Invalid)
+        );
+
+        let mut too_many_modes = buf;
+        too_many_modes[3] = MAX_USER_MODES as u8 + 1;
+        assert_eq!(
+            Config::decode(&too_many_modes[..len]),
+            Err(RecordError::Invalid)
+        );
+    }
+}
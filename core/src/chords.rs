@@ -0,0 +1,128 @@
+use micropad_config::{CHORD_BUTTONS, MAX_CHORDS};
+use micropad_protocol::Chord;
+
+use crate::input::Scan;
+
+/// How long a button that's part of a chord waits for the rest of the
+/// chord before sending its own key.
+pub const CHORD_WINDOW_MS: u32 = 50;
+
+/// Buttons that can be chorded are numbered from 0, so one state each.
+const CHORD_BUTTON_COUNT: usize = CHORD_BUTTONS.count_ones() as usize;
+
+#[derive(Copy, Clone, Eq, PartialEq, Debug)]
+enum ButtonState {
+    Up,
+    /// Held, and waiting to see if the rest of a chord follows.
+    Waiting {
+        since: u32,
+    },
+    /// Held, and sending its own key.
+    Single,
+    /// Held as part of a chord. Stays so until released, even if the
+    /// chord ends first, so letting go of a chord doesn't send stray keys.
+    Chorded,
+}
+
+/// What the held buttons add up to on one pass.
+#[derive(Copy, Clone, Eq, PartialEq, Debug, Default)]
+pub struct Resolved {
+    /// Buttons sending their own keys, one bit per button number.
+    pub singles: u8,
+    /// Chords sending their keys, one bit per chord index.
+    pub chords: u8,
+}
+
+/// Sorts held buttons into chords and single presses. Buttons outside any
+/// of the mode's chords send their keys straight away. The rest wait up to
+/// `CHORD_WINDOW_MS` for a chord to complete, and send their own key if it
+/// doesn't, or as a tap if they're released first.
+pub struct ChordTracker {
+    buttons: [ButtonState; CHORD_BUTTON_COUNT],
+    active: u8,
+}
+
+impl ChordTracker {
+    pub const fn new() -> Self {
+        Self {
+            buttons: [ButtonState::Up; CHORD_BUTTON_COUNT],
+            active: 0,
+        }
+    }
+
+    pub fn update(&mut self, scan: &Scan, chords: &[Option<Chord>; MAX_CHORDS]) -> Resolved {
+        let held = scan.buttons & CHORD_BUTTONS;
+        let chorded = chords
+            .iter()
+            .flatten()
+            .fold(0, |buttons, chord| buttons | chord.buttons);
+        let mut resolved = Resolved::default();
+
+        for (index, chord) in chords.iter().enumerate() {
+            if !matches!(chord, Some(chord) if held & chord.buttons == chord.buttons) {
+                self.active &= !(1 << index);
+            }
+        }
+
+        for (button, state) in self.buttons.iter_mut().enumerate() {
+            let bit = 1 << button;
+            *state = match (*state, held & bit != 0) {
+                (ButtonState::Up, true) if chorded & bit != 0 => {
+                    ButtonState::Waiting { since: scan.now }
+                }
+                (ButtonState::Up, true) => ButtonState::Single,
+                (ButtonState::Waiting { .. }, false) => {
+                    resolved.singles |= bit;
+                    ButtonState::Up
+                }
+                (_, false) => ButtonState::Up,
+                (state, true) => state,
+            };
+        }
+
+        // Lower numbered chords win any buttons they share
+        for (index, chord) in chords.iter().enumerate() {
+            let chord = match chord {
+                Some(chord) if self.active & (1 << index) == 0 => chord,
+                _ => continue,
+            };
+            if held & chord.buttons != chord.buttons || !self.all_waiting(chord) {
+                continue;
+            }
+            self.active |= 1 << index;
+            for (button, state) in self.buttons.iter_mut().enumerate() {
+                if chord.contains(button as u8) {
+                    *state = ButtonState::Chorded;
+                }
+            }
+        }
+
+        for (button, state) in self.buttons.iter_mut().enumerate() {
+            if let ButtonState::Waiting { since } = *state {
+                if scan.now.wrapping_sub(since) >= CHORD_WINDOW_MS {
+                    *state = ButtonState::Single;
+                }
+            }
+            if *state == ButtonState::Single {
+                resolved.singles |= 1 << button;
+            }
+        }
+
+        resolved.chords = self.active;
+        resolved
+    }
+
+    fn all_waiting(&self, chord: &Chord) -> bool {
+        self.buttons
+            .iter()
+            .enumerate()
+            .filter(|(button, _)| chord.contains(*button as u8))
+            .all(|(_, state)| matches!(state, ButtonState::Waiting { .. }))
+    }
+}
+
+impl Default for ChordTracker {
+    fn default() -> Self {
+        Self::new()
+    }
+}
@@ -0,0 +1,284 @@
+use micropad_config::{
+    Config, Eeprom, Flash, Mode, BUILT_IN_MODE_COUNT, MAX_DEBOUNCE_MS, MAX_RECORD_SIZE,
+};
+use micropad_protocol::{Chord, Color, Key, Message, ModeName, ResponseCode, ResponsePayload};
+
+use crate::serial::Outbox;
+use crate::{CAPABILITIES, MAJOR_VERSION, MINOR_VERSION, PATCH_VERSION};
+
+/// Everything the host can change over the serial protocol.
+#[derive(Clone)]
+pub struct ControlState {
+    pub config: Config,
+}
+
+impl ControlState {
+    pub const fn new() -> Self {
+        Self {
+            config: Config::new(),
+        }
+    }
+
+    pub fn set_led_brightness(&mut self, brightness: u8) {
+        self.config.led_brightness = brightness;
+    }
+
+    pub fn get_led_brightness(&self) -> u8 {
+        self.config.led_brightness
+    }
+
+    pub fn set_debounce_time(&mut self, debounce_ms: u8) -> Result<(), ResponseCode> {
+        if debounce_ms > MAX_DEBOUNCE_MS {
+            return Err(ResponseCode::InvalidArgument);
+        }
+        self.config.debounce_ms = debounce_ms;
+        Ok(())
+    }
+
+    pub fn get_debounce_time(&self) -> u8 {
+        self.config.debounce_ms
+    }
+
+    pub fn next_mode(&mut self) {
+        self.config.modes.next();
+    }
+
+    pub fn get_mode_index(&self) -> u8 {
+        self.config.modes.current_index()
+    }
+
+    pub fn get_mode(&self) -> &Mode {
+        self.config.modes.current()
+    }
+
+    pub fn get_key_binding(&self, mode: u8, slot: u8) -> Option<Key> {
+        self.config
+            .modes
+            .get(mode)?
+            .keys
+            .get(slot as usize)
+            .copied()
+    }
+
+    /// Rebind a slot. Takes effect on the next pass of the main loop.
+    pub fn set_key_binding(&mut self, mode: u8, slot: u8, key: Key) -> Result<(), ResponseCode> {
+        self.config
+            .modes
+            .get_mut(mode)
+            .ok_or(ResponseCode::NotFound)?
+            .set_key(slot, key)
+    }
+
+    pub fn get_slot_color(&self, mode: u8, slot: u8) -> Option<Color> {
+        self.config
+            .modes
+            .get(mode)?
+            .colors
+            .get(slot as usize)
+            .copied()
+    }
+
+    pub fn set_slot_color(&mut self, mode: u8, slot: u8, color: Color) -> bool {
+        match self
+            .config
+            .modes
+            .get_mut(mode)
+            .and_then(|mode| mode.colors.get_mut(slot as usize))
+        {
+            Some(slot_color) => {
+                *slot_color = color;
+                true
+            }
+            None => false,
+        }
+    }
+
+    pub fn get_chord(&self, mode: u8, index: u8) -> Option<Option<Chord>> {
+        self.config
+            .modes
+            .get(mode)?
+            .chords
+            .get(index as usize)
+            .copied()
+    }
+
+    pub fn set_chord(
+        &mut self,
+        mode: u8,
+        index: u8,
+        chord: Option<Chord>,
+    ) -> Result<(), ResponseCode> {
+        self.config
+            .modes
+            .get_mut(mode)
+            .ok_or(ResponseCode::NotFound)?
+            .set_chord(index, chord)
+    }
+
+    pub fn get_mode_name(&self, mode: u8) -> Option<ModeName> {
+        self.config.modes.name(mode)
+    }
+}
+
+impl Default for ControlState {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Answer a request from the host. Settings messages fail with a storage
+/// error when there is no flash to keep them in.
+pub fn handle_message<F: Flash>(
+    control_state: &mut ControlState,
+    config_store: Option<&mut Eeprom<F>>,
+    outbox: &mut Outbox,
+    message: Message,
+) -> (ResponseCode, ResponsePayload) {
+    match message {
+        Message::Ping => (ResponseCode::Ok, ResponsePayload::None),
+        Message::SetLedBrightness(brightness) => {
+            control_state.set_led_brightness(brightness);
+            (ResponseCode::Ok, ResponsePayload::None)
+        }
+        Message::GetLedBrightness => (
+            ResponseCode::Ok,
+            ResponsePayload::LedBrightness(control_state.get_led_brightness()),
+        ),
+        Message::SetDebounceTime(debounce_ms) => {
+            empty_response(control_state.set_debounce_time(debounce_ms))
+        }
+        Message::GetDebounceTime => (
+            ResponseCode::Ok,
+            ResponsePayload::DebounceTime(control_state.get_debounce_time()),
+        ),
+        Message::GetModeInfo => (
+            ResponseCode::Ok,
+            ResponsePayload::ModeInfo {
+                built_in_mode_count: BUILT_IN_MODE_COUNT as u8,
+                user_mode_count: control_state.config.modes.user_mode_count(),
+                current_mode_index: control_state.get_mode_index(),
+            },
+        ),
+        Message::GetVersion => (
+            ResponseCode::Ok,
+            ResponsePayload::Version {
+                major: MAJOR_VERSION,
+                minor: MINOR_VERSION,
+                patch: PATCH_VERSION,
+            },
+        ),
+        Message::GetCapabilities => (
+            ResponseCode::Ok,
+            ResponsePayload::Capabilities(CAPABILITIES),
+        ),
+        Message::Subscribe(events) => {
+            outbox.subscribe(events);
+            (ResponseCode::Ok, ResponsePayload::None)
+        }
+        Message::Unsubscribe(events) => {
+            outbox.unsubscribe(events);
+            (ResponseCode::Ok, ResponsePayload::None)
+        }
+        Message::GetKeyBinding { mode, slot } => match control_state.get_key_binding(mode, slot) {
+            Some(key) => (ResponseCode::Ok, ResponsePayload::KeyBinding(key)),
+            None => (ResponseCode::NotFound, ResponsePayload::None),
+        },
+        Message::SetKeyBinding { mode, slot, key } => {
+            empty_response(control_state.set_key_binding(mode, slot, key))
+        }
+        Message::GetSlotColor { mode, slot } => match control_state.get_slot_color(mode, slot) {
+            Some(color) => (ResponseCode::Ok, ResponsePayload::SlotColor(color)),
+            None => (ResponseCode::NotFound, ResponsePayload::None),
+        },
+        Message::SetSlotColor { mode, slot, color } => {
+            if control_state.set_slot_color(mode, slot, color) {
+                (ResponseCode::Ok, ResponsePayload::None)
+            } else {
+                (ResponseCode::NotFound, ResponsePayload::None)
+            }
+        }
+        Message::GetChord { mode, index } => match control_state.get_chord(mode, index) {
+            Some(chord) => (ResponseCode::Ok, ResponsePayload::Chord(chord)),
+            None => (ResponseCode::NotFound, ResponsePayload::None),
+        },
+        Message::SetChord { mode, index, chord } => {
+            empty_response(control_state.set_chord(mode, index, chord))
+        }
+        Message::GetModeName(mode) => match control_state.get_mode_name(mode) {
+            Some(name) => (ResponseCode::Ok, ResponsePayload::ModeName(name)),
+            None => (ResponseCode::NotFound, ResponsePayload::None),
+        },
+        Message::CreateUserMode { copy_from, name } => {
+            match control_state.config.modes.create(copy_from, name) {
+                Ok(mode) => (ResponseCode::Ok, ResponsePayload::ModeIndex(mode)),
+                Err(code) => (code, ResponsePayload::None),
+            }
+        }
+        Message::DeleteUserMode(mode) => empty_response(control_state.config.modes.delete(mode)),
+        Message::MoveUserMode { from, to } => {
+            empty_response(control_state.config.modes.move_to(from, to))
+        }
+        Message::RenameUserMode { mode, name } => {
+            empty_response(control_state.config.modes.rename(mode, name))
+        }
+        Message::SetMode(mode) => empty_response(control_state.config.modes.set_current(mode)),
+        Message::GetMode => (
+            ResponseCode::Ok,
+            ResponsePayload::ModeIndex(control_state.get_mode_index()),
+        ),
+        Message::SaveConfig | Message::LoadConfig | Message::FactoryReset => {
+            let result = match config_store {
+                Some(config_store) => handle_config_message(config_store, control_state, &message),
+                None => Err(ResponseCode::StorageError),
+            };
+            empty_response(result)
+        }
+    }
+}
+
+/// Read back the most recently saved settings.
+pub fn load_config<F: Flash>(config_store: &Eeprom<F>) -> Result<Config, ResponseCode> {
+    let mut buf = [0u8; MAX_RECORD_SIZE];
+    let record = config_store.read(&mut buf).ok_or(ResponseCode::NotFound)?;
+    Config::decode(record).map_err(|_| ResponseCode::StorageError)
+}
+
+fn save_config<F: Flash>(
+    config_store: &mut Eeprom<F>,
+    config: &Config,
+) -> Result<(), ResponseCode> {
+    let mut buf = [0u8; MAX_RECORD_SIZE];
+    let len = config.encode(&mut buf);
+    config_store
+        .write(&buf[..len])
+        .map_err(|_| ResponseCode::StorageError)
+}
+
+fn handle_config_message<F: Flash>(
+    config_store: &mut Eeprom<F>,
+    control_state: &mut ControlState,
+    message: &Message,
+) -> Result<(), ResponseCode> {
+    match message {
+        Message::SaveConfig => save_config(config_store, &control_state.config),
+        Message::LoadConfig => {
+            control_state.config = load_config(config_store)?;
+            Ok(())
+        }
+        Message::FactoryReset => {
+            config_store
+                .erase()
+                .map_err(|_| ResponseCode::StorageError)?;
+            control_state.config = Config::new();
+            Ok(())
+        }
+        _ => Err(ResponseCode::UnknownMessage),
+    }
+}
+
+fn empty_response(result: Result<(), ResponseCode>) -> (ResponseCode, ResponsePayload) {
+    match result {
+        Ok(()) => (ResponseCode::Ok, ResponsePayload::None),
+        Err(code) => (code, ResponsePayload::None),
+    }
+}
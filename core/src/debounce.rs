@@ -0,0 +1,164 @@
+//! Filters contact bounce out of button readings.
+//!
+//! A button only changes state once its pin has read the new level for the
+//! whole debounce time. Bounce resets the wait, so a press is reported once
+//! the contacts settle, and glitches shorter than the debounce time never
+//! show up at all. Time comes from a millisecond clock, so the filter
+//! behaves the same however fast the main loop runs.
+
+/// The debounced state of one input.
+#[derive(Copy, Clone, Debug, Default)]
+pub struct Debouncer {
+    state: bool,
+    /// When the pin started reading the opposite of `state`, if it has.
+    changed_at: Option<u32>,
+}
+
+impl Debouncer {
+    pub const fn new() -> Self {
+        Self {
+            state: false,
+            changed_at: None,
+        }
+    }
+
+    /// Feed in a raw reading taken at `now`, in milliseconds from any
+    /// starting point, returning the debounced state.
+    pub fn update(&mut self, raw: bool, now: u32, debounce_ms: u8) -> bool {
+        if raw == self.state {
+            self.changed_at = None;
+            return self.state;
+        }
+        let changed_at = *self.changed_at.get_or_insert(now);
+        if now.wrapping_sub(changed_at) >= debounce_ms as u32 {
+            self.state = raw;
+            self.changed_at = None;
+        }
+        self.state
+    }
+
+    pub fn state(&self) -> bool {
+        self.state
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const DEBOUNCE_MS: u8 = 5;
+
+    /// Run a trace of `(millisecond, level)` samples through a debouncer,
+    /// returning when the debounced state changed and what to.
+    fn edges(trace: &[(u32, bool)], debounce_ms: u8) -> [Option<(u32, bool)>; 4] {
+        let mut debouncer = Debouncer::new();
+        let mut edges = [None; 4];
+        let mut count = 0;
+        for &(now, raw) in trace {
+            let previous = debouncer.state();
+            if debouncer.update(raw, now, debounce_ms) != previous {
+                edges[count] = Some((now, !previous));
+                count += 1;
+            }
+        }
+        edges
+    }
+
+    /// Expand a trace recorded as the times the pin changed level into one
+    /// sample per millisecond, the way the main loop polls it.
+    fn sampled(transitions: &[(u32, bool)], until: u32) -> [(u32, bool); 64] {
+        let mut samples = [(0, false); 64];
+        let mut level = false;
+        let mut next = 0;
+        for (now, sample) in samples.iter_mut().enumerate().take(until as usize) {
+            let now = now as u32;
+            while next < transitions.len() && transitions[next].0 <= now {
+                level = transitions[next].1;
+                next += 1;
+            }
+            *sample = (now, level);
+        }
+        samples
+    }
+
+    // A typical press and release, with a few milliseconds of bounce each
+    // way, as the times the pin changed level.
+    const PRESS_AND_RELEASE: &[(u32, bool)] = &[
+        (2, true),
+        (3, false),
+        (4, true),
+        (5, false),
+        (6, true),
+        (20, false),
+        (21, true),
+        (22, false),
+    ];
+
+    // A press that bounced for longer than usual before settling.
+    const LONG_BOUNCE: &[(u32, bool)] = &[
+        (1, true),
+        (2, false),
+        (4, true),
+        (6, false),
+        (7, true),
+        (9, false),
+        (10, true),
+    ];
+
+    #[test]
+    fn reports_one_press_and_one_release() {
+        let trace = sampled(PRESS_AND_RELEASE, 40);
+        assert_eq!(
+            edges(&trace[..40], DEBOUNCE_MS),
+            [Some((11, true)), Some((27, false)), None, None]
+        );
+    }
+
+    #[test]
+    fn waits_for_long_bounces_to_settle() {
+        let trace = sampled(LONG_BOUNCE, 30);
+        assert_eq!(
+            edges(&trace[..30], DEBOUNCE_MS),
+            [Some((15, true)), None, None, None]
+        );
+    }
+
+    #[test]
+    fn ignores_glitches_shorter_than_the_debounce_time() {
+        let trace = sampled(&[(5, true), (8, false), (20, true), (21, false)], 40);
+        assert_eq!(edges(&trace[..40], DEBOUNCE_MS), [None; 4]);
+    }
+
+    #[test]
+    fn zero_debounce_time_follows_the_pin() {
+        let trace = sampled(&[(2, true), (3, false), (4, true)], 10);
+        assert_eq!(
+            edges(&trace[..10], 0),
+            [Some((2, true)), Some((3, false)), Some((4, true)), None]
+        );
+    }
+
+    #[test]
+    fn slow_loops_still_wait_for_the_debounce_time() {
+        // Samples every 4 milliseconds, as when a key press delays the loop
+        let trace = [(0, false), (4, true), (8, true), (12, true), (16, false)];
+        assert_eq!(
+            edges(&trace, DEBOUNCE_MS),
+            [Some((12, true)), None, None, None]
+        );
+    }
+
+    #[test]
+    fn survives_the_clock_wrapping() {
+        let start = u32::MAX - 2;
+        let trace = [
+            (start, true),
+            (start.wrapping_add(3), true),
+            (start.wrapping_add(5), true),
+        ];
+        assert_eq!(
+            edges(&trace, DEBOUNCE_MS),
+            [Some((start.wrapping_add(5), true)), None, None, None]
+        );
+    }
+}
@@ -16,7 +16,7 @@ pub enum RotationAction {
  * 5. Each bit state is equal to an integer between 0 - 15 (The array length of the table below), the value is the direction of the direction the state should move.
  * 6. Any gaps in the table get assigned a value of ENC_ACTION_NONE.
  */
-static ENCODER_ACTIONS: &'static [RotationAction] = &[
+static ENCODER_ACTIONS: &[RotationAction] = &[
     RotationAction::None,
     RotationAction::CounterClockwise,
     RotationAction::Clockwise,
@@ -0,0 +1,228 @@
+use micropad_protocol::{Key, MediaCode, ScanCode, ScrollDirection};
+
+pub const KEY_REPORT_ID: u8 = 1;
+pub const MEDIA_REPORT_ID: u8 = 2;
+pub const SYSTEM_REPORT_ID: u8 = 3;
+pub const MOUSE_REPORT_ID: u8 = 4;
+
+/// Somewhere to send HID input reports, usually a USB interrupt endpoint.
+/// Reports start with their report ID.
+pub trait HidSink {
+    type Error;
+
+    /// Send a report, or `WouldBlock` while the endpoint still holds one
+    /// the host hasn't read. Reports that fail are sent again on the next
+    /// pass.
+    fn write_report(&mut self, report: &[u8]) -> nb::Result<(), Self::Error>;
+
+    /// The mouse's resolution multiplier feature report, as the host last
+    /// set it, without its report ID. Hosts that know about high
+    /// resolution scrolling turn it on for each wheel, others leave it 0.
+    fn resolution_multipliers(&self) -> u8 {
+        0
+    }
+}
+
+/// Encoder steps per detent. With high resolution scrolling on, every
+/// step is one wheel unit, and a detent one notch. Without it, the wheel
+/// moves a notch every `SCROLL_RESOLUTION` steps.
+pub const SCROLL_RESOLUTION: u8 = 4;
+
+/// Bits of the resolution multiplier feature report that turn on high
+/// resolution scrolling, for the vertical wheel and the horizontal one.
+pub const VERTICAL_HIGH_RESOLUTION: u8 = 0b0001;
+pub const HORIZONTAL_HIGH_RESOLUTION: u8 = 0b0100;
+
+/// Most keyboard keys that can be held at once.
+pub const MAX_KEYS: usize = 6;
+
+/// Most media keys that can be held at once. Each takes 2 bytes of the
+/// consumer control report, which has to fit the 8 byte endpoint.
+pub const MAX_MEDIA_KEYS: usize = 3;
+
+/// The keys held down during one pass of the main loop.
+#[derive(Copy, Clone, Eq, PartialEq, Debug)]
+pub struct HidReport {
+    current_key: usize,
+    modifiers: u8,
+    keys: [u8; MAX_KEYS],
+    current_media_key: usize,
+    media_keys: [u16; MAX_MEDIA_KEYS],
+    system_keys: u8,
+    /// Encoder steps to scroll, up on the vertical wheel and right on the
+    /// horizontal one.
+    scroll: [i32; 2],
+}
+
+impl HidReport {
+    pub const fn new() -> Self {
+        Self {
+            current_key: 0,
+            modifiers: 0,
+            keys: [0u8; MAX_KEYS],
+            current_media_key: 0,
+            media_keys: [0u16; MAX_MEDIA_KEYS],
+            system_keys: 0,
+            scroll: [0; 2],
+        }
+    }
+
+    /// Hold down a key. Keys already held, and keys past `MAX_KEYS` or
+    /// `MAX_MEDIA_KEYS`, are ignored. A combo's modifiers apply to every
+    /// key in the report, as the report only has one set of them. Scroll
+    /// keys turn the wheel one step each time they're added.
+    pub fn add_key(&mut self, key: Key) {
+        match key {
+            Key::Normal(scan_code) => self.add_scan_code(scan_code),
+            Key::Media(media_code) => self.add_media_code(media_code),
+            Key::Combo {
+                modifiers,
+                scan_code,
+            } => {
+                self.modifiers |= modifiers.0;
+                self.add_scan_code(scan_code);
+            }
+            Key::System(system_code) => self.system_keys |= system_code.bit(),
+            Key::Scroll(direction) => match direction {
+                ScrollDirection::Up => self.scroll[0] += 1,
+                ScrollDirection::Down => self.scroll[0] -= 1,
+                ScrollDirection::Right => self.scroll[1] += 1,
+                ScrollDirection::Left => self.scroll[1] -= 1,
+            },
+        }
+    }
+
+    fn add_scan_code(&mut self, scan_code: ScanCode) {
+        if self.current_key == self.keys.len()
+            || self.keys[..self.current_key].contains(&scan_code.raw())
+        {
+            return;
+        }
+        self.keys[self.current_key] = scan_code.raw();
+        self.current_key += 1;
+    }
+
+    fn add_media_code(&mut self, media_code: MediaCode) {
+        if self.current_media_key == self.media_keys.len()
+            || self.media_keys[..self.current_media_key].contains(&media_code.usage())
+        {
+            return;
+        }
+        self.media_keys[self.current_media_key] = media_code.usage();
+        self.current_media_key += 1;
+    }
+
+    pub fn reset(&mut self) {
+        *self = Self::new();
+    }
+
+    fn fill_media(&self, buf: &mut [u8; 1 + 2 * MAX_MEDIA_KEYS]) {
+        buf[0] = MEDIA_REPORT_ID;
+        for (bytes, usage) in buf[1..].chunks_exact_mut(2).zip(&self.media_keys) {
+            bytes.copy_from_slice(&usage.to_le_bytes());
+        }
+    }
+
+    fn fill_system(&self, buf: &mut [u8; 2]) {
+        buf[0] = SYSTEM_REPORT_ID;
+        buf[1] = self.system_keys;
+    }
+
+    fn fill_keys(&self, buf: &mut [u8; 2 + MAX_KEYS]) {
+        buf[0] = KEY_REPORT_ID;
+        buf[1] = self.modifiers;
+        buf[2..].copy_from_slice(&self.keys);
+    }
+}
+
+impl Default for HidReport {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// The report being built, and what the host was last sent. Each kind of
+/// report only goes out when it changes, so the host sees one press and
+/// one release however long a key is held, and a kind that fails to go
+/// out is retried until it does. The mouse report goes out whenever the
+/// wheels turn.
+pub struct Reports {
+    current: HidReport,
+    sent: HidReport,
+    /// Encoder steps scrolled but not sent yet, short of a whole wheel
+    /// unit.
+    unsent_scroll: [i32; 2],
+}
+
+impl Reports {
+    pub const fn new() -> Self {
+        Self {
+            current: HidReport::new(),
+            sent: HidReport::new(),
+            unsent_scroll: [0; 2],
+        }
+    }
+
+    pub fn current(&mut self) -> &mut HidReport {
+        &mut self.current
+    }
+
+    pub fn send_if_changed<H: HidSink>(&mut self, hid: &mut H) {
+        if self.current.media_keys != self.sent.media_keys {
+            let mut buf = [0u8; 1 + 2 * MAX_MEDIA_KEYS];
+            self.current.fill_media(&mut buf);
+            if hid.write_report(&buf).is_ok() {
+                self.sent.media_keys = self.current.media_keys;
+            }
+        }
+        if self.current.system_keys != self.sent.system_keys {
+            let mut buf = [0u8; 2];
+            self.current.fill_system(&mut buf);
+            if hid.write_report(&buf).is_ok() {
+                self.sent.system_keys = self.current.system_keys;
+            }
+        }
+        if (self.current.modifiers, self.current.keys) != (self.sent.modifiers, self.sent.keys) {
+            let mut buf = [0u8; 2 + MAX_KEYS];
+            self.current.fill_keys(&mut buf);
+            if hid.write_report(&buf).is_ok() {
+                self.sent.modifiers = self.current.modifiers;
+                self.sent.keys = self.current.keys;
+            }
+        }
+
+        let multipliers = hid.resolution_multipliers();
+        let mut wheels = [0i8; 2];
+        let mut steps = [0i32; 2];
+        for (axis, high_resolution) in [VERTICAL_HIGH_RESOLUTION, HORIZONTAL_HIGH_RESOLUTION]
+            .iter()
+            .enumerate()
+        {
+            let steps_per_unit = if multipliers & high_resolution != 0 {
+                1
+            } else {
+                SCROLL_RESOLUTION as i32
+            };
+            self.unsent_scroll[axis] += self.current.scroll[axis];
+            let units = (self.unsent_scroll[axis] / steps_per_unit).clamp(-127, 127);
+            steps[axis] = units * steps_per_unit;
+            wheels[axis] = units as i8;
+        }
+        // Steps the host didn't get stay unsent, and go out with the next
+        // report.
+        if wheels != [0, 0]
+            && hid
+                .write_report(&[MOUSE_REPORT_ID, 0, 0, wheels[0] as u8, wheels[1] as u8])
+                .is_ok()
+        {
+            self.unsent_scroll[0] -= steps[0];
+            self.unsent_scroll[1] -= steps[1];
+        }
+    }
+}
+
+impl Default for Reports {
+    fn default() -> Self {
+        Self::new()
+    }
+}
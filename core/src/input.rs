@@ -0,0 +1,111 @@
+use embedded_hal::digital::v2::InputPin;
+
+use crate::debounce::Debouncer;
+use crate::encoder::RotaryEncoder;
+use crate::{BUTTON_COUNT, ENCODER_BUTTON, NEXT_BUTTON, PLAY_PAUSE_BUTTON, PREV_BUTTON};
+
+/// What the inputs did since the previous scan.
+#[derive(Copy, Clone, Eq, PartialEq, Debug, Default)]
+pub struct Scan {
+    /// Buttons held down, one bit per button number.
+    pub buttons: u8,
+    /// Buttons held down at the previous scan.
+    pub previous_buttons: u8,
+    /// Encoder steps since the previous scan, positive for clockwise.
+    pub encoder_diff: i32,
+    /// When the scan was taken, in milliseconds.
+    pub now: u32,
+}
+
+impl Scan {
+    pub fn is_pressed(&self, button: u8) -> bool {
+        self.buttons & (1 << button) != 0
+    }
+
+    /// Whether the button went down since the previous scan.
+    pub fn was_pressed(&self, button: u8) -> bool {
+        self.is_pressed(button) && self.previous_buttons & (1 << button) == 0
+    }
+}
+
+/// The micropad's buttons and rotary encoder. The three media buttons are
+/// pulled down and read high when pressed, the encoder button is pulled up
+/// and reads low. Buttons are debounced, while the encoder's quadrature
+/// decoding already ignores bounce.
+pub struct Inputs<PlayPause, Next, Prev, EncoderButton, EncoderA, EncoderB>
+where
+    PlayPause: InputPin,
+    Next: InputPin,
+    Prev: InputPin,
+    EncoderButton: InputPin,
+    EncoderA: InputPin,
+    EncoderB: InputPin,
+{
+    play_pause: PlayPause,
+    next: Next,
+    prev: Prev,
+    encoder_button: EncoderButton,
+    encoder: RotaryEncoder<EncoderA, EncoderB>,
+    encoder_count: i32,
+    debouncers: [Debouncer; BUTTON_COUNT as usize],
+    buttons: u8,
+}
+
+impl<PlayPause, Next, Prev, EncoderButton, EncoderA, EncoderB>
+    Inputs<PlayPause, Next, Prev, EncoderButton, EncoderA, EncoderB>
+where
+    PlayPause: InputPin,
+    Next: InputPin,
+    Prev: InputPin,
+    EncoderButton: InputPin,
+    EncoderA: InputPin,
+    EncoderB: InputPin,
+{
+    pub fn new(
+        play_pause: PlayPause,
+        next: Next,
+        prev: Prev,
+        encoder_button: EncoderButton,
+        encoder: RotaryEncoder<EncoderA, EncoderB>,
+    ) -> Self {
+        Self {
+            play_pause,
+            next,
+            prev,
+            encoder_button,
+            encoder,
+            encoder_count: 0,
+            debouncers: [Debouncer::new(); BUTTON_COUNT as usize],
+            buttons: 0,
+        }
+    }
+
+    /// Sample every input once. `now` is a millisecond clock, which only
+    /// has to count up and may wrap.
+    pub fn scan(&mut self, now: u32, debounce_ms: u8) -> Scan {
+        let encoder_count = self.encoder.read_count();
+        let encoder_diff = encoder_count - self.encoder_count;
+        self.encoder_count = encoder_count;
+
+        let mut raw = [false; BUTTON_COUNT as usize];
+        raw[PLAY_PAUSE_BUTTON as usize] = self.play_pause.is_high().unwrap_or(false);
+        raw[NEXT_BUTTON as usize] = self.next.is_high().unwrap_or(false);
+        raw[PREV_BUTTON as usize] = self.prev.is_high().unwrap_or(false);
+        raw[ENCODER_BUTTON as usize] = self.encoder_button.is_low().unwrap_or(false);
+
+        let previous_buttons = self.buttons;
+        self.buttons = 0;
+        for (button, (debouncer, raw)) in self.debouncers.iter_mut().zip(raw).enumerate() {
+            if debouncer.update(raw, now, debounce_ms) {
+                self.buttons |= 1 << button;
+            }
+        }
+
+        Scan {
+            buttons: self.buttons,
+            previous_buttons,
+            encoder_diff,
+            now,
+        }
+    }
+}
@@ -0,0 +1,150 @@
+use micropad_protocol::{Color, Direction, Event, Key};
+
+use crate::chords::ChordTracker;
+use crate::control::ControlState;
+use crate::hid::{HidSink, Reports};
+use crate::input::Scan;
+use crate::led::{LEDIndicatorState, LedSink};
+use crate::serial::Outbox;
+use crate::{BUTTON_COUNT, ENCODER_BUTTON};
+
+/// Pulsed when the encoder button switches modes.
+const MODE_SWITCH_COLOR: Color = Color::new(255, 255, 0);
+
+/// The slot each chordable button sends, by button number.
+const BUTTON_SLOTS: [usize; 3] = [2, 3, 4];
+
+/// Turns scans of the inputs into key presses, mode switches, LED pulses
+/// and events for the host.
+pub struct Keypad {
+    reports: Reports,
+    chords: ChordTracker,
+    led_indicator: LEDIndicatorState,
+    previous_mode_index: u8,
+}
+
+impl Keypad {
+    pub const fn new() -> Self {
+        Self {
+            reports: Reports::new(),
+            chords: ChordTracker::new(),
+            led_indicator: LEDIndicatorState::new(),
+            previous_mode_index: 0,
+        }
+    }
+
+    /// Act on one scan, sending HID reports for any keys that changed.
+    /// Every held input adds its key to the report, so several keys can be
+    /// down at once. Returns whether a key is held down.
+    pub fn update<H: HidSink>(
+        &mut self,
+        scan: &Scan,
+        control_state: &mut ControlState,
+        outbox: &mut Outbox,
+        hid: &mut H,
+    ) -> bool {
+        let brightness = control_state.get_led_brightness();
+        let mode = *control_state.get_mode();
+
+        // The LED pulses the color of the first slot in use
+        let mut pulse_slot = None;
+        let report = self.reports.current();
+        report.reset();
+        let mut press = |slot: usize, key| {
+            report.add_key(key);
+            pulse_slot.get_or_insert(slot);
+        };
+
+        // Encoder. Scroll keys turn the wheel a step for every step
+        // turned, other keys are tapped once.
+        let steps = scan.encoder_diff.unsigned_abs();
+        if steps > 0 {
+            let slot = if scan.encoder_diff > 0 { 0 } else { 1 };
+            let key = mode.keys[slot];
+            let taps = if matches!(key, Key::Scroll(_)) {
+                steps
+            } else {
+                1
+            };
+            for _ in 0..taps {
+                press(slot, key);
+            }
+        }
+
+        let resolved = self.chords.update(scan, &mode.chords);
+        for (index, chord) in mode.chords.iter().enumerate() {
+            match chord {
+                Some(chord) if resolved.chords & (1 << index) != 0 => {
+                    // Chords pulse the color of their first button
+                    let button = chord.buttons.trailing_zeros() as usize;
+                    press(BUTTON_SLOTS[button], chord.key);
+                }
+                _ => {}
+            }
+        }
+        for (button, slot) in BUTTON_SLOTS.iter().enumerate() {
+            if resolved.singles & (1 << button) != 0 {
+                press(*slot, mode.keys[*slot]);
+            }
+        }
+
+        if let Some(slot) = pulse_slot {
+            self.led_indicator
+                .pulse_color(mode.colors[slot], brightness);
+        }
+        if scan.was_pressed(ENCODER_BUTTON) {
+            // Switching modes, don't send a HID report.
+            self.led_indicator
+                .pulse_color(MODE_SWITCH_COLOR, brightness);
+            control_state.next_mode();
+        }
+        self.reports.send_if_changed(hid);
+
+        self.push_events(scan, control_state.get_mode_index(), outbox);
+        pulse_slot.is_some()
+    }
+
+    /// Step the LED fade. Kept apart from `update`, so the LED can be
+    /// written without holding up USB.
+    pub fn write_led<L: LedSink>(&mut self, led: &mut L) {
+        self.led_indicator.write_if_blinking(led);
+    }
+
+    /// Queue events for whatever changed since the last scan.
+    fn push_events(&mut self, scan: &Scan, mode_index: u8, outbox: &mut Outbox) {
+        let changed_buttons = scan.buttons ^ scan.previous_buttons;
+        for button in 0..BUTTON_COUNT {
+            if changed_buttons & (1 << button) == 0 {
+                continue;
+            }
+            if scan.is_pressed(button) {
+                outbox.push_event(Event::ButtonDown(button));
+            } else {
+                outbox.push_event(Event::ButtonUp(button));
+            }
+        }
+
+        let direction = if scan.encoder_diff > 0 {
+            Direction::Clockwise
+        } else {
+            Direction::CounterClockwise
+        };
+        for _ in 0..scan.encoder_diff.unsigned_abs() {
+            outbox.push_event(Event::EncoderStep {
+                encoder: 0,
+                direction,
+            });
+        }
+
+        if mode_index != self.previous_mode_index {
+            outbox.push_event(Event::ModeChanged(mode_index));
+        }
+        self.previous_mode_index = mode_index;
+    }
+}
+
+impl Default for Keypad {
+    fn default() -> Self {
+        Self::new()
+    }
+}
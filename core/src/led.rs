@@ -0,0 +1,49 @@
+use micropad_protocol::Color;
+
+/// The micropad's RGB LED.
+pub trait LedSink {
+    fn write(&mut self, color: Color);
+}
+
+/// Fades the LED out after each key press, starting at the pulsed color
+/// scaled by the LED brightness.
+pub struct LEDIndicatorState {
+    color: Color,
+    phase: u16,
+}
+
+impl LEDIndicatorState {
+    pub const fn new() -> Self {
+        Self {
+            color: Color::new(0, 0, 0),
+            phase: 0,
+        }
+    }
+
+    pub fn pulse_color(&mut self, color: Color, brightness: u8) {
+        self.color = color;
+        self.phase = (brightness as u16) << 8;
+    }
+
+    /// Step the fade, writing the LED if it is still lit.
+    pub fn write_if_blinking<L: LedSink>(&mut self, led: &mut L) {
+        if self.phase > 0 {
+            self.phase = self.phase.saturating_sub(10);
+            // Scale every channel alike, so the color keeps its hue. A
+            // full channel comes out at exactly the phase's level.
+            let level = (self.phase >> 8) + 1;
+            let scale = |channel: u8| ((channel as u16 * level) >> 8) as u8;
+            led.write(Color::new(
+                scale(self.color.r),
+                scale(self.color.g),
+                scale(self.color.b),
+            ));
+        }
+    }
+}
+
+impl Default for LEDIndicatorState {
+    fn default() -> Self {
+        Self::new()
+    }
+}
@@ -0,0 +1,85 @@
+//! The parts of the micropad firmware that don't touch hardware: scanning
+//! the inputs, turning them into key presses and LED pulses, the settings
+//! the host can change, and the serial protocol it changes them over.
+//!
+//! Pins are `embedded-hal` traits, and reports and LED colors go out
+//! through `HidSink` and `LedSink`, so all of it runs and is tested on the
+//! host. Shared by the firmware and `micropad-sim`, so the simulator
+//! answers requests exactly the way a micropad does.
+
+#![no_std]
+
+pub mod chords;
+pub mod control;
+pub mod debounce;
+pub mod encoder;
+pub mod hid;
+pub mod input;
+pub mod keypad;
+pub mod led;
+pub mod serial;
+
+pub use chords::ChordTracker;
+pub use control::{handle_message, load_config, ControlState};
+pub use debounce::Debouncer;
+pub use encoder::RotaryEncoder;
+pub use hid::{HidReport, HidSink, Reports};
+pub use input::{Inputs, Scan};
+pub use keypad::Keypad;
+pub use led::{LEDIndicatorState, LedSink};
+pub use serial::{poll, Inbox, Outbox};
+
+use micropad_config::MAX_USER_MODES;
+use micropad_protocol::{
+    Capabilities, MessageSet, MessageType, FRAME_SIZE, MAX_PAYLOAD_SIZE, PROTOCOL_VERSION,
+};
+
+pub const MAJOR_VERSION: u8 = 0;
+pub const MINOR_VERSION: u8 = 1;
+pub const PATCH_VERSION: u8 = 0;
+
+pub const SUPPORTED_MESSAGES: MessageSet = MessageSet::empty()
+    .with(MessageType::Ping)
+    .with(MessageType::GetVersion)
+    .with(MessageType::SetLedBrightness)
+    .with(MessageType::GetLedBrightness)
+    .with(MessageType::GetModeInfo)
+    .with(MessageType::GetCapabilities)
+    .with(MessageType::Subscribe)
+    .with(MessageType::Unsubscribe)
+    .with(MessageType::GetKeyBinding)
+    .with(MessageType::SetKeyBinding)
+    .with(MessageType::GetModeName)
+    .with(MessageType::CreateUserMode)
+    .with(MessageType::DeleteUserMode)
+    .with(MessageType::MoveUserMode)
+    .with(MessageType::RenameUserMode)
+    .with(MessageType::SaveConfig)
+    .with(MessageType::LoadConfig)
+    .with(MessageType::FactoryReset)
+    .with(MessageType::SetMode)
+    .with(MessageType::GetMode)
+    .with(MessageType::GetSlotColor)
+    .with(MessageType::SetSlotColor)
+    .with(MessageType::SetDebounceTime)
+    .with(MessageType::GetDebounceTime)
+    .with(MessageType::GetChord)
+    .with(MessageType::SetChord);
+
+// Button numbering used in events
+pub const PLAY_PAUSE_BUTTON: u8 = 0;
+pub const NEXT_BUTTON: u8 = 1;
+pub const PREV_BUTTON: u8 = 2;
+pub const ENCODER_BUTTON: u8 = 3;
+pub const BUTTON_COUNT: u8 = 4;
+
+pub const CAPABILITIES: Capabilities = Capabilities {
+    protocol_version: PROTOCOL_VERSION,
+    frame_size: FRAME_SIZE as u8,
+    max_payload_size: MAX_PAYLOAD_SIZE as u8,
+    supported_messages: SUPPORTED_MESSAGES,
+    button_count: BUTTON_COUNT,
+    encoder_count: 1,
+    led_count: 1,
+    max_user_modes: MAX_USER_MODES as u8,
+};
@@ -0,0 +1,231 @@
+use embedded_hal::serial::{Read, Write};
+use micropad_protocol::{
+    Event, EventSet, FrameDecoder, FrameEncoder, Message, MessageFrame, Packet, PacketDecoder,
+    ProtocolError, ResponseCode, ResponsePayload, UNSOLICITED_REQUEST_ID,
+};
+
+const EVENT_QUEUE_SIZE: usize = 8;
+
+/// Answer the next request from the host, if one has arrived. Any earlier
+/// multi-frame response is finished first, so requests are answered one at
+/// a time and in order.
+pub fn poll<S, H>(serial: &mut S, inbox: &mut Inbox, outbox: &mut Outbox, handle: H)
+where
+    S: Read<u8> + Write<u8>,
+    H: FnOnce(&mut Outbox, Message) -> (ResponseCode, ResponsePayload),
+{
+    if outbox.flush(serial).is_err() {
+        return;
+    }
+
+    // Corrupted packets are answered with a checksum error, and the decoder
+    // picks up again at the next packet delimiter.
+    if let Ok((request_id, message)) = inbox.read_message(serial) {
+        // Every response echoes the request ID of the message it answers,
+        // so hosts can match them up.
+        let (code, payload) = match message {
+            Ok(message) => handle(outbox, message),
+            Err(err) => (err.response_code(), ResponsePayload::None),
+        };
+        outbox.respond(request_id, code, &payload);
+        let _ = outbox.flush(serial);
+    }
+}
+
+/// Read bytes until a complete packet has arrived. Partial packets stay in
+/// the decoder until the rest of their bytes show up on a later poll.
+pub fn read_frame<R>(
+    packets: &mut PacketDecoder,
+    reader: &mut R,
+) -> nb::Result<Result<MessageFrame, ProtocolError>, R::Error>
+where
+    R: Read<u8>,
+{
+    loop {
+        if let Some(frame) = packets.push(reader.read()?) {
+            return Ok(frame);
+        }
+    }
+}
+
+/// Reassembles requests from the host, which may span several frames.
+pub struct Inbox {
+    packets: PacketDecoder,
+    frames: FrameDecoder,
+    discarding: Option<u8>,
+}
+
+impl Inbox {
+    pub const fn new() -> Self {
+        Self {
+            packets: PacketDecoder::new(),
+            frames: FrameDecoder::new(),
+            discarding: None,
+        }
+    }
+
+    /// Read until a request is complete, returning its request ID along
+    /// with the message or the reason it was rejected. Frames of partial
+    /// requests are held until their final frame arrives.
+    pub fn read_message<R>(
+        &mut self,
+        reader: &mut R,
+    ) -> nb::Result<(u8, Result<Message, ProtocolError>), R::Error>
+    where
+        R: Read<u8>,
+    {
+        loop {
+            let frame = read_frame(&mut self.packets, reader)?;
+            let request_id = self.packets.last_request_id();
+            let frame = match frame {
+                Ok(frame) => frame,
+                Err(err) => {
+                    self.reject(request_id);
+                    return Ok((request_id, Err(err)));
+                }
+            };
+
+            // The rest of a request that was already answered with an
+            // error, so it can't be mistaken for a request of its own.
+            if self.discarding == Some(frame.request_id) {
+                if !frame.is_continued() {
+                    self.discarding = None;
+                }
+                continue;
+            }
+            self.discarding = None;
+
+            // A new request means the host gave up on the partial one
+            if self.frames.is_in_progress() && frame.request_id != self.frames.request_id() {
+                self.frames.reset();
+            }
+
+            match self.frames.push(&frame) {
+                Ok(true) => return Ok((request_id, self.frames.message())),
+                Ok(false) => {}
+                Err(err) => {
+                    self.reject(request_id);
+                    return Ok((request_id, Err(err)));
+                }
+            }
+        }
+    }
+
+    /// Drop the request, along with any of its frames still to come.
+    fn reject(&mut self, request_id: u8) {
+        self.frames.reset();
+        if request_id != UNSOLICITED_REQUEST_ID {
+            self.discarding = Some(request_id);
+        }
+    }
+}
+
+impl Default for Inbox {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Everything waiting to be written to the host: the frames of the current
+/// response, and any events for subscribed hosts. Packets are written as
+/// the serial buffer drains, so long responses go out over several USB
+/// polls. Events are only sent between packets, never in the middle of one.
+pub struct Outbox {
+    response: Option<FrameEncoder>,
+    packet: Option<Packet>,
+    offset: usize,
+    events: [Option<Event>; EVENT_QUEUE_SIZE],
+    events_head: usize,
+    events_len: usize,
+    subscriptions: EventSet,
+}
+
+impl Outbox {
+    pub const fn new() -> Self {
+        Self {
+            response: None,
+            packet: None,
+            offset: 0,
+            events: [None; EVENT_QUEUE_SIZE],
+            events_head: 0,
+            events_len: 0,
+            subscriptions: EventSet::empty(),
+        }
+    }
+
+    /// Queue a response. Callers must wait until `flush` succeeds before
+    /// handling the next message.
+    pub fn respond(&mut self, request_id: u8, code: ResponseCode, payload: &ResponsePayload) {
+        self.response = Some(FrameEncoder::response(request_id, code, payload));
+    }
+
+    pub fn subscribe(&mut self, events: EventSet) {
+        self.subscriptions = self.subscriptions.union(events);
+    }
+
+    pub fn unsubscribe(&mut self, events: EventSet) {
+        self.subscriptions = self.subscriptions.difference(events);
+        if self.subscriptions.is_empty() {
+            self.events_len = 0;
+        }
+    }
+
+    /// Queue an event if the host subscribed to it. Events are dropped
+    /// while the queue is full, which only happens when the host has
+    /// stopped reading.
+    pub fn push_event(&mut self, event: Event) {
+        if !self.subscriptions.contains(event.category()) || self.events_len == EVENT_QUEUE_SIZE {
+            return;
+        }
+        let tail = (self.events_head + self.events_len) % EVENT_QUEUE_SIZE;
+        self.events[tail] = Some(event);
+        self.events_len += 1;
+    }
+
+    fn pop_event(&mut self) -> Option<Event> {
+        if self.events_len == 0 {
+            return None;
+        }
+        let event = self.events[self.events_head].take();
+        self.events_head = (self.events_head + 1) % EVENT_QUEUE_SIZE;
+        self.events_len -= 1;
+        event
+    }
+
+    /// Write as much as the writer accepts. Returns `Ok` once everything
+    /// queued has been written.
+    pub fn flush<W>(&mut self, writer: &mut W) -> nb::Result<(), W::Error>
+    where
+        W: Write<u8>,
+    {
+        loop {
+            if let Some(ref packet) = self.packet {
+                let bytes = packet.as_bytes();
+                while self.offset < bytes.len() {
+                    writer.write(bytes[self.offset])?;
+                    self.offset += 1;
+                }
+                self.packet = None;
+                self.offset = 0;
+            }
+
+            // Responses go out ahead of any queued events
+            let response_frame = self.response.as_mut().and_then(|frames| frames.next());
+            if let Some(frame) = response_frame {
+                self.packet = Some(Packet::encode(&frame));
+            } else if let Some(event) = self.pop_event() {
+                self.response = None;
+                self.packet = Some(Packet::encode(&MessageFrame::from(&event)));
+            } else {
+                self.response = None;
+                return Ok(());
+            }
+        }
+    }
+}
+
+impl Default for Outbox {
+    fn default() -> Self {
+        Self::new()
+    }
+}
@@ -0,0 +1,883 @@
+//! Drives the core through simulated button presses and encoder turns, and
+//! checks the HID reports and LED writes that come out.
+
+use std::cell::Cell;
+use std::convert::{Infallible, TryFrom};
+use std::rc::Rc;
+
+use embedded_hal::digital::v2::InputPin;
+use embedded_hal::serial::Write;
+use micropad_config::{Eeprom, Flash, FlashError, MAX_DEBOUNCE_MS};
+use micropad_core::chords::CHORD_WINDOW_MS;
+use micropad_core::hid::{HORIZONTAL_HIGH_RESOLUTION, SCROLL_RESOLUTION, VERTICAL_HIGH_RESOLUTION};
+use micropad_core::{
+    handle_message, ControlState, HidSink, Inputs, Keypad, LedSink, Outbox, RotaryEncoder, Scan,
+    CAPABILITIES,
+};
+use micropad_protocol::{
+    Chord, Color, Direction, Event, EventSet, Key, MediaCode, Message, ModeName, Modifiers,
+    PacketDecoder, ResponseCode, ResponsePayload, ScanCode, ScrollDirection, SystemCode,
+};
+
+/// A pin whose level the test sets.
+#[derive(Clone, Default)]
+struct Pin(Rc<Cell<bool>>);
+
+impl Pin {
+    fn set(&self, high: bool) {
+        self.0.set(high);
+    }
+}
+
+impl InputPin for Pin {
+    type Error = Infallible;
+
+    fn is_high(&self) -> Result<bool, Infallible> {
+        Ok(self.0.get())
+    }
+
+    fn is_low(&self) -> Result<bool, Infallible> {
+        Ok(!self.0.get())
+    }
+}
+
+/// Reports written, the resolution multipliers the host has set, and
+/// for a flaky endpoint that refuses every other write, whether it
+/// refuses the next one.
+#[derive(Default)]
+struct RecordedReports(Vec<Vec<u8>>, u8, Option<bool>);
+
+impl HidSink for RecordedReports {
+    type Error = Infallible;
+
+    fn write_report(&mut self, report: &[u8]) -> nb::Result<(), Infallible> {
+        if let Some(refuse) = &mut self.2 {
+            *refuse = !*refuse;
+            if !*refuse {
+                return Err(nb::Error::WouldBlock);
+            }
+        }
+        self.0.push(report.to_vec());
+        Ok(())
+    }
+
+    fn resolution_multipliers(&self) -> u8 {
+        self.1
+    }
+}
+
+#[derive(Default)]
+struct RecordedLed(Vec<Color>);
+
+impl LedSink for RecordedLed {
+    fn write(&mut self, color: Color) {
+        self.0.push(color);
+    }
+}
+
+/// Bytes written to the serial port.
+#[derive(Default)]
+struct RecordedSerial(Vec<u8>);
+
+impl Write<u8> for RecordedSerial {
+    type Error = Infallible;
+
+    fn write(&mut self, byte: u8) -> nb::Result<(), Infallible> {
+        self.0.push(byte);
+        Ok(())
+    }
+
+    fn flush(&mut self) -> nb::Result<(), Infallible> {
+        Ok(())
+    }
+}
+
+struct RamFlash([u8; 2048]);
+
+impl Flash for RamFlash {
+    fn page_size(&self) -> usize {
+        1024
+    }
+
+    fn page_count(&self) -> usize {
+        2
+    }
+
+    fn read(&self, offset: usize, buf: &mut [u8]) {
+        buf.copy_from_slice(&self.0[offset..offset + buf.len()]);
+    }
+
+    fn write(&mut self, offset: usize, data: &[u8]) -> Result<(), FlashError> {
+        self.0[offset..offset + data.len()].copy_from_slice(data);
+        Ok(())
+    }
+
+    fn erase_page(&mut self, page: usize) -> Result<(), FlashError> {
+        self.0[page * 1024..(page + 1) * 1024].fill(0xFF);
+        Ok(())
+    }
+}
+
+/// Quadrature states of the encoder pins, in clockwise order.
+const CLOCKWISE: [(bool, bool); 4] = [(false, false), (true, false), (true, true), (false, true)];
+
+/// A micropad with its pins wired up to the test.
+struct Board {
+    play_pause: Pin,
+    next: Pin,
+    prev: Pin,
+    encoder_button: Pin,
+    encoder_a: Pin,
+    encoder_b: Pin,
+    encoder_phase: usize,
+    /// Milliseconds since the board started, one per tick.
+    now: u32,
+    inputs: Inputs<Pin, Pin, Pin, Pin, Pin, Pin>,
+    keypad: Keypad,
+    control_state: ControlState,
+    config_store: Eeprom<RamFlash>,
+    outbox: Outbox,
+    reports: RecordedReports,
+    led: RecordedLed,
+}
+
+impl Board {
+    fn new() -> Board {
+        let (play_pause, next, prev) = (Pin::default(), Pin::default(), Pin::default());
+        let (encoder_button, encoder_a, encoder_b) =
+            (Pin::default(), Pin::default(), Pin::default());
+        // The encoder button is pulled up, so it reads high while released
+        encoder_button.set(true);
+        let inputs = Inputs::new(
+            play_pause.clone(),
+            next.clone(),
+            prev.clone(),
+            encoder_button.clone(),
+            RotaryEncoder::new(encoder_a.clone(), encoder_b.clone()),
+        );
+        // Test presses are clean, so most tests skip debouncing
+        let mut control_state = ControlState::new();
+        control_state.set_debounce_time(0).unwrap();
+        Board {
+            play_pause,
+            next,
+            prev,
+            encoder_button,
+            encoder_a,
+            encoder_b,
+            encoder_phase: 0,
+            now: 0,
+            inputs,
+            keypad: Keypad::new(),
+            control_state,
+            config_store: Eeprom::new(RamFlash([0xFF; 2048])),
+            outbox: Outbox::new(),
+            reports: RecordedReports::default(),
+            led: RecordedLed::default(),
+        }
+    }
+
+    /// One pass of the firmware's main loop. Returns whether a key is held.
+    fn tick(&mut self) -> bool {
+        self.now = self.now.wrapping_add(1);
+        let scan = self
+            .inputs
+            .scan(self.now, self.control_state.get_debounce_time());
+        let key_pressed = self.keypad.update(
+            &scan,
+            &mut self.control_state,
+            &mut self.outbox,
+            &mut self.reports,
+        );
+        self.keypad.write_led(&mut self.led);
+        key_pressed
+    }
+
+    fn ticks(&mut self, count: usize) {
+        for _ in 0..count {
+            self.tick();
+        }
+    }
+
+    /// Move the encoder one quadrature state, one step of the count.
+    fn turn(&mut self, clockwise: bool) {
+        self.encoder_phase = if clockwise {
+            (self.encoder_phase + 1) % CLOCKWISE.len()
+        } else {
+            (self.encoder_phase + CLOCKWISE.len() - 1) % CLOCKWISE.len()
+        };
+        let (a, b) = CLOCKWISE[self.encoder_phase];
+        self.encoder_a.set(a);
+        self.encoder_b.set(b);
+    }
+
+    fn send(&mut self, message: Message) -> ResponseCode {
+        handle_message(
+            &mut self.control_state,
+            Some(&mut self.config_store),
+            &mut self.outbox,
+            message,
+        )
+        .0
+    }
+
+    fn take_reports(&mut self) -> Vec<Vec<u8>> {
+        std::mem::take(&mut self.reports.0)
+    }
+
+    /// Events the board has queued for the host.
+    fn take_events(&mut self) -> Vec<Event> {
+        let mut serial = RecordedSerial::default();
+        self.outbox.flush(&mut serial).unwrap();
+        let mut decoder = PacketDecoder::new();
+        serial
+            .0
+            .into_iter()
+            .filter_map(|byte| decoder.push(byte))
+            .map(|frame| Event::try_from(&frame.unwrap()).unwrap())
+            .collect()
+    }
+}
+
+fn media_report(codes: &[MediaCode]) -> Vec<u8> {
+    let mut report = vec![2, 0, 0, 0, 0, 0, 0];
+    for (bytes, code) in report[1..].chunks_exact_mut(2).zip(codes) {
+        bytes.copy_from_slice(&code.usage().to_le_bytes());
+    }
+    report
+}
+
+fn mouse_report(wheel: i8, pan: i8) -> Vec<u8> {
+    vec![4, 0, 0, wheel as u8, pan as u8]
+}
+
+fn key_report(keys: &[u8]) -> Vec<u8> {
+    let mut report = vec![1, 0, 0, 0, 0, 0, 0, 0];
+    report[2..2 + keys.len()].copy_from_slice(keys);
+    report
+}
+
+#[test]
+fn idle_board_sends_nothing() {
+    let mut board = Board::new();
+    board.ticks(10);
+    assert!(board.reports.0.is_empty());
+    assert!(board.led.0.is_empty());
+}
+
+#[test]
+fn held_button_sends_one_press_and_one_release() {
+    let mut board = Board::new();
+
+    board.play_pause.set(true);
+    assert!(board.tick());
+    board.ticks(5);
+    assert_eq!(
+        board.take_reports(),
+        vec![media_report(&[MediaCode::PLAY_PAUSE])]
+    );
+
+    board.play_pause.set(false);
+    assert!(!board.tick());
+    board.ticks(5);
+    assert_eq!(board.take_reports(), vec![media_report(&[])]);
+}
+
+#[test]
+fn bouncing_button_sends_one_press_and_one_release() {
+    let mut board = Board::new();
+    assert_eq!(board.send(Message::SetDebounceTime(5)), ResponseCode::Ok);
+
+    for level in [true, false, true, false, true] {
+        board.play_pause.set(level);
+        board.tick();
+    }
+    assert!(board.take_reports().is_empty());
+    board.ticks(5);
+    assert_eq!(
+        board.take_reports(),
+        vec![media_report(&[MediaCode::PLAY_PAUSE])]
+    );
+
+    for level in [false, true, false] {
+        board.play_pause.set(level);
+        board.tick();
+    }
+    board.ticks(5);
+    assert_eq!(board.take_reports(), vec![media_report(&[])]);
+}
+
+#[test]
+fn debounce_time_is_limited() {
+    let mut board = Board::new();
+    assert_eq!(
+        board.send(Message::SetDebounceTime(MAX_DEBOUNCE_MS + 1)),
+        ResponseCode::InvalidArgument
+    );
+    assert_eq!(
+        board.send(Message::SetDebounceTime(MAX_DEBOUNCE_MS)),
+        ResponseCode::Ok
+    );
+    assert_eq!(board.control_state.get_debounce_time(), MAX_DEBOUNCE_MS);
+}
+
+#[test]
+fn buttons_send_their_slot_keys() {
+    let mut board = Board::new();
+
+    for (pin, code) in [
+        (board.next.clone(), MediaCode::SCAN_NEXT),
+        (board.prev.clone(), MediaCode::SCAN_PREV),
+    ] {
+        pin.set(true);
+        board.tick();
+        pin.set(false);
+        board.tick();
+        assert_eq!(
+            board.take_reports(),
+            vec![media_report(&[code]), media_report(&[])]
+        );
+    }
+}
+
+#[test]
+fn encoder_steps_tap_keys() {
+    let mut board = Board::new();
+
+    board.turn(true);
+    assert!(board.tick());
+    assert!(!board.tick());
+    assert_eq!(
+        board.take_reports(),
+        vec![media_report(&[MediaCode::VOLUME_UP]), media_report(&[])]
+    );
+
+    board.turn(false);
+    board.ticks(2);
+    assert_eq!(
+        board.take_reports(),
+        vec![media_report(&[MediaCode::VOLUME_DOWN]), media_report(&[])]
+    );
+}
+
+#[test]
+fn encoder_button_switches_modes_once_per_press() {
+    let mut board = Board::new();
+
+    board.encoder_button.set(false);
+    board.ticks(20);
+    assert_eq!(board.control_state.get_mode_index(), 1);
+    assert!(board.reports.0.is_empty());
+    assert_eq!(board.led.0[0], Color::new(126, 126, 0));
+
+    board.encoder_button.set(true);
+    board.ticks(2);
+    board.encoder_button.set(false);
+    board.tick();
+    assert_eq!(board.control_state.get_mode_index(), 0);
+}
+
+#[test]
+fn keys_follow_the_current_mode() {
+    let mut board = Board::new();
+    board.encoder_button.set(false);
+    board.tick();
+    board.encoder_button.set(true);
+    board.tick();
+
+    board.play_pause.set(true);
+    board.tick();
+    board.play_pause.set(false);
+    board.tick();
+    assert_eq!(
+        board.take_reports(),
+        vec![key_report(&[ScanCode::Return.raw()]), key_report(&[])]
+    );
+}
+
+#[test]
+fn held_inputs_all_send_their_keys() {
+    let mut board = Board::new();
+    board.send(Message::SetMode(1));
+
+    board.play_pause.set(true);
+    board.next.set(true);
+    board.turn(true);
+    board.tick();
+    board.prev.set(true);
+    board.tick();
+    board.play_pause.set(false);
+    board.next.set(false);
+    board.prev.set(false);
+    board.tick();
+    assert_eq!(
+        board.take_reports(),
+        vec![
+            key_report(&[
+                ScanCode::DownArrow.raw(),
+                ScanCode::Return.raw(),
+                ScanCode::RightArrow.raw(),
+            ]),
+            key_report(&[
+                ScanCode::Return.raw(),
+                ScanCode::RightArrow.raw(),
+                ScanCode::LeftArrow.raw(),
+            ]),
+            key_report(&[]),
+        ]
+    );
+}
+
+#[test]
+fn held_media_keys_all_send_their_usages() {
+    let mut board = Board::new();
+    let calculator = MediaCode::from_usage(0x192).unwrap();
+    assert_eq!(
+        board.send(Message::SetKeyBinding {
+            mode: 0,
+            slot: 4,
+            key: Key::Media(calculator),
+        }),
+        ResponseCode::Ok
+    );
+
+    board.play_pause.set(true);
+    board.tick();
+    board.prev.set(true);
+    board.tick();
+    board.play_pause.set(false);
+    board.tick();
+    board.prev.set(false);
+    board.tick();
+    assert_eq!(
+        board.take_reports(),
+        vec![
+            media_report(&[MediaCode::PLAY_PAUSE]),
+            media_report(&[MediaCode::PLAY_PAUSE, calculator]),
+            media_report(&[calculator]),
+            media_report(&[]),
+        ]
+    );
+}
+
+#[test]
+fn system_keys_send_the_system_control_report() {
+    let mut board = Board::new();
+    assert_eq!(
+        board.send(Message::SetKeyBinding {
+            mode: 0,
+            slot: 2,
+            key: Key::System(SystemCode::Sleep),
+        }),
+        ResponseCode::Ok
+    );
+
+    board.play_pause.set(true);
+    board.ticks(5);
+    board.play_pause.set(false);
+    board.ticks(5);
+    assert_eq!(board.take_reports(), vec![vec![3, 0b010], vec![3, 0]]);
+}
+
+/// A board whose first mode scrolls `clockwise` and the opposite way.
+fn scrolling_board(clockwise: ScrollDirection, counter_clockwise: ScrollDirection) -> Board {
+    let mut board = Board::new();
+    for (slot, direction) in [(0, clockwise), (1, counter_clockwise)] {
+        assert_eq!(
+            board.send(Message::SetKeyBinding {
+                mode: 0,
+                slot,
+                key: Key::Scroll(direction),
+            }),
+            ResponseCode::Ok
+        );
+    }
+    board
+}
+
+#[test]
+fn encoder_scrolls_a_notch_per_detent() {
+    let mut board = scrolling_board(ScrollDirection::Down, ScrollDirection::Up);
+
+    for _ in 0..SCROLL_RESOLUTION - 1 {
+        board.turn(true);
+        board.tick();
+    }
+    assert!(board.take_reports().is_empty());
+    board.turn(true);
+    board.tick();
+    assert_eq!(board.take_reports(), vec![mouse_report(-1, 0)]);
+
+    // Part of a detent back cancels out part of one forward
+    board.turn(true);
+    board.tick();
+    board.turn(false);
+    board.tick();
+    for _ in 0..SCROLL_RESOLUTION {
+        board.turn(false);
+        board.tick();
+    }
+    assert_eq!(board.take_reports(), vec![mouse_report(1, 0)]);
+}
+
+#[test]
+fn high_resolution_scrolling_sends_every_step() {
+    let mut board = scrolling_board(ScrollDirection::Right, ScrollDirection::Left);
+    board.reports.1 = VERTICAL_HIGH_RESOLUTION;
+
+    board.turn(true);
+    board.tick();
+    assert!(board.take_reports().is_empty());
+
+    board.reports.1 = VERTICAL_HIGH_RESOLUTION | HORIZONTAL_HIGH_RESOLUTION;
+    board.turn(true);
+    board.tick();
+    board.turn(false);
+    board.tick();
+    assert_eq!(
+        board.take_reports(),
+        vec![mouse_report(0, 2), mouse_report(0, -1)]
+    );
+}
+
+#[test]
+fn refused_scrolling_is_sent_with_the_next_report() {
+    let mut board = scrolling_board(ScrollDirection::Up, ScrollDirection::Down);
+    board.reports.1 = VERTICAL_HIGH_RESOLUTION;
+    board.reports.2 = Some(true);
+
+    board.turn(true);
+    board.tick();
+    assert!(board.take_reports().is_empty());
+    board.turn(true);
+    board.tick();
+    assert_eq!(board.take_reports(), vec![mouse_report(2, 0)]);
+}
+
+#[test]
+fn every_encoder_step_sends_an_event() {
+    let mut board = Board::new();
+    board.outbox.subscribe(EventSet::ENCODERS);
+
+    let scan = Scan {
+        encoder_diff: -3,
+        now: 1,
+        ..Scan::default()
+    };
+    board.keypad.update(
+        &scan,
+        &mut board.control_state,
+        &mut board.outbox,
+        &mut board.reports,
+    );
+    let step = Event::EncoderStep {
+        encoder: 0,
+        direction: Direction::CounterClockwise,
+    };
+    assert_eq!(board.take_events(), vec![step; 3]);
+}
+
+#[test]
+fn events_wait_for_the_whole_response() {
+    let mut outbox = Outbox::new();
+    outbox.subscribe(EventSet::BUTTONS);
+    outbox.push_event(Event::ButtonDown(0));
+    outbox.respond(
+        5,
+        ResponseCode::Ok,
+        &ResponsePayload::Capabilities(CAPABILITIES),
+    );
+    outbox.push_event(Event::ButtonUp(0));
+
+    let mut serial = RecordedSerial::default();
+    outbox.flush(&mut serial).unwrap();
+    let mut decoder = PacketDecoder::new();
+    let frames: Vec<_> = serial
+        .0
+        .into_iter()
+        .filter_map(|byte| decoder.push(byte))
+        .map(Result::unwrap)
+        .collect();
+    let request_ids: Vec<_> = frames.iter().map(|frame| frame.request_id).collect();
+    assert_eq!(request_ids, [5, 5, 5, 0, 0]);
+    let events: Vec<_> = frames[3..]
+        .iter()
+        .map(|frame| Event::try_from(frame).unwrap())
+        .collect();
+    assert_eq!(events, [Event::ButtonDown(0), Event::ButtonUp(0)]);
+}
+
+#[test]
+fn scroll_keys_only_bind_to_the_encoder() {
+    let mut board = Board::new();
+    let scroll = Key::Scroll(ScrollDirection::Up);
+    assert_eq!(
+        board.send(Message::SetKeyBinding {
+            mode: 0,
+            slot: 2,
+            key: scroll,
+        }),
+        ResponseCode::InvalidArgument
+    );
+    assert_eq!(
+        board.send(Message::SetChord {
+            mode: 0,
+            index: 0,
+            chord: Chord::new(0b011, scroll).ok(),
+        }),
+        ResponseCode::InvalidArgument
+    );
+}
+
+fn chorded_board() -> Board {
+    let mut board = Board::new();
+    assert_eq!(
+        board.send(Message::SetChord {
+            mode: 0,
+            index: 0,
+            chord: Chord::new(0b110, Key::Media(MediaCode::MUTE)).ok(),
+        }),
+        ResponseCode::Ok
+    );
+    board
+}
+
+#[test]
+fn chord_sends_its_key_instead_of_the_buttons() {
+    let mut board = chorded_board();
+
+    board.next.set(true);
+    board.ticks(10);
+    board.prev.set(true);
+    board.ticks(100);
+    board.next.set(false);
+    board.tick();
+    board.prev.set(false);
+    board.tick();
+    assert_eq!(
+        board.take_reports(),
+        vec![media_report(&[MediaCode::MUTE]), media_report(&[])]
+    );
+}
+
+#[test]
+fn chord_buttons_alone_send_their_own_keys() {
+    let mut board = chorded_board();
+
+    // Held past the chord window
+    board.next.set(true);
+    board.ticks(CHORD_WINDOW_MS as usize);
+    assert!(board.take_reports().is_empty());
+    board.tick();
+    assert_eq!(
+        board.take_reports(),
+        vec![media_report(&[MediaCode::SCAN_NEXT])]
+    );
+    board.next.set(false);
+    board.tick();
+    assert_eq!(board.take_reports(), vec![media_report(&[])]);
+
+    // Tapped within it
+    board.prev.set(true);
+    board.ticks(3);
+    board.prev.set(false);
+    board.ticks(2);
+    assert_eq!(
+        board.take_reports(),
+        vec![media_report(&[MediaCode::SCAN_PREV]), media_report(&[])]
+    );
+
+    // Buttons outside the chord don't wait
+    board.play_pause.set(true);
+    board.tick();
+    assert_eq!(
+        board.take_reports(),
+        vec![media_report(&[MediaCode::PLAY_PAUSE])]
+    );
+}
+
+#[test]
+fn chords_are_limited_to_the_media_buttons() {
+    let mut board = Board::new();
+    let chord = Chord::new(0b1001, Key::Media(MediaCode::MUTE)).ok();
+    assert_eq!(
+        board.send(Message::SetChord {
+            mode: 0,
+            index: 0,
+            chord
+        }),
+        ResponseCode::InvalidArgument
+    );
+    assert_eq!(
+        board.send(Message::SetChord {
+            mode: 0,
+            index: 4,
+            chord: None
+        }),
+        ResponseCode::NotFound
+    );
+}
+
+#[test]
+fn combos_hold_their_modifiers() {
+    let mut board = Board::new();
+    board.send(Message::SetMode(1));
+    let modifiers = Modifiers::LEFT_CTRL.union(Modifiers::LEFT_SHIFT);
+    assert_eq!(
+        board.send(Message::SetKeyBinding {
+            mode: 1,
+            slot: 3,
+            key: Key::Combo {
+                modifiers,
+                scan_code: ScanCode::UpArrow,
+            },
+        }),
+        ResponseCode::Ok
+    );
+
+    board.next.set(true);
+    board.tick();
+    board.next.set(false);
+    board.tick();
+    let mut combo = key_report(&[ScanCode::UpArrow.raw()]);
+    combo[1] = modifiers.0;
+    assert_eq!(board.take_reports(), vec![combo, key_report(&[])]);
+}
+
+#[test]
+fn switching_between_media_and_normal_keys_sends_both_reports() {
+    let mut board = Board::new();
+    assert_eq!(
+        board.send(Message::SetKeyBinding {
+            mode: 0,
+            slot: 3,
+            key: Key::Normal(ScanCode::UpArrow),
+        }),
+        ResponseCode::Ok
+    );
+
+    board.play_pause.set(true);
+    board.tick();
+    board.play_pause.set(false);
+    board.next.set(true);
+    board.tick();
+    assert_eq!(
+        board.take_reports(),
+        vec![
+            media_report(&[MediaCode::PLAY_PAUSE]),
+            media_report(&[]),
+            key_report(&[ScanCode::UpArrow.raw()]),
+        ]
+    );
+}
+
+#[test]
+fn refused_reports_are_sent_on_the_next_pass() {
+    let mut board = Board::new();
+    board.reports.2 = Some(true);
+    assert_eq!(
+        board.send(Message::SetKeyBinding {
+            mode: 0,
+            slot: 3,
+            key: Key::Normal(ScanCode::UpArrow),
+        }),
+        ResponseCode::Ok
+    );
+
+    board.play_pause.set(true);
+    board.tick();
+    assert!(board.take_reports().is_empty());
+    board.ticks(5);
+    assert_eq!(
+        board.take_reports(),
+        vec![media_report(&[MediaCode::PLAY_PAUSE])]
+    );
+
+    board.play_pause.set(false);
+    board.next.set(true);
+    board.ticks(5);
+    board.next.set(false);
+    board.ticks(5);
+    assert_eq!(
+        board.take_reports(),
+        vec![
+            key_report(&[ScanCode::UpArrow.raw()]),
+            media_report(&[]),
+            key_report(&[]),
+        ]
+    );
+}
+
+#[test]
+fn user_modes_are_limited_to_the_reported_capacity() {
+    let mut board = Board::new();
+    let create = || Message::CreateUserMode {
+        copy_from: 0,
+        name: ModeName::empty(),
+    };
+
+    for _ in 0..CAPABILITIES.max_user_modes {
+        assert_eq!(board.send(create()), ResponseCode::Ok);
+    }
+    assert_eq!(board.send(create()), ResponseCode::InvalidArgument);
+}
+
+#[test]
+fn led_pulses_the_slot_color_and_fades_out() {
+    let mut board = Board::new();
+    board.send(Message::SetLedBrightness(20));
+    board.send(Message::SetSlotColor {
+        mode: 0,
+        slot: 2,
+        color: Color::new(0, 10, 200),
+    });
+
+    board.play_pause.set(true);
+    board.tick();
+    board.play_pause.set(false);
+    board.ticks(1000);
+
+    let writes = &board.led.0;
+    assert_eq!(writes[0], Color::new(0, 0, 15));
+    assert_eq!(*writes.last().unwrap(), Color::new(0, 0, 0));
+    assert!(writes.windows(2).all(|pair| pair[1].b <= pair[0].b));
+    // The LED is left alone once it has faded out
+    assert_eq!(writes.len(), 20 * 256 / 10);
+}
+
+#[test]
+fn led_pulse_keeps_the_slot_colors_hue() {
+    let mut board = Board::new();
+    board.send(Message::SetLedBrightness(255));
+    board.send(Message::SetSlotColor {
+        mode: 0,
+        slot: 2,
+        color: Color::new(240, 120, 0),
+    });
+
+    board.play_pause.set(true);
+    board.tick();
+    board.play_pause.set(false);
+    board.ticks(1000);
+
+    let writes = &board.led.0;
+    assert_eq!(writes[0], Color::new(239, 119, 0));
+    // Red stays twice green, give or take rounding, all the way down
+    assert!(writes
+        .iter()
+        .all(|color| color.r / 2 == color.g && color.b == 0));
+}
+
+#[test]
+fn settings_survive_save_and_load() {
+    let mut board = Board::new();
+    board.send(Message::SetMode(1));
+    assert_eq!(board.send(Message::SaveConfig), ResponseCode::Ok);
+    board.send(Message::SetMode(0));
+    assert_eq!(board.send(Message::LoadConfig), ResponseCode::Ok);
+    assert_eq!(board.control_state.get_mode_index(), 1);
+
+    assert_eq!(board.send(Message::FactoryReset), ResponseCode::Ok);
+    assert_eq!(board.control_state.get_mode_index(), 0);
+    assert_eq!(board.send(Message::LoadConfig), ResponseCode::NotFound);
+}
@@ -1,8 +1,12 @@
 MEMORY
 {
   /* NOTE K = KiBi = 1024 bytes */
-  FLASH : ORIGIN = 0x08000000, LENGTH = 31K
-  DATA : ORIGIN = 0x080070c00, LENGTH = 1K
+  /* The STM32F042K6 has 32K of flash in 1K pages. The last two pages
+     hold saved settings, so an interrupted erase of one still leaves a
+     good copy in the other, and the program gets the 30K before them. */
+  FLASH : ORIGIN = 0x08000000, LENGTH = 30K
+  /* Saved settings, two pages for wear leveling. See src/flash.rs */
+  DATA : ORIGIN = 0x08007800, LENGTH = 2K
   RAM : ORIGIN = 0x20000000, LENGTH = 4K
 }
 
@@ -0,0 +1,43 @@
+use core::cell::Cell;
+
+use cortex_m::interrupt::{free as disable_interrupts, Mutex};
+use cortex_m::peripheral::{syst::SystClkSource, SYST};
+use cortex_m_rt::exception;
+
+use crate::hal::rcc::Rcc;
+
+static MILLIS: Mutex<Cell<u32>> = Mutex::new(Cell::new(0));
+
+/// A millisecond clock, counted by the SysTick interrupt.
+pub struct Clock {
+    _syst: SYST,
+}
+
+impl Clock {
+    pub fn start(mut syst: SYST, rcc: &Rcc) -> Clock {
+        syst.set_clock_source(SystClkSource::Core);
+        syst.set_reload(rcc.clocks.hclk().0 / 1_000 - 1);
+        syst.clear_current();
+        syst.enable_counter();
+        syst.enable_interrupt();
+        Clock { _syst: syst }
+    }
+
+    /// Milliseconds since the clock started. Wraps after about 49 days.
+    pub fn now(&self) -> u32 {
+        disable_interrupts(|cs| MILLIS.borrow(cs).get())
+    }
+
+    pub fn delay_ms(&self, ms: u32) {
+        let start = self.now();
+        while self.now().wrapping_sub(start) < ms {}
+    }
+}
+
+#[exception]
+fn SysTick() {
+    disable_interrupts(|cs| {
+        let millis = MILLIS.borrow(cs);
+        millis.set(millis.get().wrapping_add(1));
+    });
+}
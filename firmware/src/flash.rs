@@ -0,0 +1,103 @@
+use core::ptr;
+
+use micropad_config::{Flash, FlashError};
+
+use crate::hal::pac::FLASH;
+
+/// Start of the pages set aside for settings, the DATA region in memory.x.
+const CONFIG_START: usize = 0x0800_7800;
+const PAGE_SIZE: usize = 1024;
+const PAGE_COUNT: usize = 2;
+
+const KEY1: u32 = 0x4567_0123;
+const KEY2: u32 = 0xCDEF_89AB;
+
+/// The settings pages of the STM32F042's internal flash. The controller is
+/// only unlocked for the duration of each program or erase.
+pub struct ConfigFlash {
+    flash: FLASH,
+}
+
+impl ConfigFlash {
+    pub fn new(flash: FLASH) -> Self {
+        Self { flash }
+    }
+
+    fn unlock(&mut self) {
+        if self.flash.cr.read().lock().bit_is_set() {
+            self.flash.keyr.write(|w| w.fkeyr().bits(KEY1));
+            self.flash.keyr.write(|w| w.fkeyr().bits(KEY2));
+        }
+    }
+
+    fn lock(&mut self) {
+        self.flash.cr.modify(|_, w| w.lock().set_bit());
+    }
+
+    /// Wait for the current operation, then check and clear its status.
+    fn finish(&mut self) -> Result<(), FlashError> {
+        while self.flash.sr.read().bsy().bit_is_set() {}
+        let sr = self.flash.sr.read();
+        let failed = sr.pgerr().bit_is_set() || sr.wrprt().bit_is_set();
+        self.flash
+            .sr
+            .write(|w| w.eop().set_bit().pgerr().set_bit().wrprt().set_bit());
+        if failed {
+            Err(FlashError::Hardware)
+        } else {
+            Ok(())
+        }
+    }
+
+    fn program(&mut self, offset: usize, data: &[u8]) -> Result<(), FlashError> {
+        self.flash.cr.modify(|_, w| w.pg().set_bit());
+        for (i, half_word) in data.chunks(2).enumerate() {
+            let address = (CONFIG_START + offset + i * 2) as *mut u16;
+            unsafe {
+                ptr::write_volatile(address, u16::from_le_bytes([half_word[0], half_word[1]]))
+            };
+            self.finish()?;
+        }
+        Ok(())
+    }
+
+    fn erase(&mut self, page: usize) -> Result<(), FlashError> {
+        let address = (CONFIG_START + page * PAGE_SIZE) as u32;
+        self.flash.cr.modify(|_, w| w.per().set_bit());
+        self.flash.ar.write(|w| w.far().bits(address));
+        self.flash.cr.modify(|_, w| w.strt().set_bit());
+        self.finish()
+    }
+}
+
+impl Flash for ConfigFlash {
+    fn page_size(&self) -> usize {
+        PAGE_SIZE
+    }
+
+    fn page_count(&self) -> usize {
+        PAGE_COUNT
+    }
+
+    fn read(&self, offset: usize, buf: &mut [u8]) {
+        for (i, byte) in buf.iter_mut().enumerate() {
+            *byte = unsafe { ptr::read_volatile((CONFIG_START + offset + i) as *const u8) };
+        }
+    }
+
+    fn write(&mut self, offset: usize, data: &[u8]) -> Result<(), FlashError> {
+        self.unlock();
+        let result = self.program(offset, data);
+        self.flash.cr.modify(|_, w| w.pg().clear_bit());
+        self.lock();
+        result
+    }
+
+    fn erase_page(&mut self, page: usize) -> Result<(), FlashError> {
+        self.unlock();
+        let result = self.erase(page);
+        self.flash.cr.modify(|_, w| w.per().clear_bit());
+        self.lock();
+        result
+    }
+}
@@ -1,12 +1,19 @@
 use usb_device::class_prelude::*;
 use usb_device::Result;
 
+use micropad_core::hid::{MOUSE_REPORT_ID, SCROLL_RESOLUTION};
+use micropad_core::HidSink;
+
+/// The keyboard, consumer control and system control reports on one HID
+/// interface, and the mouse wheels on a second, so hosts treat them as a
+/// mouse.
 pub struct KeyboardHidClass<'a, B: UsbBus> {
     interface: InterfaceNumber,
     endpoint: EndpointIn<'a, B>,
-    reports: [HIDReport; 2],
-    buf: [u8; 5],
-    current_report: usize,
+    mouse_interface: InterfaceNumber,
+    mouse_endpoint: EndpointIn<'a, B>,
+    /// The mouse's feature report, as the host last set it.
+    resolution_multipliers: u8,
 }
 
 impl<B: UsbBus> KeyboardHidClass<'_, B> {
@@ -14,67 +21,53 @@ impl<B: UsbBus> KeyboardHidClass<'_, B> {
         KeyboardHidClass {
             interface: alloc.interface(),
             endpoint: alloc.interrupt(8, 10),
-            reports: [HIDReport::new(), HIDReport::new()],
-            buf: [0u8; 5],
-            current_report: 0,
-        }
-    }
-
-    pub fn add_key(&mut self, key: Key) {
-        self.reports[self.current_report].add_key(key);
-    }
-
-    pub fn reset_report(&mut self) {
-        self.reports[self.current_report].reset();
-    }
-
-    pub fn send_media_report(&mut self) {
-        self.reports[self.current_report].fill_media(&mut self.buf[0..2]);
-        let _ = self.endpoint.write(&self.buf[0..2]);
-        self.swap_reports();
-    }
-
-    pub fn send_key_report(&mut self) {
-        self.reports[self.current_report].fill_keys(&mut self.buf);
-        let _ = self.endpoint.write(&self.buf);
-        self.swap_reports();
-    }
-
-    pub fn send_media_report_if_changed(&mut self) {
-        if self.reports[self.current_report]
-            .media_changed(&self.reports[(self.current_report + 1) % 2])
-        {
-            self.send_media_report();
+            mouse_interface: alloc.interface(),
+            mouse_endpoint: alloc.interrupt(8, 10),
+            resolution_multipliers: 0,
         }
     }
+}
 
-    pub fn send_key_report_if_changed(&mut self) {
-        if self.reports[self.current_report]
-            .keys_changed(&self.reports[(self.current_report + 1) % 2])
-        {
-            self.send_key_report();
+impl<B: UsbBus> HidSink for KeyboardHidClass<'_, B> {
+    type Error = UsbError;
+
+    fn write_report(&mut self, report: &[u8]) -> nb::Result<(), UsbError> {
+        let endpoint = if report[0] == MOUSE_REPORT_ID {
+            &self.mouse_endpoint
+        } else {
+            &self.endpoint
+        };
+        match endpoint.write(report) {
+            Ok(_) => Ok(()),
+            Err(UsbError::WouldBlock) => Err(nb::Error::WouldBlock),
+            Err(err) => Err(nb::Error::Other(err)),
         }
     }
 
-    fn swap_reports(&mut self) {
-        let next_report = (self.current_report + 1) % 2;
-        self.reports[next_report] = self.reports[self.current_report];
-        self.current_report = (self.current_report + 1) % 2;
+    fn resolution_multipliers(&self) -> u8 {
+        self.resolution_multipliers
     }
 }
 
 const USB_CLASS_HID: u8 = 0x03;
+const IF_SUBCLASS_NONE: u8 = 0x00;
 const IF_SUBCLASS_BOOT: u8 = 0x01;
+const IF_PROTOCOL_NONE: u8 = 0x00;
 const IF_PROTOCOL_KEYBOARD: u8 = 0x01;
 
 const HID_DESCRIPTOR: u8 = 0x21;
 const HID_COUNTRY_CODE: u8 = 0x00;
 const HID_REPORT_DESCRIPTOR: u8 = 0x22;
 const HID_KEYBOARD_REPORT_DESC_SIZE: u8 = USB_HID_REPORT_DESCRIPTOR.len() as u8;
+const HID_MOUSE_REPORT_DESC_SIZE: u8 = USB_HID_MOUSE_REPORT_DESCRIPTOR.len() as u8;
 
 const USB_DESCRIPTOR_TYPE_HID: u8 = 0x21;
 const USB_DESCRIPTOR_TYPE_HIDREPORT: u8 = 0x22;
 
+const HID_REQUEST_GET_REPORT: u8 = 0x01;
+const HID_REQUEST_SET_REPORT: u8 = 0x09;
+const HID_REPORT_TYPE_FEATURE: u8 = 0x03;
+
 const USB_HID_DESCRIPTOR: &[u8] = &[
     0x09,
     HID_DESCRIPTOR,
@@ -87,6 +80,18 @@ const USB_HID_DESCRIPTOR: &[u8] = &[
     0x00,
 ];
 
+const USB_HID_MOUSE_DESCRIPTOR: &[u8] = &[
+    0x09,
+    HID_DESCRIPTOR,
+    0x11,
+    0x00, // HID class spec release number, bcdHID
+    HID_COUNTRY_CODE,
+    0x01, // bNumDescriptors
+    HID_REPORT_DESCRIPTOR,
+    HID_MOUSE_REPORT_DESC_SIZE,
+    0x00,
+];
+
 const USB_HID_REPORT_DESCRIPTOR: &[u8] = &[
     0x05, 0x01, // Usage Page (Generic Desktop Ctrls)
     0x09, 0x06, // Usage (Keyboard)
@@ -100,13 +105,13 @@ const USB_HID_REPORT_DESCRIPTOR: &[u8] = &[
     0x15, 0x00, //   Logical Minimum (0)
     0x25, 0x01, //   Logical Maximum (1)
     0x81, 0x02, //   Input (Data,Var,Abs,No Wrap,Linear,Preferred State,No Null Position)
-    0x95, 0x03, //   Report Count (3)
+    0x95, 0x06, //   Report Count (6)
     0x75, 0x08, //   Report Size (8)
     0x15, 0x00, //   Logical Minimum (0)
-    0x25, 0x64, //   Logical Maximum (100)
+    0x26, 0xDD, 0x00, //   Logical Maximum (221)
     0x05, 0x07, //   Usage Page (Kbrd/Keypad)
     0x19, 0x00, //   Usage Minimum (0x00)
-    0x29, 0x65, //   Usage Maximum (0x65)
+    0x29, 0xDD, //   Usage Maximum (0xDD)
     0x81, 0x00, //   Input (Data,Array,Abs,No Wrap,Linear,Preferred State,No Null Position)
     0xC0, // End Collection
     0x05, 0x0C, // Usage Page (Consumer)
@@ -114,19 +119,89 @@ const USB_HID_REPORT_DESCRIPTOR: &[u8] = &[
     0xA1, 0x01, // Collection (Application)
     0x85, 0x02, //   Report ID (2)
     0x05, 0x0C, //   Usage Page (Consumer)
+    0x75, 0x10, //   Report Size (16)
+    0x95, 0x03, //   Report Count (3)
+    0x15, 0x00, //   Logical Minimum (0)
+    0x26, 0xFF, 0x0F, //   Logical Maximum (4095)
+    0x19, 0x00, //   Usage Minimum (0x00)
+    0x2A, 0xFF, 0x0F, //   Usage Maximum (0x0FFF)
+    0x81, 0x00, //   Input (Data,Array,Abs,No Wrap,Linear,Preferred State,No Null Position)
+    0xC0, // End Collection
+    0x05, 0x01, // Usage Page (Generic Desktop Ctrls)
+    0x09, 0x80, // Usage (Sys Control)
+    0xA1, 0x01, // Collection (Application)
+    0x85, 0x03, //   Report ID (3)
     0x15, 0x00, //   Logical Minimum (0)
     0x25, 0x01, //   Logical Maximum (1)
     0x75, 0x01, //   Report Size (1)
-    0x95, 0x08, //   Report Count (8)
-    0x09, 0xB5, //   Usage (Scan Next Track)
-    0x09, 0xB6, //   Usage (Scan Previous Track)
-    0x09, 0xB7, //   Usage (Stop)
-    0x09, 0xB8, //   Usage (Eject)
-    0x09, 0xCD, //   Usage (Play/Pause)
-    0x09, 0xE2, //   Usage (Mute)
-    0x09, 0xE9, //   Usage (Volume Increment)
-    0x09, 0xEA, //   Usage (Volume Decrement)
+    0x95, 0x03, //   Report Count (3)
+    0x19, 0x81, //   Usage Minimum (Sys Power Down)
+    0x29, 0x83, //   Usage Maximum (Sys Wake Up)
     0x81, 0x02, //   Input (Data,Var,Abs,No Wrap,Linear,Preferred State,No Null Position)
+    0x95, 0x05, //   Report Count (5)
+    0x81, 0x03, //   Input (Const,Var,Abs,No Wrap,Linear,Preferred State,No Null Position)
+    0xC0, // End Collection
+];
+
+// The wheels each sit in a logical collection with a Resolution
+// Multiplier, which hosts that support high resolution scrolling set to 1
+// through the feature report. One notch is then SCROLL_RESOLUTION units.
+const _: () = assert!(MOUSE_REPORT_ID == 0x04 && SCROLL_RESOLUTION == 0x04);
+const USB_HID_MOUSE_REPORT_DESCRIPTOR: &[u8] = &[
+    0x05, 0x01, // Usage Page (Generic Desktop Ctrls)
+    0x09, 0x02, // Usage (Mouse)
+    0xA1, 0x01, // Collection (Application)
+    0x85, 0x04, //   Report ID (4)
+    0x09, 0x01, //   Usage (Pointer)
+    0xA1, 0x00, //   Collection (Physical)
+    0x09, 0x30, //     Usage (X)
+    0x09, 0x31, //     Usage (Y)
+    0x15, 0x81, //     Logical Minimum (-127)
+    0x25, 0x7F, //     Logical Maximum (127)
+    0x75, 0x08, //     Report Size (8)
+    0x95, 0x02, //     Report Count (2)
+    0x81, 0x06, //     Input (Data,Var,Rel,No Wrap,Linear,Preferred State,No Null Position)
+    0xA1, 0x02, //     Collection (Logical)
+    0x09, 0x48, //       Usage (Resolution Multiplier)
+    0x15, 0x00, //       Logical Minimum (0)
+    0x25, 0x01, //       Logical Maximum (1)
+    0x35, 0x01, //       Physical Minimum (1)
+    0x45, 0x04, //       Physical Maximum (4)
+    0x75, 0x02, //       Report Size (2)
+    0x95, 0x01, //       Report Count (1)
+    0xB1, 0x02, //       Feature (Data,Var,Abs,No Wrap,Linear,Preferred State)
+    0x35, 0x00, //       Physical Minimum (0)
+    0x45, 0x00, //       Physical Maximum (0)
+    0x09, 0x38, //       Usage (Wheel)
+    0x15, 0x81, //       Logical Minimum (-127)
+    0x25, 0x7F, //       Logical Maximum (127)
+    0x75, 0x08, //       Report Size (8)
+    0x95, 0x01, //       Report Count (1)
+    0x81, 0x06, //       Input (Data,Var,Rel,No Wrap,Linear,Preferred State,No Null Position)
+    0xC0, //     End Collection
+    0xA1, 0x02, //     Collection (Logical)
+    0x09, 0x48, //       Usage (Resolution Multiplier)
+    0x15, 0x00, //       Logical Minimum (0)
+    0x25, 0x01, //       Logical Maximum (1)
+    0x35, 0x01, //       Physical Minimum (1)
+    0x45, 0x04, //       Physical Maximum (4)
+    0x75, 0x02, //       Report Size (2)
+    0x95, 0x01, //       Report Count (1)
+    0xB1, 0x02, //       Feature (Data,Var,Abs,No Wrap,Linear,Preferred State)
+    0x35, 0x00, //       Physical Minimum (0)
+    0x45, 0x00, //       Physical Maximum (0)
+    0x05, 0x0C, //       Usage Page (Consumer)
+    0x0A, 0x38, 0x02, //       Usage (AC Pan)
+    0x15, 0x81, //       Logical Minimum (-127)
+    0x25, 0x7F, //       Logical Maximum (127)
+    0x75, 0x08, //       Report Size (8)
+    0x95, 0x01, //       Report Count (1)
+    0x81, 0x06, //       Input (Data,Var,Rel,No Wrap,Linear,Preferred State,No Null Position)
+    0xC0, //     End Collection
+    0x75, 0x04, //     Report Size (4)
+    0x95, 0x01, //     Report Count (1)
+    0xB1, 0x03, //     Feature (Const,Var,Abs,No Wrap,Linear,Preferred State)
+    0xC0, //   End Collection
     0xC0, // End Collection
 ];
 
@@ -157,148 +232,104 @@ impl<B: UsbBus> UsbClass<B> for KeyboardHidClass<'_, B> {
 
         writer.endpoint(&self.endpoint)?;
 
+        writer.interface(
+            self.mouse_interface,
+            USB_CLASS_HID,
+            IF_SUBCLASS_NONE,
+            IF_PROTOCOL_NONE,
+        )?;
+
+        writer.write(
+            HID_DESCRIPTOR,
+            &[
+                0x11,
+                0x00, // HID class spec release number, bcdHID
+                HID_COUNTRY_CODE,
+                0x01, // bNumDescriptors
+                HID_REPORT_DESCRIPTOR,
+                HID_MOUSE_REPORT_DESC_SIZE,
+                0x00,
+            ],
+        )?;
+
+        writer.endpoint(&self.mouse_endpoint)?;
+
         Ok(())
     }
 
+    fn reset(&mut self) {
+        // Hosts turn high resolution scrolling back on after enumerating
+        self.resolution_multipliers = 0;
+    }
+
     fn control_in(&mut self, xfer: ControlIn<B>) {
         let req = xfer.request();
 
-        if !(req.recipient == control::Recipient::Interface
-            && req.index == u8::from(self.interface) as u16)
-        {
+        if req.recipient != control::Recipient::Interface {
+            return;
+        }
+        let (hid_descriptor, report_descriptor) = if req.index == u8::from(self.interface) as u16 {
+            (USB_HID_DESCRIPTOR, USB_HID_REPORT_DESCRIPTOR)
+        } else if req.index == u8::from(self.mouse_interface) as u16 {
+            (USB_HID_MOUSE_DESCRIPTOR, USB_HID_MOUSE_REPORT_DESCRIPTOR)
+        } else {
+            return;
+        };
+
+        if req.request_type == control::RequestType::Class {
+            // The host reading back the mouse's resolution multipliers
+            if req.request == HID_REQUEST_GET_REPORT
+                && req.index == u8::from(self.mouse_interface) as u16
+                && req.value == feature_report_value(MOUSE_REPORT_ID)
+            {
+                xfer.accept_with(&[MOUSE_REPORT_ID, self.resolution_multipliers])
+                    .ok();
+            } else {
+                xfer.reject().ok();
+            }
             return;
         }
 
         match req.descriptor_type_index().0 {
             USB_DESCRIPTOR_TYPE_HID => {
-                xfer.accept_with_static(USB_HID_DESCRIPTOR).ok();
+                xfer.accept_with_static(hid_descriptor).ok();
             }
             USB_DESCRIPTOR_TYPE_HIDREPORT => {
-                xfer.accept_with_static(USB_HID_REPORT_DESCRIPTOR).ok();
+                xfer.accept_with_static(report_descriptor).ok();
             }
             _ => {
                 xfer.reject().ok();
             }
         }
     }
-}
-
-#[derive(Copy, Clone)]
-struct HIDReport {
-    current_key: usize,
-    keys: [u8; 3],
-    media_keys: u8,
-}
-
-// Scan codes taken from: https://www.usb.org/sites/default/files/documents/hut1_12v2.pdf
-#[derive(Copy, Clone, Eq, PartialEq, Debug)]
-#[allow(dead_code)]
-pub enum Key {
-    Normal(ScanCode),
-    Media(MediaCode),
-}
-
-#[repr(u8)]
-#[derive(Copy, Clone, Eq, PartialEq, Debug)]
-#[allow(dead_code)]
-pub enum ScanCode {
-    Return = 0x28,
-    RightArrow = 0x4F,
-    LeftArrow = 0x50,
-    DownArrow = 0x51,
-    UpArrow = 0x52,
-}
-
-// See documentation here: https://notes.iopush.net/custom-usb-hid-device-descriptor-media-keyboard/
-#[repr(u8)]
-#[derive(Copy, Clone, Eq, PartialEq, Debug)]
-#[allow(dead_code)]
-pub enum MediaCode {
-    ScanNext = 0x01,
-    ScanPrev = 0x02,
-    Stop = 0x04,
-    Eject = 0x08,
-    PlayPause = 0x10,
-    Mute = 0x20,
-    VolumeUp = 0x40,
-    VolumeDown = 0x80,
-}
-
-impl MediaCode {
-    pub fn raw(&self) -> u8 {
-        *self as u8
-    }
-}
 
-impl ScanCode {
-    pub fn raw(&self) -> u8 {
-        *self as u8
-    }
-}
+    fn control_out(&mut self, xfer: ControlOut<B>) {
+        let req = xfer.request();
 
-impl HIDReport {
-    pub fn new() -> Self {
-        Self {
-            current_key: 0,
-            keys: [0u8; 3],
-            media_keys: 0,
+        if !(req.request_type == control::RequestType::Class
+            && req.recipient == control::Recipient::Interface
+            && req.index == u8::from(self.mouse_interface) as u16)
+        {
+            return;
         }
-    }
 
-    fn add_key(&mut self, key: Key) {
-        match key {
-            Key::Normal(scan_code) => {
-                if self.current_key == 2 {
-                    return;
-                }
-                self.keys[self.current_key] = scan_code.raw();
-                self.current_key = self.current_key + 1;
+        // The host turning high resolution scrolling on or off
+        match xfer.data() {
+            [MOUSE_REPORT_ID, multipliers]
+                if req.request == HID_REQUEST_SET_REPORT
+                    && req.value == feature_report_value(MOUSE_REPORT_ID) =>
+            {
+                self.resolution_multipliers = *multipliers;
+                xfer.accept().ok();
             }
-            Key::Media(media_code) => {
-                self.media_keys |= media_code.raw();
+            _ => {
+                xfer.reject().ok();
             }
         }
     }
-
-    fn reset(&mut self) {
-        self.current_key = 0;
-        self.media_keys = 0;
-        for i in &mut self.keys {
-            *i = 0
-        }
-    }
-
-    fn fill_media(&self, buf: &mut [u8]) {
-        buf[0] = 2; // Report id
-        buf[1] = self.media_keys;
-    }
-
-    fn fill_keys(&self, buf: &mut [u8]) {
-        buf[0] = 1; // Report id
-        buf[1] = 0; // Omit modifiers for now
-        buf[2] = self.keys[0];
-        buf[3] = self.keys[1];
-        buf[4] = self.keys[2];
-    }
-
-    fn media_changed(&self, other: &HIDReport) -> bool {
-        self.media_keys != other.media_keys
-    }
-
-    fn keys_changed(&self, other: &HIDReport) -> bool {
-        self.keys[0] != other.keys[0]
-            || self.keys[1] != other.keys[1]
-            || self.keys[2] != other.keys[2]
-    }
 }
 
-impl PartialEq for HIDReport {
-    fn eq(&self, other: &Self) -> bool {
-        self.keys[0] == other.keys[0]
-            && self.keys[1] == other.keys[1]
-            && self.keys[2] == other.keys[2]
-            && self.media_keys == other.media_keys
-    }
+/// The wValue of a GET_REPORT or SET_REPORT request for a feature report.
+fn feature_report_value(report_id: u8) -> u16 {
+    (HID_REPORT_TYPE_FEATURE as u16) << 8 | report_id as u16
 }
-
-impl Eq for HIDReport {}
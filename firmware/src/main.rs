@@ -1,13 +1,19 @@
 #![no_main]
 #![no_std]
 
-pub mod encoder;
+pub mod clock;
+pub mod flash;
 pub mod hid;
 
 use apa102_spi::{Apa102, PixelOrder};
-use embedded_hal::serial::{Read, Write};
-use encoder::RotaryEncoder;
-use micropad_protocol::{Message, MessageFrame, ResponseCode, ResponsePayload};
+use clock::Clock;
+use flash::ConfigFlash;
+use micropad_config::Eeprom;
+use micropad_core::{
+    handle_message, load_config, ControlState, Inbox, Inputs, Keypad, LedSink, Outbox,
+    RotaryEncoder,
+};
+use micropad_protocol::{Color, EventSet};
 use smart_leds::{gamma, SmartLedsWrite};
 use smart_leds_trait::RGB8;
 
@@ -16,7 +22,6 @@ use panic_halt as _;
 use stm32f0xx_hal as hal;
 
 use hal::{
-    delay::Delay,
     gpio::{
         gpioa::{PA0, PA1, PA10, PA2, PA3, PA5, PA6, PA7, PA8, PA9},
         Alternate, Floating, Input, Output, PullDown, PullUp, PushPull, AF0,
@@ -36,11 +41,7 @@ use core::{cell::RefCell, ops::DerefMut};
 use cortex_m::{interrupt::free as disable_interrupts, interrupt::Mutex, peripheral::NVIC};
 use cortex_m_rt::entry;
 
-use crate::hid::{Key, KeyboardHidClass, MediaCode, ScanCode};
-
-const MAJOR_VERSION: u8 = 0;
-const MINOR_VERSION: u8 = 1;
-const PATCH_VERSION: u8 = 0;
+use crate::hid::KeyboardHidClass;
 
 static mut USB_BUS_ALLOC: Option<UsbBusAllocator<UsbBus<hal::usb::Peripheral>>> = None;
 static USB_DEV: Mutex<RefCell<Option<UsbDevice<UsbBus<hal::usb::Peripheral>>>>> =
@@ -49,122 +50,48 @@ static USB_KEYBOARD: Mutex<RefCell<Option<KeyboardHidClass<UsbBus<hal::usb::Peri
     Mutex::new(RefCell::new(None));
 static USB_SERIAL: Mutex<RefCell<Option<SerialPort<UsbBus<hal::usb::Peripheral>>>>> =
     Mutex::new(RefCell::new(None));
-
-type Mode = [Key; 5];
-
-static MUSIC_MODE: Mode = [
-    Key::Media(MediaCode::VolumeUp),
-    Key::Media(MediaCode::VolumeDown),
-    Key::Media(MediaCode::PlayPause),
-    Key::Media(MediaCode::ScanNext),
-    Key::Media(MediaCode::ScanPrev),
-];
-
-static NAV_MODE: Mode = [
-    Key::Normal(ScanCode::DownArrow),
-    Key::Normal(ScanCode::UpArrow),
-    Key::Normal(ScanCode::Return),
-    Key::Normal(ScanCode::RightArrow),
-    Key::Normal(ScanCode::LeftArrow),
-];
-
-static MODES: [&'static Mode; 2] = [&MUSIC_MODE, &NAV_MODE];
-
-static CONTROL_STATE: Mutex<RefCell<ControlState>> = Mutex::new(RefCell::new(ControlState {
-    led_brightness: 127,
-    mode_index: 0,
-}));
+static USB_INBOX: Mutex<RefCell<Inbox>> = Mutex::new(RefCell::new(Inbox::new()));
+static USB_OUTBOX: Mutex<RefCell<Outbox>> = Mutex::new(RefCell::new(Outbox::new()));
+
+static CONFIG_STORE: Mutex<RefCell<Option<Eeprom<ConfigFlash>>>> = Mutex::new(RefCell::new(None));
+
+static CONTROL_STATE: Mutex<RefCell<ControlState>> = Mutex::new(RefCell::new(ControlState::new()));
+
+type ButtonsAndEncoder = Inputs<
+    PA0<Input<PullDown>>,
+    PA2<Input<PullDown>>,
+    PA1<Input<PullDown>>,
+    PA3<Input<PullUp>>,
+    PA8<Input<Floating>>,
+    PA9<Input<Floating>>,
+>;
+
+type LedSpi = spi::Spi<
+    hal::stm32::SPI1,
+    PA5<Alternate<AF0>>,
+    PA6<Alternate<AF0>>,
+    PA7<Alternate<AF0>>,
+    spi::EightBit,
+>;
 
 struct Devices {
     ok_led: PA10<Output<PushPull>>,
-    delay: Delay,
-    play_pause: PA0<Input<PullDown>>,
-    next: PA2<Input<PullDown>>,
-    prev: PA1<Input<PullDown>>,
-    enc_btn: PA3<Input<PullUp>>,
-    apa102: Apa102<
-        spi::Spi<
-            hal::stm32::SPI1,
-            PA5<Alternate<AF0>>,
-            PA6<Alternate<AF0>>,
-            PA7<Alternate<AF0>>,
-            spi::EightBit,
-        >,
-    >,
-    encoder: RotaryEncoder<PA8<Input<Floating>>, PA9<Input<Floating>>>,
-}
-
-#[derive(Clone)]
-struct ControlState {
-    led_brightness: u8,
-    mode_index: u8,
-}
-
-impl ControlState {
-    fn set_led_brightness(&mut self, brightness: u8) {
-        self.led_brightness = brightness;
-    }
-
-    fn get_led_brightness(&self) -> u8 {
-        self.led_brightness
-    }
-
-    fn next_mode(&mut self) {
-        self.mode_index = (self.mode_index + 1) % MODES.len() as u8;
-    }
-
-    fn get_mode_index(&self) -> u8 {
-        self.mode_index
-    }
-
-    fn get_mode(&self) -> &'static Mode {
-        MODES[self.mode_index as usize]
-    }
-}
-
-struct LEDIndicatorState {
-    color: RGB8,
-    phase: u16,
+    clock: Clock,
+    inputs: ButtonsAndEncoder,
+    led: Led,
 }
 
-impl LEDIndicatorState {
-    fn new() -> Self {
-        Self {
-            color: RGB8 { r: 0, g: 0, b: 0 },
-            phase: 0,
-        }
-    }
-
-    fn pulse_color(&mut self, color: RGB8, brightness: u8) {
-        self.color = color;
-        self.phase = (brightness as u16) << 8;
-    }
+/// The APA102 on SPI1.
+struct Led(Apa102<LedSpi>);
 
-    fn write_if_blinking(
-        &mut self,
-        apa102: &mut Apa102<
-            spi::Spi<
-                hal::stm32::SPI1,
-                PA5<Alternate<AF0>>,
-                PA6<Alternate<AF0>>,
-                PA7<Alternate<AF0>>,
-                spi::EightBit,
-            >,
-        >,
-    ) {
-        if self.phase > 0 {
-            self.phase -= 10;
-            if self.color.r != 0 {
-                self.color.r = (self.phase >> 8) as u8;
-            };
-            if self.color.g != 0 {
-                self.color.g = (self.phase >> 8) as u8;
-            };
-            if self.color.b != 0 {
-                self.color.b = (self.phase >> 8) as u8;
-            };
-            apa102.write(gamma([self.color].iter().cloned())).unwrap();
-        }
+impl LedSink for Led {
+    fn write(&mut self, color: Color) {
+        let color = RGB8 {
+            r: color.r,
+            g: color.g,
+            b: color.b,
+        };
+        self.0.write(gamma([color].iter().cloned())).unwrap();
     }
 }
 
@@ -181,6 +108,13 @@ fn setup() -> Devices {
             .pclk(24.mhz())
             .freeze(&mut peripherals.FLASH);
 
+        // Start from the saved settings, if there are any
+        let config_store = Eeprom::new(ConfigFlash::new(peripherals.FLASH));
+        if let Ok(config) = load_config(&config_store) {
+            CONTROL_STATE.borrow(cs).borrow_mut().config = config;
+        }
+        *CONFIG_STORE.borrow(cs).borrow_mut() = Some(config_store);
+
         let gpioa = peripherals.GPIOA.split(&mut rcc);
         let (
             play_pause,
@@ -209,7 +143,7 @@ fn setup() -> Devices {
             gpioa.pa11,                        // USB dm
             gpioa.pa12,                        // USB dp
         );
-        let delay = Delay::new(core.SYST, &rcc);
+        let clock = Clock::start(core.SYST, &rcc);
         let spi = spi::Spi::spi1(
             peripherals.SPI1,
             (sck, miso, mosi),
@@ -221,7 +155,13 @@ fn setup() -> Devices {
             &mut rcc,
         );
         let apa102 = Apa102::new_with_options(spi, 4, true, PixelOrder::RBG);
-        let encoder = RotaryEncoder::new(enc_cw, enc_ccw);
+        let inputs = Inputs::new(
+            play_pause,
+            next,
+            prev,
+            enc_btn,
+            RotaryEncoder::new(enc_cw, enc_ccw),
+        );
         let usb = hal::usb::Peripheral {
             usb: peripherals.USB,
             pin_dm: usb_dm,
@@ -251,13 +191,9 @@ fn setup() -> Devices {
         ok_led.set_high().ok();
         Devices {
             ok_led,
-            delay,
-            play_pause,
-            next,
-            prev,
-            enc_btn,
-            apa102,
-            encoder,
+            clock,
+            inputs,
+            led: Led(apa102),
         }
     })
 }
@@ -268,146 +204,42 @@ fn main() -> ! {
 
     let led_color_reset: [RGB8; 1] = [RGB8 { r: 0, g: 0, b: 0 }];
     devices
-        .apa102
+        .led
+        .0
         .write(led_color_reset.iter().cloned())
         .unwrap();
 
-    let mut led_indicator = LEDIndicatorState::new();
-    let mut current_encoder_count = 0;
-    let mut key: Option<Key> = None;
+    let mut keypad = Keypad::new();
 
     loop {
-        let control_state = disable_interrupts(|cs| CONTROL_STATE.borrow(cs).borrow().clone());
-        let current_mode = control_state.get_mode();
-
-        // Sample encoder
-        let encoder_sample: i32 = devices.encoder.read_count();
-        let encoder_diff: i32 = encoder_sample - current_encoder_count;
-        current_encoder_count = encoder_sample;
-
-        // Encoder
-        if encoder_diff > 0 {
-            led_indicator.pulse_color(
-                RGB8 {
-                    r: 0,
-                    b: 255,
-                    g: 255,
-                },
-                control_state.get_led_brightness(),
-            );
-            key = Some(current_mode[0]);
-        } else if encoder_diff < 0 {
-            led_indicator.pulse_color(
-                RGB8 {
-                    r: 255,
-                    b: 255,
-                    g: 0,
-                },
-                control_state.get_led_brightness(),
-            );
-            key = Some(current_mode[1]);
-        }
-        // Buttons
-        else if devices.play_pause.is_high().unwrap() {
-            led_indicator.pulse_color(
-                RGB8 { r: 0, g: 0, b: 255 },
-                control_state.get_led_brightness(),
-            );
-            key = Some(current_mode[2]);
-        } else if devices.next.is_high().unwrap() {
-            led_indicator.pulse_color(
-                RGB8 { r: 0, g: 255, b: 0 },
-                control_state.get_led_brightness(),
-            );
-            key = Some(current_mode[3]);
-        } else if devices.prev.is_high().unwrap() {
-            led_indicator.pulse_color(
-                RGB8 { r: 255, g: 0, b: 0 },
-                control_state.get_led_brightness(),
-            );
-            key = Some(current_mode[4]);
-        } else if devices.enc_btn.is_low().unwrap() {
-            led_indicator.pulse_color(
-                RGB8 {
-                    r: 255,
-                    g: 255,
-                    b: 0,
-                },
-                control_state.get_led_brightness(),
-            );
-            key = None; // Switching modes, don't send a HID report.
-            disable_interrupts(|cs| {
-                CONTROL_STATE.borrow(cs).borrow_mut().next_mode();
-            });
-        } else {
-            // Encoder diff is zero, and no buttons currently pressed. Reset report.
-            key = None;
-        }
-
-        disable_interrupts(|cs| {
-            if let &mut Some(ref mut keyboard) = USB_KEYBOARD.borrow(cs).borrow_mut().deref_mut() {
-                match key {
-                    Some(k) => keyboard.add_key(k),
-                    None => keyboard.reset_report(),
-                };
+        let debounce_ms =
+            disable_interrupts(|cs| CONTROL_STATE.borrow(cs).borrow().get_debounce_time());
+        let scan = devices.inputs.scan(devices.clock.now(), debounce_ms);
+
+        let key_pressed = disable_interrupts(|cs| {
+            let mut control_state = CONTROL_STATE.borrow(cs).borrow_mut();
+            let mut outbox = USB_OUTBOX.borrow(cs).borrow_mut();
+            let key_pressed = match USB_KEYBOARD.borrow(cs).borrow_mut().deref_mut() {
+                Some(keyboard) => keypad.update(&scan, &mut control_state, &mut outbox, keyboard),
+                None => false,
+            };
 
-                keyboard.send_media_report_if_changed();
-                keyboard.send_key_report_if_changed();
+            // Send any events the scan queued, if the host is subscribed
+            if let &mut Some(ref mut serial) = USB_SERIAL.borrow(cs).borrow_mut().deref_mut() {
+                let _ = outbox.flush(serial);
             }
+            key_pressed
         });
 
-        led_indicator.write_if_blinking(&mut devices.apa102);
+        keypad.write_led(&mut devices.led);
 
         // Make sure we delay outside of our 'disable_interrupts' block
-        if key.is_some() {
-            devices.delay.delay_ms(10u32);
+        if key_pressed {
+            devices.clock.delay_ms(10);
         }
     }
 }
 
-fn read_into_frame<R>(frame: &mut MessageFrame, reader: &mut R) -> nb::Result<(), R::Error>
-where
-    R: Read<u8>,
-{
-    for i in 0..frame.frame_size() {
-        match reader.read() {
-            Ok(b) => frame.buf[i] = b,
-            Err(err) => return Err(err),
-        }
-    }
-
-    Ok(())
-}
-
-fn write_response<W>(
-    frame: &mut MessageFrame,
-    writer: &mut W,
-    code: ResponseCode,
-) -> nb::Result<(), W::Error>
-where
-    W: Write<u8>,
-{
-    write_response_payload(frame, writer, code, &ResponsePayload::None)
-}
-
-fn write_response_payload<W>(
-    frame: &mut MessageFrame,
-    writer: &mut W,
-    code: ResponseCode,
-    payload: &ResponsePayload,
-) -> nb::Result<(), W::Error>
-where
-    W: Write<u8>,
-{
-    frame.buf[0] = code.raw();
-    payload.fill(frame);
-
-    for i in 0..frame.frame_size() {
-        writer.write(frame.buf[i])?;
-    }
-    Ok(())
-}
-
 fn poll_usb() {
     disable_interrupts(|cs| {
         if let (&mut Some(ref mut device), &mut Some(ref mut keyboard), &mut Some(ref mut serial)) = (
@@ -416,64 +248,19 @@ fn poll_usb() {
             USB_SERIAL.borrow(cs).borrow_mut().deref_mut(),
         ) {
             device.poll(&mut [keyboard, serial]);
-            let mut message_frame = MessageFrame::new();
-
-            if let Ok(_) = read_into_frame(&mut message_frame, serial) {
-                let message = Message::from(&message_frame);
-                match message {
-                    Message::Ping => {
-                        let _ = write_response(&mut message_frame, serial, ResponseCode::Ok);
-                    }
-                    Message::SetLedBrightness(brightness) => {
-                        CONTROL_STATE
-                            .borrow(cs)
-                            .borrow_mut()
-                            .set_led_brightness(brightness);
-                        let _ = write_response(&mut message_frame, serial, ResponseCode::Ok);
-                    }
-                    Message::GetLedBrightness => {
-                        let brightness = CONTROL_STATE.borrow(cs).borrow().get_led_brightness();
-                        let _ = write_response_payload(
-                            &mut message_frame,
-                            serial,
-                            ResponseCode::Ok,
-                            &ResponsePayload::LedBrightness(brightness),
-                        );
-                    }
-                    Message::GetModeInfo => {
-                        let current_mode = CONTROL_STATE.borrow(cs).borrow().get_mode_index();
-                        let _ = write_response_payload(
-                            &mut message_frame,
-                            serial,
-                            ResponseCode::Ok,
-                            &ResponsePayload::ModeInfo {
-                                built_in_mode_count: MODES.len() as u8,
-                                user_mode_count: 0,
-                                current_mode_index: current_mode,
-                            },
-                        );
-                    }
-                    Message::GetVersion => {
-                        let _ = write_response_payload(
-                            &mut message_frame,
-                            serial,
-                            ResponseCode::Ok,
-                            &ResponsePayload::Version {
-                                major: MAJOR_VERSION,
-                                minor: MINOR_VERSION,
-                                patch: PATCH_VERSION,
-                            },
-                        );
-                    }
-                    _ => {
-                        let _ = write_response(
-                            &mut message_frame,
-                            serial,
-                            ResponseCode::UnknownMessage,
-                        );
-                    }
-                };
-            };
+            let mut outbox = USB_OUTBOX.borrow(cs).borrow_mut();
+
+            // The host closed the port, so nobody is listening for events
+            if !serial.dtr() {
+                outbox.unsubscribe(EventSet::ALL);
+            }
+
+            let mut inbox = USB_INBOX.borrow(cs).borrow_mut();
+            let mut control_state = CONTROL_STATE.borrow(cs).borrow_mut();
+            let mut config_store = CONFIG_STORE.borrow(cs).borrow_mut();
+            micropad_core::poll(serial, &mut inbox, &mut outbox, |outbox, message| {
+                handle_message(&mut control_state, config_store.as_mut(), outbox, message)
+            });
         }
     });
 }
@@ -0,0 +1,50 @@
+//! Chords, keys sent by holding several buttons together, and their
+//! on-wire encoding.
+//!
+//! A chord is sent as 4 bytes: a button mask, one bit per button number,
+//! followed by its key. A mask of 0 marks an unused chord.
+
+use crate::keys::{Key, ENCODED_KEY_SIZE};
+use crate::ProtocolError;
+
+/// Size of an encoded chord, in bytes.
+pub const ENCODED_CHORD_SIZE: usize = 1 + ENCODED_KEY_SIZE;
+
+/// A key sent instead of the buttons' own keys while all of `buttons` are
+/// held.
+#[derive(Copy, Clone, Eq, PartialEq, Debug)]
+pub struct Chord {
+    pub buttons: u8,
+    pub key: Key,
+}
+
+impl Chord {
+    /// Fails with `InvalidArgument` unless at least two buttons are given.
+    pub fn new(buttons: u8, key: Key) -> Result<Chord, ProtocolError> {
+        if buttons.count_ones() < 2 {
+            return Err(ProtocolError::InvalidArgument);
+        }
+        Ok(Chord { buttons, key })
+    }
+
+    pub fn contains(&self, button: u8) -> bool {
+        self.buttons & (1 << button) != 0
+    }
+}
+
+pub fn encode(chord: &Option<Chord>) -> [u8; ENCODED_CHORD_SIZE] {
+    let mut bytes = [0u8; ENCODED_CHORD_SIZE];
+    if let Some(chord) = chord {
+        bytes[0] = chord.buttons;
+        bytes[1..].copy_from_slice(&chord.key.encode());
+    }
+    bytes
+}
+
+pub fn decode(bytes: &[u8]) -> Result<Option<Chord>, ProtocolError> {
+    match bytes.first() {
+        None => Err(ProtocolError::Truncated),
+        Some(0) => Ok(None),
+        Some(buttons) => Ok(Some(Chord::new(*buttons, Key::decode(&bytes[1..])?)?)),
+    }
+}
@@ -0,0 +1,33 @@
+//! LED colors, and their on-wire encoding.
+//!
+//! A color is sent as 3 bytes: red, green, then blue.
+
+use crate::ProtocolError;
+
+/// Size of an encoded color, in bytes.
+pub const ENCODED_COLOR_SIZE: usize = 3;
+
+/// The color the LED pulses when a slot is used.
+#[derive(Copy, Clone, Eq, PartialEq, Debug)]
+pub struct Color {
+    pub r: u8,
+    pub g: u8,
+    pub b: u8,
+}
+
+impl Color {
+    pub const fn new(r: u8, g: u8, b: u8) -> Color {
+        Color { r, g, b }
+    }
+
+    pub fn encode(&self) -> [u8; ENCODED_COLOR_SIZE] {
+        [self.r, self.g, self.b]
+    }
+
+    pub fn decode(bytes: &[u8]) -> Result<Color, ProtocolError> {
+        match bytes {
+            [r, g, b, ..] => Ok(Color::new(*r, *g, *b)),
+            _ => Err(ProtocolError::Truncated),
+        }
+    }
+}
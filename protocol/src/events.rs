@@ -0,0 +1,132 @@
+//! Events the micropad pushes to subscribed hosts.
+//!
+//! Event frames are sent with `UNSOLICITED_REQUEST_ID`, and carry an event
+//! type in the header byte where responses carry their response code. Event
+//! types start at 0x40 so they can never be mistaken for a response code.
+
+use core::convert::TryFrom;
+
+use crate::{MessageFrame, ProtocolError, UNSOLICITED_REQUEST_ID};
+
+/// A set of event categories a host can subscribe to.
+#[derive(Copy, Clone, Eq, PartialEq, Debug)]
+pub struct EventSet(pub u8);
+
+impl EventSet {
+    pub const BUTTONS: EventSet = EventSet(0x01);
+    pub const ENCODERS: EventSet = EventSet(0x02);
+    pub const MODES: EventSet = EventSet(0x04);
+    pub const ALL: EventSet = EventSet(0x07);
+
+    pub const fn empty() -> EventSet {
+        EventSet(0)
+    }
+
+    pub fn union(&self, other: EventSet) -> EventSet {
+        EventSet(self.0 | other.0)
+    }
+
+    pub fn difference(&self, other: EventSet) -> EventSet {
+        EventSet(self.0 & !other.0)
+    }
+
+    pub fn contains(&self, other: EventSet) -> bool {
+        self.0 & other.0 == other.0
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.0 == 0
+    }
+}
+
+#[repr(u8)]
+#[derive(Copy, Clone, Eq, PartialEq, Debug)]
+pub enum Direction {
+    Clockwise = 0x01,
+    CounterClockwise = 0x02,
+}
+
+impl TryFrom<u8> for Direction {
+    type Error = ProtocolError;
+
+    fn try_from(direction: u8) -> Result<Direction, ProtocolError> {
+        match direction {
+            0x01 => Ok(Direction::Clockwise),
+            0x02 => Ok(Direction::CounterClockwise),
+            _ => Err(ProtocolError::InvalidArgument),
+        }
+    }
+}
+
+/// Something that happened on the micropad. Buttons and encoders are
+/// numbered from 0, in the order reported by the capabilities.
+#[derive(Copy, Clone, Eq, PartialEq, Debug)]
+pub enum Event {
+    ButtonDown(u8),
+    ButtonUp(u8),
+    EncoderStep { encoder: u8, direction: Direction },
+    ModeChanged(u8),
+}
+
+impl Event {
+    fn code(&self) -> u8 {
+        match self {
+            Event::ButtonDown(_) => 0x41,
+            Event::ButtonUp(_) => 0x42,
+            Event::EncoderStep { .. } => 0x43,
+            Event::ModeChanged(_) => 0x44,
+        }
+    }
+
+    /// The subscription category the event belongs to.
+    pub fn category(&self) -> EventSet {
+        match self {
+            Event::ButtonDown(_) | Event::ButtonUp(_) => EventSet::BUTTONS,
+            Event::EncoderStep { .. } => EventSet::ENCODERS,
+            Event::ModeChanged(_) => EventSet::MODES,
+        }
+    }
+
+    /// True for frames that carry an event rather than a response.
+    pub fn is_event_frame(frame: &MessageFrame) -> bool {
+        frame.request_id == UNSOLICITED_REQUEST_ID && frame.header() >= 0x40
+    }
+}
+
+impl From<&Event> for MessageFrame {
+    fn from(event: &Event) -> Self {
+        let mut frame = MessageFrame::new();
+        frame.request_id = UNSOLICITED_REQUEST_ID;
+        frame.buf[0] = event.code();
+        match event {
+            Event::ButtonDown(button) | Event::ButtonUp(button) => {
+                frame.buf[1] = *button;
+            }
+            Event::EncoderStep { encoder, direction } => {
+                frame.buf[1] = *encoder;
+                frame.buf[2] = *direction as u8;
+            }
+            Event::ModeChanged(mode_index) => {
+                frame.buf[1] = *mode_index;
+            }
+        }
+        frame
+    }
+}
+
+impl TryFrom<&MessageFrame> for Event {
+    type Error = ProtocolError;
+
+    fn try_from(frame: &MessageFrame) -> Result<Event, ProtocolError> {
+        match frame.header() {
+            0x41 => Ok(Event::ButtonDown(frame.buf[1])),
+            0x42 => Ok(Event::ButtonUp(frame.buf[1])),
+            0x43 => Ok(Event::EncoderStep {
+                encoder: frame.buf[1],
+                direction: Direction::try_from(frame.buf[2])?,
+            }),
+            0x44 => Ok(Event::ModeChanged(frame.buf[1])),
+            code => Err(ProtocolError::UnknownMessage(code)),
+        }
+    }
+}
@@ -0,0 +1,346 @@
+//! Wire framing for message frames.
+//!
+//! Each `MessageFrame` is sent as a packet: the request ID and frame bytes
+//! followed by a CRC-8 of those bytes, COBS encoded so the packet contains
+//! no zero bytes, and terminated by a single 0x00 delimiter. A receiver that loses or gains
+//! a byte discards at most one packet, and is back in sync at the next
+//! delimiter.
+
+use crate::{MessageFrame, ProtocolError, FRAME_SIZE, UNSOLICITED_REQUEST_ID};
+
+/// Marks the end of every packet on the wire.
+pub const PACKET_DELIMITER: u8 = 0x00;
+
+/// Request ID, frame bytes and the trailing checksum byte, before COBS
+/// encoding.
+const PACKET_DATA_SIZE: usize = FRAME_SIZE + 2;
+
+/// Offset of the checksum byte within the decoded packet.
+const CHECKSUM_OFFSET: usize = PACKET_DATA_SIZE - 1;
+
+/// Largest encoded packet, including the COBS overhead byte and delimiter.
+pub const MAX_PACKET_SIZE: usize = PACKET_DATA_SIZE + 2;
+
+/// CRC-8 with polynomial 0x07 and a zero initial value (CRC-8/SMBUS).
+pub fn crc8(bytes: &[u8]) -> u8 {
+    let mut crc = 0u8;
+    for byte in bytes {
+        crc ^= *byte;
+        for _ in 0..8 {
+            crc = if crc & 0x80 != 0 {
+                (crc << 1) ^ 0x07
+            } else {
+                crc << 1
+            };
+        }
+    }
+    crc
+}
+
+/// An encoded packet, ready to be written to the wire.
+#[derive(Clone)]
+pub struct Packet {
+    buf: [u8; MAX_PACKET_SIZE],
+    len: usize,
+}
+
+impl Packet {
+    pub fn encode(frame: &MessageFrame) -> Packet {
+        let mut data = [0u8; PACKET_DATA_SIZE];
+        data[0] = frame.request_id;
+        data[1..CHECKSUM_OFFSET].copy_from_slice(&frame.buf);
+        data[CHECKSUM_OFFSET] = crc8(&data[..CHECKSUM_OFFSET]);
+
+        let mut packet = Packet {
+            buf: [0u8; MAX_PACKET_SIZE],
+            len: 0,
+        };
+        packet.len = cobs_encode(&data, &mut packet.buf);
+        packet.buf[packet.len] = PACKET_DELIMITER;
+        packet.len += 1;
+        packet
+    }
+
+    /// The encoded bytes, including the trailing delimiter.
+    pub fn as_bytes(&self) -> &[u8] {
+        &self.buf[..self.len]
+    }
+}
+
+/// Reassembles packets from a byte stream, one byte at a time.
+pub struct PacketDecoder {
+    buf: [u8; MAX_PACKET_SIZE],
+    len: usize,
+    overflowed: bool,
+    last_request_id: u8,
+}
+
+impl PacketDecoder {
+    pub const fn new() -> PacketDecoder {
+        PacketDecoder {
+            buf: [0u8; MAX_PACKET_SIZE],
+            len: 0,
+            overflowed: false,
+            last_request_id: UNSOLICITED_REQUEST_ID,
+        }
+    }
+
+    /// The request ID of the most recently completed packet. For packets
+    /// that failed their checksum this is the ID byte as received, and for
+    /// packets too malformed to find the ID it is `UNSOLICITED_REQUEST_ID`.
+    pub fn last_request_id(&self) -> u8 {
+        self.last_request_id
+    }
+
+    /// Feeds the next byte from the wire. Returns `None` until a delimiter
+    /// ends a packet, then the decoded frame or the reason the packet was
+    /// rejected. Empty packets, such as repeated delimiters, are skipped.
+    pub fn push(&mut self, byte: u8) -> Option<Result<MessageFrame, ProtocolError>> {
+        if byte != PACKET_DELIMITER {
+            if self.len < self.buf.len() {
+                self.buf[self.len] = byte;
+                self.len += 1;
+            } else {
+                self.overflowed = true;
+            }
+            return None;
+        }
+
+        if self.len == 0 && !self.overflowed {
+            return None;
+        }
+
+        self.last_request_id = UNSOLICITED_REQUEST_ID;
+        let result = if self.overflowed {
+            Err(ProtocolError::Framing)
+        } else {
+            self.decode()
+        };
+        self.reset();
+        Some(result)
+    }
+
+    /// Discards any partially received packet.
+    pub fn reset(&mut self) {
+        self.len = 0;
+        self.overflowed = false;
+    }
+
+    fn decode(&mut self) -> Result<MessageFrame, ProtocolError> {
+        let mut data = [0u8; MAX_PACKET_SIZE];
+        let data_len = cobs_decode(&self.buf[..self.len], &mut data)?;
+        if data_len != PACKET_DATA_SIZE {
+            return Err(ProtocolError::Framing);
+        }
+        self.last_request_id = data[0];
+        if crc8(&data[..CHECKSUM_OFFSET]) != data[CHECKSUM_OFFSET] {
+            return Err(ProtocolError::Checksum);
+        }
+
+        let mut frame = MessageFrame::new();
+        frame.request_id = data[0];
+        frame.buf.copy_from_slice(&data[1..CHECKSUM_OFFSET]);
+        Ok(frame)
+    }
+}
+
+impl Default for PacketDecoder {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Consistent Overhead Byte Stuffing. `out` must have room for one byte more
+/// than `data`. Returns the encoded length.
+fn cobs_encode(data: &[u8], out: &mut [u8]) -> usize {
+    let mut code_index = 0;
+    let mut out_index = 1;
+    let mut code = 1u8;
+
+    for byte in data {
+        if *byte == 0 {
+            out[code_index] = code;
+            code_index = out_index;
+            out_index += 1;
+            code = 1;
+        } else {
+            out[out_index] = *byte;
+            out_index += 1;
+            code += 1;
+            if code == 0xFF {
+                out[code_index] = code;
+                code_index = out_index;
+                out_index += 1;
+                code = 1;
+            }
+        }
+    }
+    out[code_index] = code;
+    out_index
+}
+
+/// Reverses `cobs_encode`, returning the decoded length.
+fn cobs_decode(encoded: &[u8], out: &mut [u8]) -> Result<usize, ProtocolError> {
+    let mut in_index = 0;
+    let mut out_index = 0;
+
+    while in_index < encoded.len() {
+        let code = encoded[in_index] as usize;
+        if code == 0 || in_index + code > encoded.len() {
+            return Err(ProtocolError::Framing);
+        }
+        in_index += 1;
+        for _ in 1..code {
+            *out.get_mut(out_index).ok_or(ProtocolError::Framing)? = encoded[in_index];
+            out_index += 1;
+            in_index += 1;
+        }
+        if code < 0xFF && in_index < encoded.len() {
+            *out.get_mut(out_index).ok_or(ProtocolError::Framing)? = 0;
+            out_index += 1;
+        }
+    }
+    Ok(out_index)
+}
+
+#[cfg(test)]
+mod tests {
+    extern crate std;
+
+    use super::*;
+    use std::vec;
+    use std::vec::Vec;
+
+    fn frame(request_id: u8, bytes: [u8; FRAME_SIZE]) -> MessageFrame {
+        let mut frame = MessageFrame::new();
+        frame.request_id = request_id;
+        frame.buf = bytes;
+        frame
+    }
+
+    /// Pushes bytes, returning every packet they completed.
+    fn push_all(
+        decoder: &mut PacketDecoder,
+        bytes: &[u8],
+    ) -> Vec<Result<MessageFrame, ProtocolError>> {
+        bytes
+            .iter()
+            .filter_map(|byte| decoder.push(*byte))
+            .collect()
+    }
+
+    #[test]
+    fn crc8_matches_smbus_check_value() {
+        assert_eq!(crc8(b"123456789"), 0xF4);
+        assert_eq!(crc8(&[]), 0);
+    }
+
+    #[test]
+    fn cobs_round_trips_zeros_and_long_runs() {
+        let mut data = [0u8; 300];
+        for (i, byte) in data.iter_mut().enumerate() {
+            *byte = if i % 100 == 0 { 0 } else { (i % 255 + 1) as u8 };
+        }
+        for len in [0, 1, 2, 254, 255, 256, 300] {
+            let mut encoded = [0u8; 310];
+            let encoded_len = cobs_encode(&data[..len], &mut encoded);
+            assert!(!encoded[..encoded_len].contains(&0));
+
+            let mut decoded = [0u8; 300];
+            assert_eq!(cobs_decode(&encoded[..encoded_len], &mut decoded), Ok(len));
+            assert_eq!(decoded[..len], data[..len]);
+        }
+    }
+
+    #[test]
+    fn packets_round_trip() {
+        let sent = frame(7, [0x0A, 0, 1, 0, 0, 0xFF, 0, 2]);
+        let packet = Packet::encode(&sent);
+        assert_eq!(packet.as_bytes().last(), Some(&PACKET_DELIMITER));
+        assert!(!packet.as_bytes()[..packet.as_bytes().len() - 1].contains(&0));
+
+        let mut decoder = PacketDecoder::new();
+        let received = push_all(&mut decoder, packet.as_bytes());
+        assert_eq!(received.len(), 1);
+        let received = received[0].as_ref().unwrap();
+        assert_eq!(received.request_id, 7);
+        assert_eq!(received.buf, sent.buf);
+        assert_eq!(decoder.last_request_id(), 7);
+    }
+
+    #[test]
+    fn any_flipped_bit_fails_the_checksum() {
+        let mut data = [0u8; PACKET_DATA_SIZE];
+        data[0] = 9;
+        data[1..CHECKSUM_OFFSET].copy_from_slice(&[0x05, 1, 2, 3, 0, 0, 0, 0]);
+        data[CHECKSUM_OFFSET] = crc8(&data[..CHECKSUM_OFFSET]);
+
+        let mut decoder = PacketDecoder::new();
+        for byte in 0..PACKET_DATA_SIZE {
+            for bit in 0..8 {
+                let mut corrupted = data;
+                corrupted[byte] ^= 1 << bit;
+                let mut encoded = [0u8; MAX_PACKET_SIZE];
+                let len = cobs_encode(&corrupted, &mut encoded);
+                encoded[len] = PACKET_DELIMITER;
+
+                let received = push_all(&mut decoder, &encoded[..=len]);
+                assert_eq!(received.len(), 1);
+                assert_eq!(
+                    received[0].as_ref().err(),
+                    Some(&ProtocolError::Checksum),
+                    "byte {} bit {}",
+                    byte,
+                    bit
+                );
+                assert_eq!(decoder.last_request_id(), corrupted[0]);
+            }
+        }
+    }
+
+    #[test]
+    fn decoder_resyncs_after_garbage() {
+        let packet = Packet::encode(&frame(3, [0x01, 0, 0, 0, 0, 0, 0, 0]));
+        let mut decoder = PacketDecoder::new();
+
+        // Repeated delimiters are skipped
+        assert!(push_all(&mut decoder, &[0, 0, 0]).is_empty());
+
+        // Line noise ending in a delimiter costs one rejected packet
+        let received = push_all(&mut decoder, &[0x42, 0x13, 0x37, 0x00]);
+        assert_eq!(received.len(), 1);
+        assert!(received[0].is_err());
+        assert_eq!(decoder.last_request_id(), UNSOLICITED_REQUEST_ID);
+
+        // Noise glued to the front of a packet loses that packet only
+        let mut noisy = vec![0x42, 0x13];
+        noisy.extend_from_slice(packet.as_bytes());
+        noisy.extend_from_slice(packet.as_bytes());
+        let received = push_all(&mut decoder, &noisy);
+        assert_eq!(received.len(), 2);
+        assert!(received[0].is_err());
+        assert_eq!(received[1].as_ref().unwrap().request_id, 3);
+    }
+
+    #[test]
+    fn oversize_packet_is_rejected() {
+        let mut decoder = PacketDecoder::new();
+        let received = push_all(&mut decoder, &[0x11; MAX_PACKET_SIZE + 5]);
+        assert!(received.is_empty());
+        let received = push_all(&mut decoder, &[PACKET_DELIMITER]);
+        assert_eq!(received.len(), 1);
+        assert_eq!(received[0].as_ref().err(), Some(&ProtocolError::Framing));
+
+        // A packet one byte short is rejected too
+        let packet = Packet::encode(&frame(4, [0x01, 1, 2, 3, 4, 5, 6, 7]));
+        let bytes = packet.as_bytes();
+        let mut short = bytes[..bytes.len() - 2].to_vec();
+        short.push(PACKET_DELIMITER);
+        let received = push_all(&mut decoder, &short);
+        assert_eq!(received[0].as_ref().err(), Some(&ProtocolError::Framing));
+
+        // And the decoder is back in sync for the next one
+        let received = push_all(&mut decoder, bytes);
+        assert_eq!(received[0].as_ref().unwrap().request_id, 4);
+    }
+}
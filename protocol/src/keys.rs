@@ -0,0 +1,840 @@
+//! Keys that can be bound to the micropad's inputs, and their on-wire
+//! encoding.
+//!
+//! A key is sent as 3 bytes: a key kind, followed by a 16 bit little endian
+//! code whose meaning depends on the kind.
+//!
+//! Keys are written for people as their names: keyboard keys as `PageDown`
+//! or `F13`, with any modifiers first, as in `ctrl+shift+M`, and media keys
+//! as `media:PlayPause`, system keys as `system:Sleep` and mouse wheel
+//! scrolling as `scroll:Down`. Codes are accepted too, as `normal:0x28`,
+//! `media:0xCD` or `system:0x82`.
+
+use core::convert::TryFrom;
+use core::fmt;
+use core::str::FromStr;
+
+use crate::ProtocolError;
+
+/// Size of an encoded key, in bytes.
+pub const ENCODED_KEY_SIZE: usize = 3;
+
+const KIND_NORMAL: u8 = 0x01;
+const KIND_MEDIA: u8 = 0x02;
+const KIND_COMBO: u8 = 0x03;
+const KIND_SYSTEM: u8 = 0x04;
+const KIND_SCROLL: u8 = 0x05;
+
+// Scan codes taken from: https://www.usb.org/sites/default/files/documents/hut1_12v2.pdf
+#[derive(Copy, Clone, Eq, PartialEq, Debug)]
+pub enum Key {
+    Normal(ScanCode),
+    Media(MediaCode),
+    /// A keyboard key pressed with modifiers held, like Ctrl+Shift+M.
+    Combo {
+        modifiers: Modifiers,
+        scan_code: ScanCode,
+    },
+    System(SystemCode),
+    /// The mouse wheel, scrolled by each step the encoder turns.
+    Scroll(ScrollDirection),
+}
+
+impl Key {
+    pub fn encode(&self) -> [u8; ENCODED_KEY_SIZE] {
+        let (kind, code) = match self {
+            Key::Normal(scan_code) => (KIND_NORMAL, scan_code.raw() as u16),
+            Key::Media(media_code) => (KIND_MEDIA, media_code.usage()),
+            Key::Combo {
+                modifiers,
+                scan_code,
+            } => (
+                KIND_COMBO,
+                u16::from_le_bytes([scan_code.raw(), modifiers.0]),
+            ),
+            Key::System(system_code) => (KIND_SYSTEM, system_code.usage() as u16),
+            Key::Scroll(direction) => (KIND_SCROLL, direction.raw() as u16),
+        };
+        let code = code.to_le_bytes();
+        [kind, code[0], code[1]]
+    }
+
+    pub fn decode(bytes: &[u8]) -> Result<Key, ProtocolError> {
+        if bytes.len() < ENCODED_KEY_SIZE {
+            return Err(ProtocolError::Truncated);
+        }
+        let code = u16::from_le_bytes([bytes[1], bytes[2]]);
+        match bytes[0] {
+            KIND_NORMAL => {
+                let scan_code = u8::try_from(code).map_err(|_| ProtocolError::InvalidArgument)?;
+                Ok(Key::Normal(ScanCode::try_from(scan_code)?))
+            }
+            KIND_MEDIA => Ok(Key::Media(MediaCode::from_usage(code)?)),
+            KIND_COMBO => {
+                let [scan_code, modifiers] = code.to_le_bytes();
+                Ok(Key::Combo {
+                    modifiers: Modifiers(modifiers),
+                    scan_code: ScanCode::try_from(scan_code)?,
+                })
+            }
+            KIND_SYSTEM => {
+                let usage = u8::try_from(code).map_err(|_| ProtocolError::InvalidArgument)?;
+                Ok(Key::System(SystemCode::try_from(usage)?))
+            }
+            KIND_SCROLL => {
+                let direction = u8::try_from(code).map_err(|_| ProtocolError::InvalidArgument)?;
+                Ok(Key::Scroll(ScrollDirection::try_from(direction)?))
+            }
+            _ => Err(ProtocolError::InvalidArgument),
+        }
+    }
+}
+
+/// Modifier keys held with a key, one bit each, laid out as the keyboard
+/// report's modifier byte.
+#[derive(Copy, Clone, Eq, PartialEq, Debug, Default)]
+pub struct Modifiers(pub u8);
+
+impl Modifiers {
+    pub const LEFT_CTRL: Modifiers = Modifiers(0x01);
+    pub const LEFT_SHIFT: Modifiers = Modifiers(0x02);
+    pub const LEFT_ALT: Modifiers = Modifiers(0x04);
+    pub const LEFT_GUI: Modifiers = Modifiers(0x08);
+    pub const RIGHT_CTRL: Modifiers = Modifiers(0x10);
+    pub const RIGHT_SHIFT: Modifiers = Modifiers(0x20);
+    pub const RIGHT_ALT: Modifiers = Modifiers(0x40);
+    pub const RIGHT_GUI: Modifiers = Modifiers(0x80);
+
+    pub const fn empty() -> Modifiers {
+        Modifiers(0)
+    }
+
+    pub const fn union(&self, other: Modifiers) -> Modifiers {
+        Modifiers(self.0 | other.0)
+    }
+
+    pub fn contains(&self, other: Modifiers) -> bool {
+        self.0 & other.0 == other.0
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.0 == 0
+    }
+
+    /// Modifier names, in the order they're written before a key.
+    pub const NAMES: [(&'static str, Modifiers); 8] = [
+        ("ctrl", Modifiers::LEFT_CTRL),
+        ("shift", Modifiers::LEFT_SHIFT),
+        ("alt", Modifiers::LEFT_ALT),
+        ("gui", Modifiers::LEFT_GUI),
+        ("right_ctrl", Modifiers::RIGHT_CTRL),
+        ("right_shift", Modifiers::RIGHT_SHIFT),
+        ("right_alt", Modifiers::RIGHT_ALT),
+        ("right_gui", Modifiers::RIGHT_GUI),
+    ];
+
+    /// Looks up a modifier by name, ignoring case. Super, Win and Cmd are
+    /// accepted for GUI.
+    pub fn from_name(name: &str) -> Option<Modifiers> {
+        if ["super", "win", "cmd"]
+            .iter()
+            .any(|alias| alias.eq_ignore_ascii_case(name))
+        {
+            return Some(Modifiers::LEFT_GUI);
+        }
+        Modifiers::NAMES
+            .iter()
+            .find(|(n, _)| n.eq_ignore_ascii_case(name))
+            .map(|(_, modifier)| *modifier)
+    }
+
+    /// The names of the modifiers held, in `NAMES` order.
+    pub fn names(&self) -> impl Iterator<Item = &'static str> + '_ {
+        Modifiers::NAMES
+            .iter()
+            .filter(move |(_, modifier)| self.contains(*modifier))
+            .map(|(name, _)| *name)
+    }
+}
+
+/// Defines `ScanCode`, along with its conversion from raw codes and its
+/// names. Names default to the variant name.
+macro_rules! scan_codes {
+    ($($variant:ident = $code:literal $(=> $name:literal)?,)*) => {
+        /// A key on the HID Keyboard/Keypad page (0x07). The modifier keys
+        /// (0xE0 - 0xE7) are left to `Modifiers`.
+        #[repr(u8)]
+        #[derive(Copy, Clone, Eq, PartialEq, Debug)]
+        pub enum ScanCode {
+            $($variant = $code,)*
+        }
+
+        impl ScanCode {
+            /// Every scan code, in usage order.
+            pub const ALL: &'static [ScanCode] = &[$(ScanCode::$variant,)*];
+
+            pub fn name(&self) -> &'static str {
+                match self {
+                    $(ScanCode::$variant => scan_codes!(@name $variant $($name)?),)*
+                }
+            }
+        }
+
+        impl TryFrom<u8> for ScanCode {
+            type Error = ProtocolError;
+
+            fn try_from(code: u8) -> Result<ScanCode, ProtocolError> {
+                match code {
+                    $($code => Ok(ScanCode::$variant),)*
+                    _ => Err(ProtocolError::InvalidArgument),
+                }
+            }
+        }
+    };
+    (@name $variant:ident $name:literal) => { $name };
+    (@name $variant:ident) => { stringify!($variant) };
+}
+
+scan_codes! {
+    A = 0x04,
+    B = 0x05,
+    C = 0x06,
+    D = 0x07,
+    E = 0x08,
+    F = 0x09,
+    G = 0x0A,
+    H = 0x0B,
+    I = 0x0C,
+    J = 0x0D,
+    K = 0x0E,
+    L = 0x0F,
+    M = 0x10,
+    N = 0x11,
+    O = 0x12,
+    P = 0x13,
+    Q = 0x14,
+    R = 0x15,
+    S = 0x16,
+    T = 0x17,
+    U = 0x18,
+    V = 0x19,
+    W = 0x1A,
+    X = 0x1B,
+    Y = 0x1C,
+    Z = 0x1D,
+    Num1 = 0x1E => "1",
+    Num2 = 0x1F => "2",
+    Num3 = 0x20 => "3",
+    Num4 = 0x21 => "4",
+    Num5 = 0x22 => "5",
+    Num6 = 0x23 => "6",
+    Num7 = 0x24 => "7",
+    Num8 = 0x25 => "8",
+    Num9 = 0x26 => "9",
+    Num0 = 0x27 => "0",
+    Return = 0x28,
+    Escape = 0x29,
+    Backspace = 0x2A,
+    Tab = 0x2B,
+    Space = 0x2C,
+    Minus = 0x2D,
+    Equal = 0x2E,
+    LeftBracket = 0x2F,
+    RightBracket = 0x30,
+    Backslash = 0x31,
+    NonUsHash = 0x32,
+    Semicolon = 0x33,
+    Quote = 0x34,
+    Grave = 0x35,
+    Comma = 0x36,
+    Period = 0x37,
+    Slash = 0x38,
+    CapsLock = 0x39,
+    F1 = 0x3A,
+    F2 = 0x3B,
+    F3 = 0x3C,
+    F4 = 0x3D,
+    F5 = 0x3E,
+    F6 = 0x3F,
+    F7 = 0x40,
+    F8 = 0x41,
+    F9 = 0x42,
+    F10 = 0x43,
+    F11 = 0x44,
+    F12 = 0x45,
+    PrintScreen = 0x46,
+    ScrollLock = 0x47,
+    Pause = 0x48,
+    Insert = 0x49,
+    Home = 0x4A,
+    PageUp = 0x4B,
+    Delete = 0x4C,
+    End = 0x4D,
+    PageDown = 0x4E,
+    RightArrow = 0x4F,
+    LeftArrow = 0x50,
+    DownArrow = 0x51,
+    UpArrow = 0x52,
+    NumLock = 0x53,
+    KeypadSlash = 0x54,
+    KeypadAsterisk = 0x55,
+    KeypadMinus = 0x56,
+    KeypadPlus = 0x57,
+    KeypadEnter = 0x58,
+    Keypad1 = 0x59,
+    Keypad2 = 0x5A,
+    Keypad3 = 0x5B,
+    Keypad4 = 0x5C,
+    Keypad5 = 0x5D,
+    Keypad6 = 0x5E,
+    Keypad7 = 0x5F,
+    Keypad8 = 0x60,
+    Keypad9 = 0x61,
+    Keypad0 = 0x62,
+    KeypadPeriod = 0x63,
+    NonUsBackslash = 0x64,
+    Application = 0x65,
+    Power = 0x66,
+    KeypadEqual = 0x67,
+    F13 = 0x68,
+    F14 = 0x69,
+    F15 = 0x6A,
+    F16 = 0x6B,
+    F17 = 0x6C,
+    F18 = 0x6D,
+    F19 = 0x6E,
+    F20 = 0x6F,
+    F21 = 0x70,
+    F22 = 0x71,
+    F23 = 0x72,
+    F24 = 0x73,
+    Execute = 0x74,
+    Help = 0x75,
+    Menu = 0x76,
+    Select = 0x77,
+    Stop = 0x78,
+    Again = 0x79,
+    Undo = 0x7A,
+    Cut = 0x7B,
+    Copy = 0x7C,
+    Paste = 0x7D,
+    Find = 0x7E,
+    Mute = 0x7F,
+    VolumeUp = 0x80,
+    VolumeDown = 0x81,
+    LockingCapsLock = 0x82,
+    LockingNumLock = 0x83,
+    LockingScrollLock = 0x84,
+    KeypadComma = 0x85,
+    KeypadEqualSign = 0x86,
+    International1 = 0x87,
+    International2 = 0x88,
+    International3 = 0x89,
+    International4 = 0x8A,
+    International5 = 0x8B,
+    International6 = 0x8C,
+    International7 = 0x8D,
+    International8 = 0x8E,
+    International9 = 0x8F,
+    Lang1 = 0x90,
+    Lang2 = 0x91,
+    Lang3 = 0x92,
+    Lang4 = 0x93,
+    Lang5 = 0x94,
+    Lang6 = 0x95,
+    Lang7 = 0x96,
+    Lang8 = 0x97,
+    Lang9 = 0x98,
+    AlternateErase = 0x99,
+    SysReq = 0x9A,
+    Cancel = 0x9B,
+    Clear = 0x9C,
+    Prior = 0x9D,
+    Return2 = 0x9E,
+    Separator = 0x9F,
+    Out = 0xA0,
+    Oper = 0xA1,
+    ClearAgain = 0xA2,
+    CrSel = 0xA3,
+    ExSel = 0xA4,
+    Keypad00 = 0xB0,
+    Keypad000 = 0xB1,
+    ThousandsSeparator = 0xB2,
+    DecimalSeparator = 0xB3,
+    CurrencyUnit = 0xB4,
+    CurrencySubunit = 0xB5,
+    KeypadLeftParen = 0xB6,
+    KeypadRightParen = 0xB7,
+    KeypadLeftBrace = 0xB8,
+    KeypadRightBrace = 0xB9,
+    KeypadTab = 0xBA,
+    KeypadBackspace = 0xBB,
+    KeypadA = 0xBC,
+    KeypadB = 0xBD,
+    KeypadC = 0xBE,
+    KeypadD = 0xBF,
+    KeypadE = 0xC0,
+    KeypadF = 0xC1,
+    KeypadXor = 0xC2,
+    KeypadCaret = 0xC3,
+    KeypadPercent = 0xC4,
+    KeypadLess = 0xC5,
+    KeypadGreater = 0xC6,
+    KeypadAmpersand = 0xC7,
+    KeypadDoubleAmpersand = 0xC8,
+    KeypadBar = 0xC9,
+    KeypadDoubleBar = 0xCA,
+    KeypadColon = 0xCB,
+    KeypadHash = 0xCC,
+    KeypadSpace = 0xCD,
+    KeypadAt = 0xCE,
+    KeypadBang = 0xCF,
+    KeypadMemoryStore = 0xD0,
+    KeypadMemoryRecall = 0xD1,
+    KeypadMemoryClear = 0xD2,
+    KeypadMemoryAdd = 0xD3,
+    KeypadMemorySubtract = 0xD4,
+    KeypadMemoryMultiply = 0xD5,
+    KeypadMemoryDivide = 0xD6,
+    KeypadPlusMinus = 0xD7,
+    KeypadClear = 0xD8,
+    KeypadClearEntry = 0xD9,
+    KeypadBinary = 0xDA,
+    KeypadOctal = 0xDB,
+    KeypadDecimal = 0xDC,
+    KeypadHexadecimal = 0xDD,
+}
+
+/// Other names keys are commonly written with.
+const SCAN_CODE_ALIASES: [(&str, ScanCode); 7] = [
+    ("Enter", ScanCode::Return),
+    ("Esc", ScanCode::Escape),
+    ("Del", ScanCode::Delete),
+    ("Up", ScanCode::UpArrow),
+    ("Down", ScanCode::DownArrow),
+    ("Left", ScanCode::LeftArrow),
+    ("Right", ScanCode::RightArrow),
+];
+
+impl ScanCode {
+    pub fn raw(&self) -> u8 {
+        *self as u8
+    }
+
+    /// Looks up a key by its name or a common alias, ignoring case.
+    pub fn from_name(name: &str) -> Option<ScanCode> {
+        ScanCode::ALL
+            .iter()
+            .map(|scan_code| (scan_code.name(), *scan_code))
+            .chain(SCAN_CODE_ALIASES.iter().copied())
+            .find(|(n, _)| n.eq_ignore_ascii_case(name))
+            .map(|(_, scan_code)| scan_code)
+    }
+}
+
+/// A usage on the HID Consumer page (0x0C): media transport and volume
+/// keys, display brightness, application launchers and the like. Any usage
+/// from 0x001 to `MediaCode::MAX_USAGE` can be sent; the common ones have
+/// names.
+#[derive(Copy, Clone, Eq, PartialEq, Debug)]
+pub struct MediaCode(u16);
+
+impl MediaCode {
+    pub const BRIGHTNESS_UP: MediaCode = MediaCode(0x6F);
+    pub const BRIGHTNESS_DOWN: MediaCode = MediaCode(0x70);
+    pub const PLAY: MediaCode = MediaCode(0xB0);
+    pub const PAUSE: MediaCode = MediaCode(0xB1);
+    pub const RECORD: MediaCode = MediaCode(0xB2);
+    pub const FAST_FORWARD: MediaCode = MediaCode(0xB3);
+    pub const REWIND: MediaCode = MediaCode(0xB4);
+    pub const SCAN_NEXT: MediaCode = MediaCode(0xB5);
+    pub const SCAN_PREV: MediaCode = MediaCode(0xB6);
+    pub const STOP: MediaCode = MediaCode(0xB7);
+    pub const EJECT: MediaCode = MediaCode(0xB8);
+    pub const PLAY_PAUSE: MediaCode = MediaCode(0xCD);
+    pub const MUTE: MediaCode = MediaCode(0xE2);
+    pub const VOLUME_UP: MediaCode = MediaCode(0xE9);
+    pub const VOLUME_DOWN: MediaCode = MediaCode(0xEA);
+    pub const MEDIA_PLAYER: MediaCode = MediaCode(0x183);
+    pub const MAIL: MediaCode = MediaCode(0x18A);
+    pub const CALCULATOR: MediaCode = MediaCode(0x192);
+    pub const FILE_BROWSER: MediaCode = MediaCode(0x194);
+    pub const WEB_BROWSER: MediaCode = MediaCode(0x196);
+    pub const SCREEN_LOCK: MediaCode = MediaCode(0x19E);
+    pub const BROWSER_SEARCH: MediaCode = MediaCode(0x221);
+    pub const BROWSER_HOME: MediaCode = MediaCode(0x223);
+    pub const BROWSER_BACK: MediaCode = MediaCode(0x224);
+    pub const BROWSER_FORWARD: MediaCode = MediaCode(0x225);
+    pub const BROWSER_REFRESH: MediaCode = MediaCode(0x227);
+    pub const BROWSER_BOOKMARKS: MediaCode = MediaCode(0x22A);
+
+    /// Highest usage the consumer control report can carry.
+    pub const MAX_USAGE: u16 = 0xFFF;
+
+    /// Named usages, written as `media:<name>`.
+    pub const NAMES: [(&'static str, MediaCode); 27] = [
+        ("BrightnessUp", MediaCode::BRIGHTNESS_UP),
+        ("BrightnessDown", MediaCode::BRIGHTNESS_DOWN),
+        ("Play", MediaCode::PLAY),
+        ("Pause", MediaCode::PAUSE),
+        ("Record", MediaCode::RECORD),
+        ("FastForward", MediaCode::FAST_FORWARD),
+        ("Rewind", MediaCode::REWIND),
+        ("ScanNext", MediaCode::SCAN_NEXT),
+        ("ScanPrev", MediaCode::SCAN_PREV),
+        ("Stop", MediaCode::STOP),
+        ("Eject", MediaCode::EJECT),
+        ("PlayPause", MediaCode::PLAY_PAUSE),
+        ("Mute", MediaCode::MUTE),
+        ("VolumeUp", MediaCode::VOLUME_UP),
+        ("VolumeDown", MediaCode::VOLUME_DOWN),
+        ("MediaPlayer", MediaCode::MEDIA_PLAYER),
+        ("Mail", MediaCode::MAIL),
+        ("Calculator", MediaCode::CALCULATOR),
+        ("FileBrowser", MediaCode::FILE_BROWSER),
+        ("WebBrowser", MediaCode::WEB_BROWSER),
+        ("ScreenLock", MediaCode::SCREEN_LOCK),
+        ("BrowserSearch", MediaCode::BROWSER_SEARCH),
+        ("BrowserHome", MediaCode::BROWSER_HOME),
+        ("BrowserBack", MediaCode::BROWSER_BACK),
+        ("BrowserForward", MediaCode::BROWSER_FORWARD),
+        ("BrowserRefresh", MediaCode::BROWSER_REFRESH),
+        ("BrowserBookmarks", MediaCode::BROWSER_BOOKMARKS),
+    ];
+
+    /// The usage ID on the HID Consumer page, used on the wire and in the
+    /// consumer control report.
+    pub fn usage(&self) -> u16 {
+        self.0
+    }
+
+    /// The usage's name, if it has one.
+    pub fn name(&self) -> Option<&'static str> {
+        MediaCode::NAMES
+            .iter()
+            .find(|(_, media_code)| media_code == self)
+            .map(|(name, _)| *name)
+    }
+
+    /// Looks up a media key by name, ignoring case.
+    pub fn from_name(name: &str) -> Option<MediaCode> {
+        MediaCode::NAMES
+            .iter()
+            .find(|(n, _)| n.eq_ignore_ascii_case(name))
+            .map(|(_, media_code)| *media_code)
+    }
+
+    /// Fails with `InvalidArgument` for usage 0, which means no key, and
+    /// usages past `MAX_USAGE`.
+    pub fn from_usage(usage: u16) -> Result<MediaCode, ProtocolError> {
+        match usage {
+            1..=MediaCode::MAX_USAGE => Ok(MediaCode(usage)),
+            _ => Err(ProtocolError::InvalidArgument),
+        }
+    }
+}
+
+/// A usage on the HID Generic Desktop page's System Control collection,
+/// which the host handles itself whatever has focus.
+#[repr(u8)]
+#[derive(Copy, Clone, Eq, PartialEq, Debug)]
+pub enum SystemCode {
+    PowerDown = 0x81,
+    Sleep = 0x82,
+    WakeUp = 0x83,
+}
+
+impl SystemCode {
+    pub const ALL: [SystemCode; 3] = [SystemCode::PowerDown, SystemCode::Sleep, SystemCode::WakeUp];
+
+    /// The usage ID on the HID Generic Desktop page, used on the wire.
+    pub fn usage(&self) -> u8 {
+        *self as u8
+    }
+
+    /// The key's bit in the system control report.
+    pub fn bit(&self) -> u8 {
+        1 << (self.usage() - SystemCode::PowerDown.usage())
+    }
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            SystemCode::PowerDown => "PowerDown",
+            SystemCode::Sleep => "Sleep",
+            SystemCode::WakeUp => "WakeUp",
+        }
+    }
+
+    /// Looks up a system key by name, ignoring case.
+    pub fn from_name(name: &str) -> Option<SystemCode> {
+        SystemCode::ALL
+            .iter()
+            .copied()
+            .find(|system_code| system_code.name().eq_ignore_ascii_case(name))
+    }
+}
+
+impl TryFrom<u8> for SystemCode {
+    type Error = ProtocolError;
+
+    fn try_from(usage: u8) -> Result<SystemCode, ProtocolError> {
+        SystemCode::ALL
+            .iter()
+            .copied()
+            .find(|system_code| system_code.usage() == usage)
+            .ok_or(ProtocolError::InvalidArgument)
+    }
+}
+
+/// Which way a scroll key turns the mouse wheel: up and down on the
+/// vertical wheel, left and right on the horizontal one.
+#[repr(u8)]
+#[derive(Copy, Clone, Eq, PartialEq, Debug)]
+pub enum ScrollDirection {
+    Up = 0x01,
+    Down = 0x02,
+    Left = 0x03,
+    Right = 0x04,
+}
+
+impl ScrollDirection {
+    pub const ALL: [ScrollDirection; 4] = [
+        ScrollDirection::Up,
+        ScrollDirection::Down,
+        ScrollDirection::Left,
+        ScrollDirection::Right,
+    ];
+
+    pub fn raw(&self) -> u8 {
+        *self as u8
+    }
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            ScrollDirection::Up => "Up",
+            ScrollDirection::Down => "Down",
+            ScrollDirection::Left => "Left",
+            ScrollDirection::Right => "Right",
+        }
+    }
+
+    /// Looks up a direction by name, ignoring case.
+    pub fn from_name(name: &str) -> Option<ScrollDirection> {
+        ScrollDirection::ALL
+            .iter()
+            .copied()
+            .find(|direction| direction.name().eq_ignore_ascii_case(name))
+    }
+}
+
+impl TryFrom<u8> for ScrollDirection {
+    type Error = ProtocolError;
+
+    fn try_from(raw: u8) -> Result<ScrollDirection, ProtocolError> {
+        ScrollDirection::ALL
+            .iter()
+            .copied()
+            .find(|direction| direction.raw() == raw)
+            .ok_or(ProtocolError::InvalidArgument)
+    }
+}
+
+impl fmt::Display for Key {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Key::Normal(scan_code) => f.write_str(scan_code.name()),
+            Key::Media(media_code) => match media_code.name() {
+                Some(name) => write!(f, "media:{}", name),
+                None => write!(f, "media:{:#05X}", media_code.usage()),
+            },
+            Key::Combo {
+                modifiers,
+                scan_code,
+            } => {
+                for name in modifiers.names() {
+                    write!(f, "{}+", name)?;
+                }
+                f.write_str(scan_code.name())
+            }
+            Key::System(system_code) => write!(f, "system:{}", system_code.name()),
+            Key::Scroll(direction) => write!(f, "scroll:{}", direction.name()),
+        }
+    }
+}
+
+impl FromStr for Key {
+    type Err = ProtocolError;
+
+    /// Reads keys the way `Display` writes them, or by code.
+    fn from_str(value: &str) -> Result<Key, ProtocolError> {
+        if let Some((modifier_names, key)) = value.rsplit_once('+') {
+            let modifiers = modifier_names
+                .split('+')
+                .try_fold(Modifiers::empty(), |modifiers, name| {
+                    Some(modifiers.union(Modifiers::from_name(name.trim())?))
+                })
+                .ok_or(ProtocolError::InvalidArgument)?;
+            return match key.parse()? {
+                Key::Normal(scan_code) => Ok(Key::Combo {
+                    modifiers,
+                    scan_code,
+                }),
+                _ => Err(ProtocolError::InvalidArgument),
+            };
+        }
+
+        let key = match value.trim().split_once(':') {
+            Some(("normal", code)) => parse_number(code)
+                .and_then(|code| u8::try_from(code).ok())
+                .and_then(|code| ScanCode::try_from(code).ok())
+                .map(Key::Normal),
+            Some(("media", code)) => match parse_number(code) {
+                Some(usage) => MediaCode::from_usage(usage).ok(),
+                None => MediaCode::from_name(code),
+            }
+            .map(Key::Media),
+            Some(("system", code)) => match parse_number(code) {
+                Some(usage) => u8::try_from(usage)
+                    .ok()
+                    .and_then(|usage| SystemCode::try_from(usage).ok()),
+                None => SystemCode::from_name(code),
+            }
+            .map(Key::System),
+            Some(("scroll", name)) => ScrollDirection::from_name(name).map(Key::Scroll),
+            Some(_) => None,
+            None => ScanCode::from_name(value.trim()).map(Key::Normal),
+        };
+        key.ok_or(ProtocolError::InvalidArgument)
+    }
+}
+
+/// Parses a decimal or 0x prefixed hex number.
+fn parse_number(value: &str) -> Option<u16> {
+    match value.strip_prefix("0x") {
+        Some(hex) => u16::from_str_radix(hex, 16).ok(),
+        None => value.parse().ok(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    extern crate std;
+
+    use super::*;
+    use std::format;
+    use std::string::ToString;
+    use std::vec::Vec;
+
+    fn all_keys() -> Vec<Key> {
+        let mut keys: Vec<Key> = ScanCode::ALL.iter().map(|s| Key::Normal(*s)).collect();
+        keys.extend(ScanCode::ALL.iter().map(|scan_code| Key::Combo {
+            modifiers: Modifiers::LEFT_CTRL.union(Modifiers::RIGHT_GUI),
+            scan_code: *scan_code,
+        }));
+        keys.extend(MediaCode::NAMES.iter().map(|(_, m)| Key::Media(*m)));
+        keys.push(Key::Media(MediaCode::from_usage(0x29F).unwrap()));
+        keys.push(Key::Media(
+            MediaCode::from_usage(MediaCode::MAX_USAGE).unwrap(),
+        ));
+        keys.extend(SystemCode::ALL.iter().map(|s| Key::System(*s)));
+        keys.extend(ScrollDirection::ALL.iter().map(|d| Key::Scroll(*d)));
+        keys
+    }
+
+    #[test]
+    fn every_key_round_trips_through_its_name() {
+        for key in all_keys() {
+            let name = key.to_string();
+            assert_eq!(name.parse(), Ok(key), "{}", name);
+        }
+    }
+
+    #[test]
+    fn every_key_round_trips_through_its_encoding() {
+        for key in all_keys() {
+            assert_eq!(Key::decode(&key.encode()), Ok(key), "{}", key);
+        }
+    }
+
+    #[test]
+    fn names_are_unique() {
+        let names: Vec<_> = all_keys().iter().map(|key| key.to_string()).collect();
+        for (i, name) in names.iter().enumerate() {
+            assert!(!names[..i].contains(name), "{}", name);
+        }
+    }
+
+    #[test]
+    fn backup_format_1_spellings_still_parse() {
+        // Format 1 backups wrote every key by code
+        for scan_code in ScanCode::ALL.iter() {
+            let code = format!("normal:0x{:02X}", scan_code.raw());
+            assert_eq!(code.parse(), Ok(Key::Normal(*scan_code)), "{}", code);
+        }
+        let return_key = Key::Normal(ScanCode::Return);
+        assert_eq!("normal:0x28".parse(), Ok(return_key));
+        assert_eq!(
+            "ctrl+shift+normal:0x28".parse(),
+            Ok(Key::Combo {
+                modifiers: Modifiers::LEFT_CTRL.union(Modifiers::LEFT_SHIFT),
+                scan_code: ScanCode::Return,
+            })
+        );
+        assert_eq!(
+            "right_alt+gui+normal:0x04".parse(),
+            Ok(Key::Combo {
+                modifiers: Modifiers::RIGHT_ALT.union(Modifiers::LEFT_GUI),
+                scan_code: ScanCode::A,
+            })
+        );
+        assert_eq!("media:0xCD".parse(), Ok(Key::Media(MediaCode::PLAY_PAUSE)));
+        for (_, media_code) in MediaCode::NAMES.iter() {
+            let code = format!("media:0x{:02X}", media_code.usage());
+            assert_eq!(code.parse(), Ok(Key::Media(*media_code)), "{}", code);
+        }
+    }
+
+    #[test]
+    fn names_ignore_case_and_accept_aliases() {
+        assert_eq!("pagedown".parse(), Ok(Key::Normal(ScanCode::PageDown)));
+        assert_eq!("Enter".parse(), Ok(Key::Normal(ScanCode::Return)));
+        assert_eq!(
+            "media:playpause".parse(),
+            Ok(Key::Media(MediaCode::PLAY_PAUSE))
+        );
+        assert_eq!(
+            "Cmd+Shift+m".parse(),
+            Ok(Key::Combo {
+                modifiers: Modifiers::LEFT_GUI.union(Modifiers::LEFT_SHIFT),
+                scan_code: ScanCode::M,
+            })
+        );
+    }
+
+    #[test]
+    fn invalid_keys_are_rejected() {
+        for name in [
+            "",
+            "Bogus",
+            "normal:0x00",
+            "normal:0x100",
+            "media:0",
+            "media:0x1000",
+            "media:Bogus",
+            "system:0x84",
+            "scroll:Sideways",
+            "shift+media:PlayPause",
+            "shift+system:Sleep",
+            "hyper+A",
+            "ctrl+",
+            "bogus:1",
+        ] {
+            assert_eq!(
+                name.parse::<Key>(),
+                Err(ProtocolError::InvalidArgument),
+                "{}",
+                name
+            );
+        }
+    }
+}
@@ -1,25 +1,463 @@
 #![no_std]
 
+use core::convert::TryFrom;
+use core::fmt;
+
+pub mod chords;
+pub mod colors;
+pub mod events;
+pub mod framing;
+pub mod keys;
+pub mod modes;
+
+pub use chords::Chord;
+pub use colors::Color;
+pub use events::{Direction, Event, EventSet};
+pub use framing::{Packet, PacketDecoder};
+pub use keys::{Key, MediaCode, Modifiers, ScanCode, ScrollDirection, SystemCode};
+pub use modes::{ModeName, MAX_MODE_NAME_SIZE};
+
+/// Size of a single message frame on the wire, in bytes.
+pub const FRAME_SIZE: usize = 8;
+
+/// Number of payload bytes carried by each frame, following the header byte.
+pub const FRAME_PAYLOAD_SIZE: usize = FRAME_SIZE - 1;
+
+/// Set on a frame's header byte when more frames follow for the same
+/// message or response.
+pub const CONTINUATION_FLAG: u8 = 0x80;
+
+/// Largest payload that can be spread over a sequence of continuation
+/// frames.
+pub const MAX_PAYLOAD_SIZE: usize = FRAME_PAYLOAD_SIZE * 8;
+
+/// Version of the serial protocol described by this crate, reported by the
+/// micropad in its capabilities. Bumped whenever messages are added or
+/// change shape. Firmware that predates capabilities counts as version 0.
+pub const PROTOCOL_VERSION: u8 = 1;
+
+/// Request ID reserved for frames the micropad sends without being asked.
+/// Hosts number their requests from 1, wrapping back to 1 after 255.
+pub const UNSOLICITED_REQUEST_ID: u8 = 0x00;
+
+/// The request ID following `request_id`, skipping the reserved ID.
+pub fn next_request_id(request_id: u8) -> u8 {
+    match request_id.wrapping_add(1) {
+        UNSOLICITED_REQUEST_ID => 1,
+        next => next,
+    }
+}
+
+/// Errors raised while decoding messages, responses and frames.
+#[derive(Copy, Clone, Eq, PartialEq, Debug)]
+pub enum ProtocolError {
+    /// The message type byte does not match any known message.
+    UnknownMessage(u8),
+    /// The response code byte does not match any known response code.
+    UnknownResponseCode(u8),
+    /// The payload ended before all expected arguments were read.
+    Truncated,
+    /// An argument was outside the range of values it may take.
+    InvalidArgument,
+    /// The frames carried more payload than fits in a `Payload`.
+    PayloadOverflow,
+    /// A packet's checksum did not match its contents.
+    Checksum,
+    /// A packet was malformed, too long or too short.
+    Framing,
+}
+
+impl ProtocolError {
+    /// The response code the micropad replies with when a request fails
+    /// to decode.
+    pub fn response_code(&self) -> ResponseCode {
+        match self {
+            ProtocolError::UnknownMessage(_) => ResponseCode::UnknownMessage,
+            ProtocolError::UnknownResponseCode(_)
+            | ProtocolError::Truncated
+            | ProtocolError::InvalidArgument
+            | ProtocolError::PayloadOverflow => ResponseCode::InvalidArgument,
+            ProtocolError::Checksum | ProtocolError::Framing => ResponseCode::ChecksumError,
+        }
+    }
+}
+
+impl fmt::Display for ProtocolError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ProtocolError::UnknownMessage(code) => write!(f, "unknown message type 0x{:02x}", code),
+            ProtocolError::UnknownResponseCode(code) => {
+                write!(f, "unknown response code 0x{:02x}", code)
+            }
+            ProtocolError::Truncated => write!(f, "payload is missing arguments"),
+            ProtocolError::InvalidArgument => write!(f, "argument out of range"),
+            ProtocolError::PayloadOverflow => write!(f, "payload exceeds maximum size"),
+            ProtocolError::Checksum => write!(f, "packet checksum mismatch"),
+            ProtocolError::Framing => write!(f, "malformed packet"),
+        }
+    }
+}
+
 pub enum Message {
     Ping,
     GetVersion,
     SetLedBrightness(u8),
     GetLedBrightness,
     GetModeInfo,
-    Unknown,
+    GetCapabilities,
+    Subscribe(EventSet),
+    Unsubscribe(EventSet),
+    GetKeyBinding {
+        mode: u8,
+        slot: u8,
+    },
+    SetKeyBinding {
+        mode: u8,
+        slot: u8,
+        key: Key,
+    },
+    GetModeName(u8),
+    CreateUserMode {
+        copy_from: u8,
+        name: ModeName,
+    },
+    DeleteUserMode(u8),
+    MoveUserMode {
+        from: u8,
+        to: u8,
+    },
+    RenameUserMode {
+        mode: u8,
+        name: ModeName,
+    },
+    SaveConfig,
+    LoadConfig,
+    FactoryReset,
+    SetMode(u8),
+    GetMode,
+    GetSlotColor {
+        mode: u8,
+        slot: u8,
+    },
+    SetSlotColor {
+        mode: u8,
+        slot: u8,
+        color: Color,
+    },
+    SetDebounceTime(u8),
+    GetDebounceTime,
+    GetChord {
+        mode: u8,
+        index: u8,
+    },
+    SetChord {
+        mode: u8,
+        index: u8,
+        chord: Option<Chord>,
+    },
 }
 
 impl Message {
+    pub fn message_type(&self) -> MessageType {
+        match self {
+            Message::Ping => MessageType::Ping,
+            Message::GetVersion => MessageType::GetVersion,
+            Message::SetLedBrightness(_) => MessageType::SetLedBrightness,
+            Message::GetLedBrightness => MessageType::GetLedBrightness,
+            Message::GetModeInfo => MessageType::GetModeInfo,
+            Message::GetCapabilities => MessageType::GetCapabilities,
+            Message::Subscribe(_) => MessageType::Subscribe,
+            Message::Unsubscribe(_) => MessageType::Unsubscribe,
+            Message::GetKeyBinding { .. } => MessageType::GetKeyBinding,
+            Message::SetKeyBinding { .. } => MessageType::SetKeyBinding,
+            Message::GetModeName(_) => MessageType::GetModeName,
+            Message::CreateUserMode { .. } => MessageType::CreateUserMode,
+            Message::DeleteUserMode(_) => MessageType::DeleteUserMode,
+            Message::MoveUserMode { .. } => MessageType::MoveUserMode,
+            Message::RenameUserMode { .. } => MessageType::RenameUserMode,
+            Message::SaveConfig => MessageType::SaveConfig,
+            Message::LoadConfig => MessageType::LoadConfig,
+            Message::FactoryReset => MessageType::FactoryReset,
+            Message::SetMode(_) => MessageType::SetMode,
+            Message::GetMode => MessageType::GetMode,
+            Message::GetSlotColor { .. } => MessageType::GetSlotColor,
+            Message::SetSlotColor { .. } => MessageType::SetSlotColor,
+            Message::SetDebounceTime(_) => MessageType::SetDebounceTime,
+            Message::GetDebounceTime => MessageType::GetDebounceTime,
+            Message::GetChord { .. } => MessageType::GetChord,
+            Message::SetChord { .. } => MessageType::SetChord,
+        }
+    }
+
     fn code(&self) -> u8 {
+        self.message_type().raw()
+    }
+
+    /// Writes the message's arguments, without its type byte.
+    pub fn fill(&self, payload: &mut Payload) {
+        payload.clear();
         match self {
-            Message::Ping => 0x01,
-            Message::GetVersion => 0x02,
-            Message::SetLedBrightness(_) => 0x03,
-            Message::GetLedBrightness => 0x04,
-            Message::GetModeInfo => 0x05,
-            Message::Unknown => 0xFF,
+            Message::Ping
+            | Message::GetLedBrightness
+            | Message::GetModeInfo
+            | Message::GetVersion
+            | Message::GetCapabilities
+            | Message::SaveConfig
+            | Message::LoadConfig
+            | Message::FactoryReset
+            | Message::GetMode
+            | Message::GetDebounceTime => {}
+            Message::SetLedBrightness(brightness) => {
+                payload.push(*brightness);
+            }
+            Message::SetDebounceTime(ms) => {
+                payload.push(*ms);
+            }
+            Message::Subscribe(events) | Message::Unsubscribe(events) => {
+                payload.push(events.0);
+            }
+            Message::GetKeyBinding { mode, slot } | Message::GetSlotColor { mode, slot } => {
+                payload.push(*mode);
+                payload.push(*slot);
+            }
+            Message::GetChord { mode, index } => {
+                payload.push(*mode);
+                payload.push(*index);
+            }
+            Message::SetChord { mode, index, chord } => {
+                payload.push(*mode);
+                payload.push(*index);
+                payload.extend_from_slice(&chords::encode(chord));
+            }
+            Message::SetKeyBinding { mode, slot, key } => {
+                payload.push(*mode);
+                payload.push(*slot);
+                payload.extend_from_slice(&key.encode());
+            }
+            Message::SetSlotColor { mode, slot, color } => {
+                payload.push(*mode);
+                payload.push(*slot);
+                payload.extend_from_slice(&color.encode());
+            }
+            Message::GetModeName(mode) | Message::DeleteUserMode(mode) | Message::SetMode(mode) => {
+                payload.push(*mode);
+            }
+            Message::CreateUserMode { copy_from, name } => {
+                payload.push(*copy_from);
+                name.encode(payload);
+            }
+            Message::MoveUserMode { from, to } => {
+                payload.push(*from);
+                payload.push(*to);
+            }
+            Message::RenameUserMode { mode, name } => {
+                payload.push(*mode);
+                name.encode(payload);
+            }
+        }
+    }
+
+    /// Decodes a message from its type byte and reassembled arguments.
+    /// Arguments beyond those the message takes are ignored, as they are
+    /// usually frame padding.
+    pub fn decode(code: u8, payload: &[u8]) -> Result<Message, ProtocolError> {
+        let byte = |i: usize| payload.get(i).copied().ok_or(ProtocolError::Truncated);
+        let message = match MessageType::try_from(code)? {
+            MessageType::Ping => Message::Ping,
+            MessageType::GetVersion => Message::GetVersion,
+            MessageType::SetLedBrightness => Message::SetLedBrightness(byte(0)?),
+            MessageType::GetLedBrightness => Message::GetLedBrightness,
+            MessageType::GetModeInfo => Message::GetModeInfo,
+            MessageType::GetCapabilities => Message::GetCapabilities,
+            MessageType::Subscribe => Message::Subscribe(EventSet(byte(0)?)),
+            MessageType::Unsubscribe => Message::Unsubscribe(EventSet(byte(0)?)),
+            MessageType::GetKeyBinding => Message::GetKeyBinding {
+                mode: byte(0)?,
+                slot: byte(1)?,
+            },
+            MessageType::SetKeyBinding => Message::SetKeyBinding {
+                mode: byte(0)?,
+                slot: byte(1)?,
+                key: Key::decode(payload.get(2..).unwrap_or_default())?,
+            },
+            MessageType::GetModeName => Message::GetModeName(byte(0)?),
+            MessageType::CreateUserMode => Message::CreateUserMode {
+                copy_from: byte(0)?,
+                name: ModeName::decode(payload.get(1..).unwrap_or_default())?,
+            },
+            MessageType::DeleteUserMode => Message::DeleteUserMode(byte(0)?),
+            MessageType::MoveUserMode => Message::MoveUserMode {
+                from: byte(0)?,
+                to: byte(1)?,
+            },
+            MessageType::RenameUserMode => Message::RenameUserMode {
+                mode: byte(0)?,
+                name: ModeName::decode(payload.get(1..).unwrap_or_default())?,
+            },
+            MessageType::SaveConfig => Message::SaveConfig,
+            MessageType::LoadConfig => Message::LoadConfig,
+            MessageType::FactoryReset => Message::FactoryReset,
+            MessageType::SetMode => Message::SetMode(byte(0)?),
+            MessageType::GetMode => Message::GetMode,
+            MessageType::GetSlotColor => Message::GetSlotColor {
+                mode: byte(0)?,
+                slot: byte(1)?,
+            },
+            MessageType::SetSlotColor => Message::SetSlotColor {
+                mode: byte(0)?,
+                slot: byte(1)?,
+                color: Color::decode(payload.get(2..).unwrap_or_default())?,
+            },
+            MessageType::SetDebounceTime => Message::SetDebounceTime(byte(0)?),
+            MessageType::GetDebounceTime => Message::GetDebounceTime,
+            MessageType::GetChord => Message::GetChord {
+                mode: byte(0)?,
+                index: byte(1)?,
+            },
+            MessageType::SetChord => Message::SetChord {
+                mode: byte(0)?,
+                index: byte(1)?,
+                chord: chords::decode(payload.get(2..).unwrap_or_default())?,
+            },
+        };
+        Ok(message)
+    }
+}
+
+/// The type byte of each message, without its arguments.
+#[repr(u8)]
+#[derive(Copy, Clone, Eq, PartialEq, Debug)]
+pub enum MessageType {
+    Ping = 0x01,
+    GetVersion = 0x02,
+    SetLedBrightness = 0x03,
+    GetLedBrightness = 0x04,
+    GetModeInfo = 0x05,
+    GetCapabilities = 0x06,
+    Subscribe = 0x07,
+    Unsubscribe = 0x08,
+    GetKeyBinding = 0x09,
+    SetKeyBinding = 0x0A,
+    GetModeName = 0x0B,
+    CreateUserMode = 0x0C,
+    DeleteUserMode = 0x0D,
+    MoveUserMode = 0x0E,
+    RenameUserMode = 0x0F,
+    SaveConfig = 0x10,
+    LoadConfig = 0x11,
+    FactoryReset = 0x12,
+    SetMode = 0x13,
+    GetMode = 0x14,
+    GetSlotColor = 0x15,
+    SetSlotColor = 0x16,
+    SetDebounceTime = 0x17,
+    GetDebounceTime = 0x18,
+    GetChord = 0x19,
+    SetChord = 0x1A,
+}
+
+impl MessageType {
+    pub fn raw(&self) -> u8 {
+        *self as u8
+    }
+}
+
+impl TryFrom<u8> for MessageType {
+    type Error = ProtocolError;
+
+    fn try_from(code: u8) -> Result<MessageType, ProtocolError> {
+        match code {
+            0x01 => Ok(MessageType::Ping),
+            0x02 => Ok(MessageType::GetVersion),
+            0x03 => Ok(MessageType::SetLedBrightness),
+            0x04 => Ok(MessageType::GetLedBrightness),
+            0x05 => Ok(MessageType::GetModeInfo),
+            0x06 => Ok(MessageType::GetCapabilities),
+            0x07 => Ok(MessageType::Subscribe),
+            0x08 => Ok(MessageType::Unsubscribe),
+            0x09 => Ok(MessageType::GetKeyBinding),
+            0x0A => Ok(MessageType::SetKeyBinding),
+            0x0B => Ok(MessageType::GetModeName),
+            0x0C => Ok(MessageType::CreateUserMode),
+            0x0D => Ok(MessageType::DeleteUserMode),
+            0x0E => Ok(MessageType::MoveUserMode),
+            0x0F => Ok(MessageType::RenameUserMode),
+            0x10 => Ok(MessageType::SaveConfig),
+            0x11 => Ok(MessageType::LoadConfig),
+            0x12 => Ok(MessageType::FactoryReset),
+            0x13 => Ok(MessageType::SetMode),
+            0x14 => Ok(MessageType::GetMode),
+            0x15 => Ok(MessageType::GetSlotColor),
+            0x16 => Ok(MessageType::SetSlotColor),
+            0x17 => Ok(MessageType::SetDebounceTime),
+            0x18 => Ok(MessageType::GetDebounceTime),
+            0x19 => Ok(MessageType::GetChord),
+            0x1A => Ok(MessageType::SetChord),
+            code => Err(ProtocolError::UnknownMessage(code)),
+        }
+    }
+}
+
+/// A set of message types, one bit per message type byte.
+#[derive(Copy, Clone, Eq, PartialEq, Debug)]
+pub struct MessageSet(pub u64);
+
+impl MessageSet {
+    pub const fn empty() -> MessageSet {
+        MessageSet(0)
+    }
+
+    pub const fn with(self, message_type: MessageType) -> MessageSet {
+        MessageSet(self.0 | 1 << message_type as u8)
+    }
+
+    pub fn contains(&self, message_type: MessageType) -> bool {
+        self.0 & 1 << message_type.raw() != 0
+    }
+}
+
+/// Messages understood by firmware released before the capabilities
+/// handshake, which can't report them itself.
+pub const LEGACY_MESSAGES: MessageSet = MessageSet::empty()
+    .with(MessageType::Ping)
+    .with(MessageType::GetVersion)
+    .with(MessageType::SetLedBrightness)
+    .with(MessageType::GetLedBrightness)
+    .with(MessageType::GetModeInfo);
+
+/// What a micropad supports, as reported in response to
+/// `Message::GetCapabilities`.
+#[derive(Copy, Clone, Eq, PartialEq, Debug)]
+pub struct Capabilities {
+    pub protocol_version: u8,
+    pub frame_size: u8,
+    pub max_payload_size: u8,
+    pub supported_messages: MessageSet,
+    pub button_count: u8,
+    pub encoder_count: u8,
+    pub led_count: u8,
+    /// Most user modes the micropad can hold.
+    pub max_user_modes: u8,
+}
+
+impl Capabilities {
+    /// Assumed capabilities of firmware that predates the handshake.
+    pub fn legacy() -> Capabilities {
+        Capabilities {
+            protocol_version: 0,
+            frame_size: FRAME_SIZE as u8,
+            max_payload_size: FRAME_PAYLOAD_SIZE as u8,
+            supported_messages: LEGACY_MESSAGES,
+            button_count: 4,
+            encoder_count: 1,
+            led_count: 1,
+            max_user_modes: 0,
         }
     }
+
+    pub fn supports(&self, message: &Message) -> bool {
+        self.supported_messages.contains(message.message_type())
+    }
 }
 
 #[repr(u8)]
@@ -27,7 +465,11 @@ impl Message {
 pub enum ResponseCode {
     Ok = 0x00,
     UnknownMessage = 0x01,
-    Unknown = 0xFF,
+    InvalidArgument = 0x02,
+    Busy = 0x03,
+    NotFound = 0x04,
+    StorageError = 0x05,
+    ChecksumError = 0x06,
 }
 
 impl ResponseCode {
@@ -36,16 +478,38 @@ impl ResponseCode {
     }
 }
 
-impl From<u8> for ResponseCode {
-    fn from(code: u8) -> ResponseCode {
-        match code {
-            0x00 => ResponseCode::Ok,
-            0x01 => ResponseCode::UnknownMessage,
-            _ => ResponseCode::Unknown,
+impl TryFrom<u8> for ResponseCode {
+    type Error = ProtocolError;
+
+    fn try_from(code: u8) -> Result<ResponseCode, ProtocolError> {
+        match code & !CONTINUATION_FLAG {
+            0x00 => Ok(ResponseCode::Ok),
+            0x01 => Ok(ResponseCode::UnknownMessage),
+            0x02 => Ok(ResponseCode::InvalidArgument),
+            0x03 => Ok(ResponseCode::Busy),
+            0x04 => Ok(ResponseCode::NotFound),
+            0x05 => Ok(ResponseCode::StorageError),
+            0x06 => Ok(ResponseCode::ChecksumError),
+            code => Err(ProtocolError::UnknownResponseCode(code)),
         }
     }
 }
 
+impl fmt::Display for ResponseCode {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let description = match self {
+            ResponseCode::Ok => "success",
+            ResponseCode::UnknownMessage => "unsupported message",
+            ResponseCode::InvalidArgument => "invalid argument",
+            ResponseCode::Busy => "device busy",
+            ResponseCode::NotFound => "not found",
+            ResponseCode::StorageError => "storage error",
+            ResponseCode::ChecksumError => "corrupted request",
+        };
+        f.write_str(description)
+    }
+}
+
 #[derive(Debug)]
 pub enum ResponsePayload {
     None,
@@ -60,76 +524,196 @@ pub enum ResponsePayload {
         minor: u8,
         patch: u8,
     },
+    Capabilities(Capabilities),
+    KeyBinding(Key),
+    ModeName(ModeName),
+    ModeIndex(u8),
+    SlotColor(Color),
+    DebounceTime(u8),
+    Chord(Option<Chord>),
 }
 
 impl ResponsePayload {
-    pub fn fill(&self, frame: &mut MessageFrame) {
+    pub fn fill(&self, payload: &mut Payload) {
+        payload.clear();
         match self {
-            ResponsePayload::None => {
-                for i in 1..frame.frame_size() {
-                    frame.buf[i] = 0x00;
-                }
-            }
+            ResponsePayload::None => {}
             ResponsePayload::LedBrightness(brightness) => {
-                frame.buf[1] = *brightness;
-                for i in 2..frame.frame_size() {
-                    frame.buf[i] = 0x00;
-                }
+                payload.push(*brightness);
             }
             ResponsePayload::ModeInfo {
                 built_in_mode_count,
                 user_mode_count,
                 current_mode_index,
             } => {
-                frame.buf[1] = *built_in_mode_count;
-                frame.buf[2] = *user_mode_count;
-                frame.buf[3] = *current_mode_index;
-                for i in 4..frame.frame_size() {
-                    frame.buf[i] = 0x00;
-                }
+                payload.push(*built_in_mode_count);
+                payload.push(*user_mode_count);
+                payload.push(*current_mode_index);
             }
             ResponsePayload::Version {
                 major,
                 minor,
                 patch,
             } => {
-                frame.buf[1] = *major;
-                frame.buf[2] = *minor;
-                frame.buf[3] = *patch;
-                for i in 4..frame.frame_size() {
-                    frame.buf[i] = 0x00;
-                }
+                payload.push(*major);
+                payload.push(*minor);
+                payload.push(*patch);
+            }
+            ResponsePayload::Capabilities(capabilities) => {
+                payload.push(capabilities.protocol_version);
+                payload.push(capabilities.frame_size);
+                payload.push(capabilities.max_payload_size);
+                payload.extend_from_slice(&capabilities.supported_messages.0.to_le_bytes());
+                payload.push(capabilities.button_count);
+                payload.push(capabilities.encoder_count);
+                payload.push(capabilities.led_count);
+                payload.push(capabilities.max_user_modes);
+            }
+            ResponsePayload::KeyBinding(key) => {
+                payload.extend_from_slice(&key.encode());
+            }
+            ResponsePayload::ModeName(name) => {
+                name.encode(payload);
+            }
+            ResponsePayload::ModeIndex(mode) => {
+                payload.push(*mode);
+            }
+            ResponsePayload::SlotColor(color) => {
+                payload.extend_from_slice(&color.encode());
+            }
+            ResponsePayload::DebounceTime(ms) => {
+                payload.push(*ms);
+            }
+            ResponsePayload::Chord(chord) => {
+                payload.extend_from_slice(&chords::encode(chord));
             }
         }
     }
 
-    fn from_message(message: &Message, response_frame: &MessageFrame) -> ResponsePayload {
-        match message {
-            Message::Ping | Message::SetLedBrightness(_) | Message::Unknown => {
-                ResponsePayload::None
+    fn from_message(message: &Message, payload: &[u8]) -> Result<ResponsePayload, ProtocolError> {
+        let byte = |i: usize| payload.get(i).copied().ok_or(ProtocolError::Truncated);
+        let response = match message {
+            Message::Ping
+            | Message::SetLedBrightness(_)
+            | Message::Subscribe(_)
+            | Message::Unsubscribe(_)
+            | Message::SetKeyBinding { .. }
+            | Message::DeleteUserMode(_)
+            | Message::MoveUserMode { .. }
+            | Message::RenameUserMode { .. }
+            | Message::SaveConfig
+            | Message::LoadConfig
+            | Message::FactoryReset
+            | Message::SetMode(_)
+            | Message::SetSlotColor { .. }
+            | Message::SetDebounceTime(_)
+            | Message::SetChord { .. } => ResponsePayload::None,
+            Message::GetKeyBinding { .. } => ResponsePayload::KeyBinding(Key::decode(payload)?),
+            Message::GetModeName(_) => ResponsePayload::ModeName(ModeName::decode(payload)?),
+            Message::GetSlotColor { .. } => ResponsePayload::SlotColor(Color::decode(payload)?),
+            Message::GetChord { .. } => ResponsePayload::Chord(chords::decode(payload)?),
+            Message::CreateUserMode { .. } | Message::GetMode => {
+                ResponsePayload::ModeIndex(byte(0)?)
             }
-            Message::GetLedBrightness => ResponsePayload::LedBrightness(response_frame.buf[1]),
+            Message::GetLedBrightness => ResponsePayload::LedBrightness(byte(0)?),
+            Message::GetDebounceTime => ResponsePayload::DebounceTime(byte(0)?),
             Message::GetModeInfo => ResponsePayload::ModeInfo {
-                built_in_mode_count: response_frame.buf[1],
-                user_mode_count: response_frame.buf[2],
-                current_mode_index: response_frame.buf[3],
+                built_in_mode_count: byte(0)?,
+                user_mode_count: byte(1)?,
+                current_mode_index: byte(2)?,
             },
             Message::GetVersion => ResponsePayload::Version {
-                major: response_frame.buf[1],
-                minor: response_frame.buf[2],
-                patch: response_frame.buf[3],
+                major: byte(0)?,
+                minor: byte(1)?,
+                patch: byte(2)?,
             },
+            Message::GetCapabilities => {
+                let mut supported_messages = [0u8; 8];
+                for (i, b) in supported_messages.iter_mut().enumerate() {
+                    *b = byte(3 + i)?;
+                }
+                ResponsePayload::Capabilities(Capabilities {
+                    protocol_version: byte(0)?,
+                    frame_size: byte(1)?,
+                    max_payload_size: byte(2)?,
+                    supported_messages: MessageSet(u64::from_le_bytes(supported_messages)),
+                    button_count: byte(11)?,
+                    encoder_count: byte(12)?,
+                    led_count: byte(13)?,
+                    max_user_modes: byte(14)?,
+                })
+            }
+        };
+        Ok(response)
+    }
+}
+
+/// A fixed capacity byte buffer holding the payload of a message or
+/// response, before it is split into frames or after it has been
+/// reassembled from them.
+#[derive(Clone)]
+pub struct Payload {
+    buf: [u8; MAX_PAYLOAD_SIZE],
+    len: usize,
+}
+
+impl Payload {
+    pub const fn new() -> Payload {
+        Payload {
+            buf: [0u8; MAX_PAYLOAD_SIZE],
+            len: 0,
+        }
+    }
+
+    /// Appends a byte, returning false if the payload is already full.
+    pub fn push(&mut self, byte: u8) -> bool {
+        if self.len == self.buf.len() {
+            return false;
         }
+        self.buf[self.len] = byte;
+        self.len += 1;
+        true
+    }
+
+    /// Appends all the given bytes, returning false if they did not fit.
+    pub fn extend_from_slice(&mut self, bytes: &[u8]) -> bool {
+        bytes.iter().all(|b| self.push(*b))
+    }
+
+    pub fn clear(&mut self) {
+        self.len = 0;
+    }
+
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    pub fn as_slice(&self) -> &[u8] {
+        &self.buf[..self.len]
+    }
+}
+
+impl Default for Payload {
+    fn default() -> Self {
+        Self::new()
     }
 }
 
 pub struct MessageFrame {
+    /// Chosen by the host for each request, and echoed back by the micropad
+    /// on every frame of the response.
+    pub request_id: u8,
     pub buf: [u8; 8],
 }
 
 impl MessageFrame {
     pub fn new() -> MessageFrame {
         MessageFrame {
+            request_id: UNSOLICITED_REQUEST_ID,
             buf: Default::default(),
         }
     }
@@ -138,52 +722,298 @@ impl MessageFrame {
         self.buf.len()
     }
 
-    pub fn into_code_and_payload(self, message: &Message) -> (ResponseCode, ResponsePayload) {
-        let code = ResponseCode::from(self.buf[0]);
-        let payload = ResponsePayload::from_message(message, &self);
-        (code, payload)
+    /// The header byte, with the continuation flag masked off.
+    pub fn header(&self) -> u8 {
+        self.buf[0] & !CONTINUATION_FLAG
+    }
+
+    /// True when more frames follow this one.
+    pub fn is_continued(&self) -> bool {
+        self.buf[0] & CONTINUATION_FLAG != 0
+    }
+
+    pub fn payload(&self) -> &[u8] {
+        &self.buf[1..]
     }
 }
 
-impl From<&MessageFrame> for Message {
-    fn from(frame: &MessageFrame) -> Message {
-        match frame.buf[0] {
-            0x01 => Message::Ping,
-            0x02 => Message::GetVersion,
-            0x03 => Message::SetLedBrightness(frame.buf[1]),
-            0x04 => Message::GetLedBrightness,
-            0x05 => Message::GetModeInfo,
-            _ => Message::Unknown,
+impl Default for MessageFrame {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Splits a header byte and payload into a sequence of frames. Every frame
+/// but the last has the continuation flag set on its header. An empty
+/// payload still produces a single frame.
+pub struct FrameEncoder {
+    request_id: u8,
+    header: u8,
+    payload: Payload,
+    offset: usize,
+    finished: bool,
+}
+
+impl FrameEncoder {
+    pub fn new(request_id: u8, header: u8, payload: Payload) -> FrameEncoder {
+        FrameEncoder {
+            request_id,
+            header: header & !CONTINUATION_FLAG,
+            payload,
+            offset: 0,
+            finished: false,
         }
     }
+
+    pub fn request(request_id: u8, message: &Message) -> FrameEncoder {
+        let mut buf = Payload::new();
+        message.fill(&mut buf);
+        FrameEncoder::new(request_id, message.code(), buf)
+    }
+
+    pub fn response(request_id: u8, code: ResponseCode, payload: &ResponsePayload) -> FrameEncoder {
+        let mut buf = Payload::new();
+        payload.fill(&mut buf);
+        FrameEncoder::new(request_id, code.raw(), buf)
+    }
 }
 
-impl From<&Message> for MessageFrame {
-    fn from(message: &Message) -> Self {
-        let mut message_frame = MessageFrame::new();
-        match message {
-            Message::Ping
-            | Message::GetLedBrightness
-            | Message::GetModeInfo
-            | Message::GetVersion => {
-                message_frame.buf[0] = message.code();
-                for i in 1..message_frame.frame_size() {
-                    message_frame.buf[i] = 0x00;
-                }
-            }
-            Message::SetLedBrightness(brightness) => {
-                message_frame.buf[0] = message.code();
-                message_frame.buf[1] = *brightness;
-                for i in 2..message_frame.frame_size() {
-                    message_frame.buf[i] = 0x00;
-                }
-            }
-            Message::Unknown => {
-                for i in 0..message_frame.frame_size() {
-                    message_frame.buf[i] = 0x00;
-                }
+impl Iterator for FrameEncoder {
+    type Item = MessageFrame;
+
+    fn next(&mut self) -> Option<MessageFrame> {
+        if self.finished {
+            return None;
+        }
+
+        let remaining = &self.payload.as_slice()[self.offset..];
+        let chunk_len = remaining.len().min(FRAME_PAYLOAD_SIZE);
+        let mut frame = MessageFrame::new();
+        frame.request_id = self.request_id;
+        frame.buf[1..1 + chunk_len].copy_from_slice(&remaining[..chunk_len]);
+        self.offset += chunk_len;
+        self.finished = self.offset == self.payload.len();
+        frame.buf[0] = if self.finished {
+            self.header
+        } else {
+            self.header | CONTINUATION_FLAG
+        };
+        Some(frame)
+    }
+}
+
+/// Reassembles a sequence of frames produced by a `FrameEncoder`. Frame
+/// payloads are concatenated, so the result includes any zero padding from
+/// the final frame. All frames of a sequence must share a request ID.
+pub struct FrameDecoder {
+    request_id: u8,
+    header: u8,
+    payload: Payload,
+    complete: bool,
+}
+
+impl FrameDecoder {
+    pub const fn new() -> FrameDecoder {
+        FrameDecoder {
+            request_id: UNSOLICITED_REQUEST_ID,
+            header: 0,
+            payload: Payload::new(),
+            complete: false,
+        }
+    }
+
+    /// Adds the next frame, returning true once the final frame of the
+    /// sequence has been seen.
+    pub fn push(&mut self, frame: &MessageFrame) -> Result<bool, ProtocolError> {
+        if self.complete {
+            self.reset();
+        }
+        if !self.payload.is_empty() && frame.request_id != self.request_id {
+            self.reset();
+            return Err(ProtocolError::Framing);
+        }
+        self.request_id = frame.request_id;
+        self.header = frame.header();
+        if !self.payload.extend_from_slice(frame.payload()) {
+            self.reset();
+            return Err(ProtocolError::PayloadOverflow);
+        }
+        self.complete = !frame.is_continued();
+        Ok(self.complete)
+    }
+
+    pub fn reset(&mut self) {
+        self.request_id = UNSOLICITED_REQUEST_ID;
+        self.header = 0;
+        self.payload.clear();
+        self.complete = false;
+    }
+
+    pub fn is_complete(&self) -> bool {
+        self.complete
+    }
+
+    /// True after the first frames of a sequence, while its final frame
+    /// has yet to arrive.
+    pub fn is_in_progress(&self) -> bool {
+        !self.complete && !self.payload.is_empty()
+    }
+
+    pub fn request_id(&self) -> u8 {
+        self.request_id
+    }
+
+    pub fn header(&self) -> u8 {
+        self.header
+    }
+
+    pub fn payload(&self) -> &[u8] {
+        self.payload.as_slice()
+    }
+
+    /// Decodes a reassembled request.
+    pub fn message(&self) -> Result<Message, ProtocolError> {
+        Message::decode(self.header, self.payload())
+    }
+
+    /// Decodes the reassembled response to `message`. Error responses carry
+    /// no arguments, so their payload is always `ResponsePayload::None`.
+    pub fn into_code_and_payload(
+        self,
+        message: &Message,
+    ) -> Result<(ResponseCode, ResponsePayload), ProtocolError> {
+        let code = ResponseCode::try_from(self.header)?;
+        let payload = match code {
+            ResponseCode::Ok => ResponsePayload::from_message(message, self.payload())?,
+            _ => ResponsePayload::None,
+        };
+        Ok((code, payload))
+    }
+}
+
+impl Default for FrameDecoder {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    extern crate std;
+
+    use super::*;
+    use std::vec::Vec;
+
+    fn payload(len: usize) -> Payload {
+        let mut payload = Payload::new();
+        for i in 0..len {
+            // Zeros in the mix, so COBS has something to stuff
+            assert!(payload.push((i as u8).wrapping_mul(37)));
+        }
+        payload
+    }
+
+    /// Sends frames over the wire as packets and decodes them back.
+    fn over_the_wire(frames: impl Iterator<Item = MessageFrame>) -> Vec<MessageFrame> {
+        let mut decoder = PacketDecoder::new();
+        frames
+            .flat_map(|frame| {
+                let packet = Packet::encode(&frame);
+                packet.as_bytes().to_vec()
+            })
+            .filter_map(|byte| decoder.push(byte))
+            .map(|frame| frame.unwrap())
+            .collect()
+    }
+
+    #[test]
+    fn payloads_round_trip_through_frames_and_packets() {
+        for len in [0, 1, 55, 56] {
+            let sent = payload(len);
+            let frames = over_the_wire(FrameEncoder::new(9, 0x2A, sent.clone()));
+            let frame_count = len.div_ceil(FRAME_PAYLOAD_SIZE);
+            assert_eq!(frames.len(), frame_count.max(1), "{} bytes", len);
+
+            let mut decoder = FrameDecoder::new();
+            for (i, frame) in frames.iter().enumerate() {
+                assert_eq!(frame.request_id, 9);
+                let last = i == frames.len() - 1;
+                assert_eq!(frame.is_continued(), !last);
+                assert_eq!(decoder.push(frame), Ok(last));
             }
+            assert!(decoder.is_complete());
+            assert_eq!(decoder.header(), 0x2A);
+            assert_eq!(decoder.request_id(), 9);
+            // The final frame is zero padded
+            assert_eq!(&decoder.payload()[..len], sent.as_slice(), "{} bytes", len);
+            assert!(decoder.payload()[len..].iter().all(|byte| *byte == 0));
+        }
+    }
+
+    #[test]
+    fn payload_is_limited_to_max_payload_size() {
+        let mut full = payload(MAX_PAYLOAD_SIZE);
+        assert!(!full.push(0));
+        assert_eq!(full.len(), MAX_PAYLOAD_SIZE);
+    }
+
+    #[test]
+    fn frame_decoder_rejects_too_many_continuations() {
+        let mut frame = MessageFrame::new();
+        frame.request_id = 1;
+        frame.buf[0] = 0x05 | CONTINUATION_FLAG;
+        let mut decoder = FrameDecoder::new();
+        for _ in 0..MAX_PAYLOAD_SIZE / FRAME_PAYLOAD_SIZE {
+            assert_eq!(decoder.push(&frame), Ok(false));
         }
-        message_frame
+        assert_eq!(decoder.push(&frame), Err(ProtocolError::PayloadOverflow));
+        assert!(!decoder.is_in_progress());
+    }
+
+    #[test]
+    fn frame_decoder_rejects_request_id_change_mid_sequence() {
+        let first: Vec<_> = FrameEncoder::new(5, 0x2A, payload(20)).collect();
+        let second: Vec<_> = FrameEncoder::new(6, 0x2B, payload(3)).collect();
+
+        let mut decoder = FrameDecoder::new();
+        assert_eq!(decoder.push(&first[0]), Ok(false));
+        assert_eq!(decoder.push(&second[0]), Err(ProtocolError::Framing));
+        assert!(!decoder.is_in_progress());
+
+        // The next sequence decodes from scratch
+        assert_eq!(decoder.push(&second[0]), Ok(true));
+        assert_eq!(decoder.request_id(), 6);
+        assert_eq!(&decoder.payload()[..3], payload(3).as_slice());
+    }
+
+    #[test]
+    fn frame_decoder_waits_for_missing_continuations() {
+        let frames: Vec<_> = FrameEncoder::new(5, 0x2A, payload(20)).collect();
+        assert_eq!(frames.len(), 3);
+
+        let mut decoder = FrameDecoder::new();
+        assert_eq!(decoder.push(&frames[0]), Ok(false));
+        assert_eq!(decoder.push(&frames[1]), Ok(false));
+        assert!(decoder.is_in_progress());
+        assert!(!decoder.is_complete());
+
+        // The final frame never came, and the host moved on to its next
+        // request
+        let next = FrameEncoder::new(next_request_id(5), 0x01, Payload::new())
+            .next()
+            .unwrap();
+        assert_eq!(decoder.push(&next), Err(ProtocolError::Framing));
+        assert_eq!(decoder.push(&next), Ok(true));
+        assert_eq!(decoder.request_id(), 6);
+    }
+
+    #[test]
+    fn request_ids_skip_the_unsolicited_id() {
+        assert_eq!(next_request_id(1), 2);
+        assert_eq!(next_request_id(255), 1);
+        assert_ne!(
+            next_request_id(UNSOLICITED_REQUEST_ID),
+            UNSOLICITED_REQUEST_ID
+        );
     }
 }
@@ -0,0 +1,60 @@
+//! Mode names, and their on-wire encoding.
+//!
+//! A name is sent as a length byte followed by that many bytes of UTF-8.
+
+use crate::{Payload, ProtocolError};
+
+/// Longest mode name, in bytes of UTF-8.
+pub const MAX_MODE_NAME_SIZE: usize = 16;
+
+/// A short, fixed capacity mode name.
+#[derive(Copy, Clone, Eq, PartialEq)]
+pub struct ModeName {
+    buf: [u8; MAX_MODE_NAME_SIZE],
+    len: u8,
+}
+
+impl ModeName {
+    pub const fn empty() -> ModeName {
+        ModeName {
+            buf: [0u8; MAX_MODE_NAME_SIZE],
+            len: 0,
+        }
+    }
+
+    /// Fails with `InvalidArgument` for names longer than
+    /// `MAX_MODE_NAME_SIZE` bytes.
+    pub fn new(name: &str) -> Result<ModeName, ProtocolError> {
+        let bytes = name.as_bytes();
+        if bytes.len() > MAX_MODE_NAME_SIZE {
+            return Err(ProtocolError::InvalidArgument);
+        }
+        let mut mode_name = ModeName::empty();
+        mode_name.buf[..bytes.len()].copy_from_slice(bytes);
+        mode_name.len = bytes.len() as u8;
+        Ok(mode_name)
+    }
+
+    pub fn as_str(&self) -> &str {
+        // Only valid UTF-8 is ever stored
+        core::str::from_utf8(&self.buf[..self.len as usize]).unwrap_or_default()
+    }
+
+    pub fn encode(&self, payload: &mut Payload) {
+        payload.push(self.len);
+        payload.extend_from_slice(&self.buf[..self.len as usize]);
+    }
+
+    pub fn decode(bytes: &[u8]) -> Result<ModeName, ProtocolError> {
+        let len = *bytes.first().ok_or(ProtocolError::Truncated)? as usize;
+        let name = bytes.get(1..1 + len).ok_or(ProtocolError::Truncated)?;
+        let name = core::str::from_utf8(name).map_err(|_| ProtocolError::InvalidArgument)?;
+        ModeName::new(name)
+    }
+}
+
+impl core::fmt::Debug for ModeName {
+    fn fmt(&self, f: &mut core::fmt::Formatter) -> core::fmt::Result {
+        core::fmt::Debug::fmt(self.as_str(), f)
+    }
+}
@@ -0,0 +1,88 @@
+use std::convert::TryFrom;
+use std::time::Duration;
+
+use micropad_protocol::{Message, MessageType, ResponseCode};
+
+/// Misbehavior to inject, so hosts can be tested against a micropad that
+/// loses bytes, answers slowly or refuses requests.
+#[derive(Default)]
+pub struct Faults {
+    /// Bytes from the host that never arrive.
+    pub drop_incoming: Dropper,
+    /// Bytes to the host that never arrive.
+    pub drop_outgoing: Dropper,
+    /// How long to wait before answering each request.
+    pub delay: Duration,
+    /// Requests to answer with an error instead of handling them. A missing
+    /// message type matches every request.
+    pub failures: Vec<(Option<MessageType>, ResponseCode)>,
+}
+
+impl Faults {
+    /// The error to answer a message with, if it should fail.
+    pub fn failure(&self, message: &Message) -> Option<ResponseCode> {
+        let message_type = message.message_type();
+        self.failures
+            .iter()
+            .find(|(failing, _)| failing.is_none() || *failing == Some(message_type))
+            .map(|(_, code)| *code)
+    }
+}
+
+/// Drops every nth byte passing through.
+#[derive(Default)]
+pub struct Dropper {
+    every: Option<u64>,
+    count: u64,
+}
+
+impl Dropper {
+    pub fn every(n: u64) -> Self {
+        Self {
+            every: Some(n),
+            count: 0,
+        }
+    }
+
+    /// Whether the next byte should be dropped. Doesn't count the byte, so
+    /// bytes that have to be retried aren't counted twice.
+    pub fn drops_next(&self) -> bool {
+        matches!(self.every, Some(n) if (self.count + 1).is_multiple_of(n))
+    }
+
+    pub fn count(&mut self) {
+        self.count += 1;
+    }
+}
+
+/// Parses a `--fail` argument: a message type name, or `all`, and the
+/// response code to answer it with, as in `SaveConfig=storage_error`.
+pub fn parse_failure(value: &str) -> Result<(Option<MessageType>, ResponseCode), String> {
+    let mut parts = value.splitn(2, '=');
+    let (message, code) = match (parts.next(), parts.next()) {
+        (Some(message), Some(code)) => (message, code),
+        _ => return Err(format!("Expected MESSAGE=CODE, got: {}", value)),
+    };
+
+    let message_type = if message.eq_ignore_ascii_case("all") {
+        None
+    } else {
+        let message_type = (0..=u8::MAX)
+            .filter_map(|raw| MessageType::try_from(raw).ok())
+            .find(|message_type| format!("{:?}", message_type).eq_ignore_ascii_case(message))
+            .ok_or_else(|| format!("Unknown message type: {}", message))?;
+        Some(message_type)
+    };
+
+    let code = match code.parse::<u8>() {
+        Ok(raw) => ResponseCode::try_from(raw).ok(),
+        Err(_) => (0..=u8::MAX)
+            .filter_map(|raw| ResponseCode::try_from(raw).ok())
+            .find(|response_code| {
+                format!("{:?}", response_code).eq_ignore_ascii_case(&code.replace('_', ""))
+            }),
+    }
+    .ok_or_else(|| format!("Unknown response code: {}", code))?;
+
+    Ok((message_type, code))
+}
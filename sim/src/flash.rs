@@ -0,0 +1,48 @@
+use micropad_config::{Flash, FlashError};
+
+const PAGE_SIZE: usize = 1024;
+const PAGE_COUNT: usize = 2;
+
+/// The settings pages of a micropad's flash, kept in memory for as long as
+/// the simulator runs. Like real flash, programming can only clear bits, so
+/// writes that skip an erase corrupt the data instead of replacing it.
+pub struct MemoryFlash {
+    data: Vec<u8>,
+}
+
+impl MemoryFlash {
+    pub fn new() -> Self {
+        Self {
+            data: vec![0xFF; PAGE_SIZE * PAGE_COUNT],
+        }
+    }
+}
+
+impl Flash for MemoryFlash {
+    fn page_size(&self) -> usize {
+        PAGE_SIZE
+    }
+
+    fn page_count(&self) -> usize {
+        PAGE_COUNT
+    }
+
+    fn read(&self, offset: usize, buf: &mut [u8]) {
+        buf.copy_from_slice(&self.data[offset..offset + buf.len()]);
+    }
+
+    fn write(&mut self, offset: usize, data: &[u8]) -> Result<(), FlashError> {
+        for (byte, value) in self.data[offset..offset + data.len()].iter_mut().zip(data) {
+            *byte &= value;
+        }
+        Ok(())
+    }
+
+    fn erase_page(&mut self, page: usize) -> Result<(), FlashError> {
+        let start = page * PAGE_SIZE;
+        for byte in &mut self.data[start..start + PAGE_SIZE] {
+            *byte = 0xFF;
+        }
+        Ok(())
+    }
+}
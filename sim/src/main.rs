@@ -0,0 +1,191 @@
+//! A virtual micropad on a pseudo-terminal, for testing hosts without the
+//! hardware. Requests are answered by the same code as the firmware, and
+//! settings are kept in memory until the simulator exits.
+//!
+//! The pty path is printed on startup, and can be handed to the cli with
+//! `micropad_ctrl --device <path>`. Faults can be injected to see how hosts
+//! cope with a misbehaving micropad.
+
+mod faults;
+mod flash;
+mod pty;
+
+use clap::{App, Arg};
+use log::LevelFilter;
+use micropad_config::Eeprom;
+use micropad_core::{handle_message, ControlState, Inbox, Outbox};
+use micropad_protocol::{Event, EventSet, ResponsePayload};
+use simple_logger::SimpleLogger;
+
+use std::fs;
+use std::os::unix::fs::symlink;
+use std::process;
+use std::thread;
+use std::time::Duration;
+
+use faults::{parse_failure, Dropper, Faults};
+use flash::MemoryFlash;
+use pty::Pty;
+
+/// How often to check on the host while there is nothing to read, about as
+/// often as the firmware's main loop runs.
+const POLL_INTERVAL: Duration = Duration::from_millis(10);
+
+fn parse_count(value: Option<&str>, name: &str) -> Option<u64> {
+    let value = value?;
+    match value.parse::<u64>() {
+        Ok(n) if n > 0 => Some(n),
+        _ => {
+            log::error!("{} must be a positive number, got: {}", name, value);
+            process::exit(1);
+        }
+    }
+}
+
+fn main() {
+    let matches = App::new("Micropad simulator")
+        .version("0.1")
+        .author("Blake Smith <blakesmith0@gmail.com>")
+        .about("Answer the micropad serial protocol on a pseudo-terminal")
+        .arg(
+            Arg::with_name("debug")
+                .help("Enable debug logging")
+                .short("d"),
+        )
+        .arg(
+            Arg::with_name("link")
+                .long("link")
+                .takes_value(true)
+                .value_name("path")
+                .help("Also make the pty reachable at this path, replacing any symlink there"),
+        )
+        .arg(
+            Arg::with_name("drop_incoming")
+                .long("drop-incoming")
+                .takes_value(true)
+                .value_name("n")
+                .help("Drop every nth byte from the host"),
+        )
+        .arg(
+            Arg::with_name("drop_outgoing")
+                .long("drop-outgoing")
+                .takes_value(true)
+                .value_name("n")
+                .help("Drop every nth byte to the host"),
+        )
+        .arg(
+            Arg::with_name("delay")
+                .long("delay")
+                .takes_value(true)
+                .value_name("ms")
+                .help("Wait before answering each request"),
+        )
+        .arg(
+            Arg::with_name("fail")
+                .long("fail")
+                .takes_value(true)
+                .multiple(true)
+                .number_of_values(1)
+                .value_name("message=code")
+                .help(
+                    "Answer a message type with an error code instead of handling it, \
+                     as in SaveConfig=storage_error. Use all to fail every message",
+                ),
+        )
+        .get_matches();
+
+    let level = if matches.is_present("debug") {
+        LevelFilter::Debug
+    } else {
+        LevelFilter::Info
+    };
+    SimpleLogger::new().with_level(level).init().unwrap();
+
+    let mut faults = Faults::default();
+    if let Some(n) = parse_count(matches.value_of("drop_incoming"), "--drop-incoming") {
+        faults.drop_incoming = Dropper::every(n);
+    }
+    if let Some(n) = parse_count(matches.value_of("drop_outgoing"), "--drop-outgoing") {
+        faults.drop_outgoing = Dropper::every(n);
+    }
+    if let Some(delay) = matches.value_of("delay") {
+        faults.delay = match delay.parse() {
+            Ok(ms) => Duration::from_millis(ms),
+            Err(_) => {
+                log::error!("--delay must be a number of milliseconds, got: {}", delay);
+                process::exit(1);
+            }
+        };
+    }
+    for value in matches.values_of("fail").into_iter().flatten() {
+        match parse_failure(value) {
+            Ok(failure) => faults.failures.push(failure),
+            Err(message) => {
+                log::error!("{}", message);
+                process::exit(1);
+            }
+        }
+    }
+
+    let drop_incoming = std::mem::take(&mut faults.drop_incoming);
+    let drop_outgoing = std::mem::take(&mut faults.drop_outgoing);
+    let mut pty = match Pty::open(drop_incoming, drop_outgoing) {
+        Ok(pty) => pty,
+        Err(err) => {
+            log::error!("Failed to open a pseudo-terminal: {}", err);
+            process::exit(3);
+        }
+    };
+    if let Some(link) = matches.value_of("link") {
+        if matches!(fs::symlink_metadata(link), Ok(meta) if meta.file_type().is_symlink()) {
+            let _ = fs::remove_file(link);
+        }
+        if let Err(err) = symlink(pty.path(), link) {
+            log::error!("Failed to link {} to {}: {}", link, pty.path(), err);
+            process::exit(3);
+        }
+    }
+    println!("{}", pty.path());
+    log::info!("Simulating a micropad on {}", pty.path());
+
+    run(&mut pty, &faults);
+}
+
+/// Answer requests until killed, the way the firmware's USB interrupt does.
+fn run(pty: &mut Pty, faults: &Faults) -> ! {
+    let mut control_state = ControlState::new();
+    let mut config_store = Eeprom::new(MemoryFlash::new());
+    let mut inbox = Inbox::new();
+    let mut outbox = Outbox::new();
+
+    loop {
+        if let Err(err) = pty.wait(POLL_INTERVAL) {
+            log::error!("Failed to wait on the pty: {}", err);
+            process::exit(3);
+        }
+
+        // The host closed the port, so nobody is listening for events
+        if !pty.is_open() {
+            outbox.unsubscribe(EventSet::ALL);
+        }
+
+        let previous_mode_index = control_state.get_mode_index();
+        micropad_core::poll(pty, &mut inbox, &mut outbox, |outbox, message| {
+            log::debug!("Got message: {:?}", message.message_type());
+            thread::sleep(faults.delay);
+            match faults.failure(&message) {
+                Some(code) => (code, ResponsePayload::None),
+                None => {
+                    handle_message(&mut control_state, Some(&mut config_store), outbox, message)
+                }
+            }
+        });
+
+        // Nothing else changes the mode here, so a request must have
+        let mode_index = control_state.get_mode_index();
+        if mode_index != previous_mode_index {
+            outbox.push_event(Event::ModeChanged(mode_index));
+        }
+        let _ = outbox.flush(pty);
+    }
+}
@@ -0,0 +1,123 @@
+use std::io::{self, Read, Write};
+use std::os::unix::io::AsRawFd;
+use std::thread;
+use std::time::Duration;
+
+use nix::errno::Errno;
+use nix::fcntl::OFlag;
+use nix::poll::{poll, PollFd, PollFlags};
+use nix::pty::{grantpt, posix_openpt, ptsname_r, unlockpt, PtyMaster};
+use nix::sys::termios::{cfmakeraw, tcgetattr, tcsetattr, SetArg};
+
+use crate::faults::Dropper;
+
+/// The simulator's end of a pseudo-terminal. Hosts open the other end,
+/// at `path`, as if it were a micropad's serial port.
+pub struct Pty {
+    master: PtyMaster,
+    path: String,
+    open: bool,
+    drop_incoming: Dropper,
+    drop_outgoing: Dropper,
+}
+
+impl Pty {
+    pub fn open(drop_incoming: Dropper, drop_outgoing: Dropper) -> nix::Result<Pty> {
+        let master = posix_openpt(OFlag::O_RDWR | OFlag::O_NOCTTY | OFlag::O_NONBLOCK)?;
+        grantpt(&master)?;
+        unlockpt(&master)?;
+        let path = ptsname_r(&master)?;
+
+        // Bytes go through untouched, the way they do over USB
+        let mut termios = tcgetattr(master.as_raw_fd())?;
+        cfmakeraw(&mut termios);
+        tcsetattr(master.as_raw_fd(), SetArg::TCSANOW, &termios)?;
+
+        Ok(Pty {
+            master,
+            path,
+            open: false,
+            drop_incoming,
+            drop_outgoing,
+        })
+    }
+
+    pub fn path(&self) -> &str {
+        &self.path
+    }
+
+    /// Whether a host has the port open, as of the last `wait`. Stands in
+    /// for the DTR line of the micropad's USB serial port.
+    pub fn is_open(&self) -> bool {
+        self.open
+    }
+
+    /// Wait for bytes from the host, for at most `timeout`.
+    pub fn wait(&mut self, timeout: Duration) -> nix::Result<()> {
+        let mut fds = [PollFd::new(self.master.as_raw_fd(), PollFlags::POLLIN)];
+        poll(&mut fds, timeout.as_millis() as i32)?;
+        let events = fds[0].revents().unwrap_or_else(PollFlags::empty);
+
+        // With nobody on the other end, the master reports a hangup right
+        // away instead of waiting.
+        self.open = !events.contains(PollFlags::POLLHUP);
+        if !self.open {
+            thread::sleep(timeout);
+        }
+        Ok(())
+    }
+}
+
+impl embedded_hal::serial::Read<u8> for Pty {
+    type Error = io::Error;
+
+    fn read(&mut self) -> nb::Result<u8, io::Error> {
+        loop {
+            let mut byte = [0u8];
+            match self.master.read(&mut byte) {
+                Ok(0) => return Err(nb::Error::WouldBlock),
+                Ok(_) => {}
+                Err(err) => return Err(would_block(err)),
+            }
+            let dropped = self.drop_incoming.drops_next();
+            self.drop_incoming.count();
+            if dropped {
+                log::debug!("Dropped incoming byte: {:#04x}", byte[0]);
+                continue;
+            }
+            return Ok(byte[0]);
+        }
+    }
+}
+
+impl embedded_hal::serial::Write<u8> for Pty {
+    type Error = io::Error;
+
+    fn write(&mut self, byte: u8) -> nb::Result<(), io::Error> {
+        if self.drop_outgoing.drops_next() {
+            log::debug!("Dropped outgoing byte: {:#04x}", byte);
+        } else {
+            match self.master.write(&[byte]) {
+                Ok(0) => return Err(nb::Error::WouldBlock),
+                Ok(_) => {}
+                Err(err) => return Err(would_block(err)),
+            }
+        }
+        self.drop_outgoing.count();
+        Ok(())
+    }
+
+    fn flush(&mut self) -> nb::Result<(), io::Error> {
+        Ok(())
+    }
+}
+
+/// Errors that just mean "try again later": a full or empty buffer, or
+/// reading while no host has the port open.
+fn would_block(err: io::Error) -> nb::Error<io::Error> {
+    if err.kind() == io::ErrorKind::WouldBlock || err.raw_os_error() == Some(Errno::EIO as i32) {
+        nb::Error::WouldBlock
+    } else {
+        nb::Error::Other(err)
+    }
+}
@@ -0,0 +1,112 @@
+use std::io::{BufRead, BufReader};
+use std::process::{Child, ChildStdout, Command, Stdio};
+
+use micropad_core::CAPABILITIES;
+use micropad_ctrl::{Error, FirmwareVersion, Micropad};
+use micropad_protocol::{Event, EventSet, ResponseCode};
+use serialport::{SerialPortInfo, SerialPortType};
+
+/// A running simulator, killed once the test is done with it.
+struct Sim {
+    child: Child,
+    path: String,
+    // Held open so the simulator's logging doesn't hit a closed pipe
+    _stdout: BufReader<ChildStdout>,
+}
+
+impl Sim {
+    fn start(args: &[&str]) -> Sim {
+        let mut child = Command::new(env!("CARGO_BIN_EXE_micropad-sim"))
+            .args(args)
+            .stdout(Stdio::piped())
+            .stderr(Stdio::null())
+            .spawn()
+            .unwrap();
+        let mut stdout = BufReader::new(child.stdout.take().unwrap());
+        let mut path = String::new();
+        stdout.read_line(&mut path).unwrap();
+        Sim {
+            child,
+            path: path.trim().to_string(),
+            _stdout: stdout,
+        }
+    }
+
+    fn connect(&self) -> Micropad {
+        Micropad::open(&SerialPortInfo {
+            port_name: self.path.clone(),
+            port_type: SerialPortType::Unknown,
+        })
+        .unwrap()
+    }
+}
+
+impl Drop for Sim {
+    fn drop(&mut self) {
+        let _ = self.child.kill();
+        let _ = self.child.wait();
+    }
+}
+
+#[test]
+fn answers_like_the_firmware() {
+    let sim = Sim::start(&[]);
+    let mut micropad = sim.connect();
+
+    micropad.ping().unwrap();
+    assert_eq!(*micropad.capabilities(), CAPABILITIES);
+    assert_eq!(
+        micropad.version().unwrap(),
+        "0.1.0".parse::<FirmwareVersion>().unwrap()
+    );
+    micropad.set_led_brightness(40).unwrap();
+    assert_eq!(micropad.led_brightness().unwrap(), 40);
+    micropad.set_mode(1).unwrap();
+    assert_eq!(micropad.mode().unwrap(), 1);
+    assert!(matches!(
+        micropad.set_mode(200),
+        Err(Error::Response(ResponseCode::NotFound))
+    ));
+}
+
+#[test]
+fn keeps_saved_settings() {
+    let sim = Sim::start(&[]);
+    let mut micropad = sim.connect();
+
+    micropad.set_led_brightness(10).unwrap();
+    micropad.save_config().unwrap();
+    micropad.set_led_brightness(200).unwrap();
+    micropad.load_config().unwrap();
+    assert_eq!(micropad.led_brightness().unwrap(), 10);
+}
+
+#[test]
+fn reports_mode_changes() {
+    let sim = Sim::start(&[]);
+    let mut micropad = sim.connect();
+
+    micropad.subscribe(EventSet::ALL).unwrap();
+    micropad.set_mode(1).unwrap();
+    let event = (0..10).find_map(|_| micropad.next_event().unwrap());
+    assert_eq!(event, Some(Event::ModeChanged(1)));
+}
+
+#[test]
+fn injects_errors() {
+    let sim = Sim::start(&["--fail", "SaveConfig=storage_error"]);
+    let mut micropad = sim.connect();
+
+    micropad.ping().unwrap();
+    assert!(matches!(
+        micropad.save_config(),
+        Err(Error::Response(ResponseCode::StorageError))
+    ));
+}
+
+#[test]
+fn injects_dropped_bytes() {
+    let sim = Sim::start(&["--drop-outgoing", "1"]);
+    let port = serialport::new(&sim.path, 115_200).open().unwrap();
+    assert!(Micropad::new(port).is_err());
+}